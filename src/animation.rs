@@ -0,0 +1,321 @@
+use std::f32::consts::TAU;
+use std::fmt;
+
+use nalgebra::{UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+/// How a value changes between one keyframe and the next.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+pub enum Easing {
+    /// Change at a constant speed.
+    #[default]
+    Linear,
+    /// Start and finish slowly.
+    Smooth,
+    /// Keep the value until the next keyframe.
+    Step,
+}
+
+impl Easing {
+    /// Map the progress between two keyframes, 0 to 1, to how far the value has moved.
+    fn apply(&self, progress: f32) -> f32 {
+        match self {
+            Easing::Linear => progress,
+            Easing::Smooth => progress * progress * (3.0 - 2.0 * progress),
+            Easing::Step => 0.0,
+        }
+    }
+}
+
+/// A value at a point in time.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Keyframe<T> {
+    /// Seconds from the start of the animation.
+    pub time: f32,
+    pub value: T,
+    /// How the value changes on the way to the next keyframe.
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+/// Add a keyframe keeping the list sorted by time. A keyframe at the same time is replaced.
+fn insert_keyframe<T>(keyframes: &mut Vec<Keyframe<T>>, keyframe: Keyframe<T>) {
+    match keyframes
+        .iter()
+        .position(|existing| existing.time >= keyframe.time)
+    {
+        Some(index) if keyframes[index].time == keyframe.time => keyframes[index] = keyframe,
+        Some(index) => keyframes.insert(index, keyframe),
+        None => keyframes.push(keyframe),
+    }
+}
+
+/// Find the value of a track at a time. Before the first keyframe and after the last
+/// one the value is held. Returns None for an empty track.
+fn sample<T: Copy>(
+    keyframes: &[Keyframe<T>],
+    time: f32,
+    blend: impl Fn(T, T, f32) -> T,
+) -> Option<T> {
+    let first = keyframes.first()?;
+    if time <= first.time {
+        return Some(first.value);
+    }
+    for pair in keyframes.windows(2) {
+        let (from, to) = (&pair[0], &pair[1]);
+        if time < to.time {
+            let progress = (time - from.time) / (to.time - from.time);
+            return Some(blend(from.value, to.value, from.easing.apply(progress)));
+        }
+    }
+    keyframes.last().map(|last| last.value)
+}
+
+/// Blend each axis of 2 vectors.
+fn blend_vector(from: [f32; 3], to: [f32; 3], amount: f32) -> [f32; 3] {
+    [0, 1, 2].map(|axis| from[axis] + (to[axis] - from[axis]) * amount)
+}
+
+/// Turn from one rotation to another the shortest way at a steady speed. Rotations
+/// are axes scaled by the angle in radians.
+fn blend_rotation(from: [f32; 3], to: [f32; 3], amount: f32) -> [f32; 3] {
+    let from = UnitQuaternion::from_scaled_axis(Vector3::from(from));
+    let to = UnitQuaternion::from_scaled_axis(Vector3::from(to));
+    // Rotations that are all but the same can't be blended, but they don't need to be.
+    let blended = from
+        .try_slerp(&to, amount, 1.0e-6)
+        .unwrap_or(if amount < 0.5 { from } else { to });
+    blended.scaled_axis().into()
+}
+
+/// Where a layer is and if it can be seen at one point in time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pose {
+    pub translation: [f32; 3],
+    /// The axis to rotate around, scaled by the angle in radians.
+    pub rotation: [f32; 3],
+    pub visible: bool,
+}
+
+impl Pose {
+    /// A layer that has not moved.
+    pub const fn rest() -> Pose {
+        Pose {
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            visible: true,
+        }
+    }
+}
+
+/// The keyframe tracks that move one layer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct LayerAnimation {
+    #[serde(default)]
+    pub translation: Vec<Keyframe<[f32; 3]>>,
+    /// Rotations are the axis scaled by the angle in radians. Between keyframes the
+    /// layer turns the shortest way, so turns of half a turn or more need keyframes between.
+    #[serde(default)]
+    pub rotation: Vec<Keyframe<[f32; 3]>>,
+    #[serde(default)]
+    pub visibility: Vec<Keyframe<bool>>,
+    /// Start again after the last keyframe instead of holding it.
+    #[serde(default)]
+    pub looping: bool,
+}
+
+impl LayerAnimation {
+    /// Are there any keyframes?
+    pub fn is_empty(&self) -> bool {
+        self.translation.is_empty() && self.rotation.is_empty() && self.visibility.is_empty()
+    }
+
+    /// The time of the last keyframe in any track.
+    pub fn duration(&self) -> f32 {
+        let translation = self.translation.iter().map(|keyframe| keyframe.time);
+        let rotation = self.rotation.iter().map(|keyframe| keyframe.time);
+        let visibility = self.visibility.iter().map(|keyframe| keyframe.time);
+        translation
+            .chain(rotation)
+            .chain(visibility)
+            .fold(0.0, f32::max)
+    }
+
+    /// Add a translation keyframe.
+    pub fn add_translation(&mut self, time: f32, value: [f32; 3], easing: Easing) {
+        insert_keyframe(
+            &mut self.translation,
+            Keyframe {
+                time,
+                value,
+                easing,
+            },
+        );
+    }
+
+    /// Add a rotation keyframe, in radians.
+    pub fn add_rotation(&mut self, time: f32, value: [f32; 3], easing: Easing) {
+        insert_keyframe(
+            &mut self.rotation,
+            Keyframe {
+                time,
+                value,
+                easing,
+            },
+        );
+    }
+
+    /// Add a visibility keyframe. Visibility always changes in a step.
+    pub fn add_visibility(&mut self, time: f32, value: bool) {
+        insert_keyframe(
+            &mut self.visibility,
+            Keyframe {
+                time,
+                value,
+                easing: Easing::Step,
+            },
+        );
+    }
+
+    /// Work out where the layer is at a time in seconds.
+    pub fn pose(&self, time: f32) -> Pose {
+        let duration = self.duration();
+        let time = if self.looping && duration > 0.0 {
+            time.rem_euclid(duration)
+        } else {
+            time
+        };
+        let rest = Pose::rest();
+        Pose {
+            translation: sample(&self.translation, time, blend_vector).unwrap_or(rest.translation),
+            rotation: sample(&self.rotation, time, blend_rotation).unwrap_or(rest.rotation),
+            visible: sample(&self.visibility, time, |from, _to, _amount| from)
+                .unwrap_or(rest.visible),
+        }
+    }
+}
+
+/// Ready made animations.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Preset {
+    /// Turn around the vertical axis.
+    Spin,
+    /// Move up and down.
+    Bob,
+    /// Blink on and off.
+    Pulse,
+    /// Slide in from behind and stop.
+    SlideIn,
+}
+
+/// The name of a preset was not recognised.
+#[derive(Debug, PartialEq)]
+pub struct UnknownPreset(pub String);
+
+impl fmt::Display for UnknownPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown animation preset {}, expected spin, bob, pulse or slide-in",
+            self.0
+        )
+    }
+}
+
+impl Preset {
+    /// Find a preset by name.
+    pub fn from_name(name: &str) -> Result<Preset, UnknownPreset> {
+        match name.to_lowercase().as_str() {
+            "spin" => Ok(Preset::Spin),
+            "bob" => Ok(Preset::Bob),
+            "pulse" => Ok(Preset::Pulse),
+            "slide-in" | "slidein" | "slide_in" => Ok(Preset::SlideIn),
+            _ => Err(UnknownPreset(name.to_string())),
+        }
+    }
+
+    /// Build the keyframes for a world where positions go from -range to +range.
+    pub fn animation(&self, range: i32) -> LayerAnimation {
+        let mut animation = LayerAnimation::default();
+        match self {
+            Preset::Spin => {
+                // Rotations blend the shortest way, so a whole turn needs a keyframe
+                // every quarter turn.
+                for quarter in 0..=4 {
+                    let angle = TAU * quarter as f32 / 4.0;
+                    animation.add_rotation(quarter as f32, [0.0, angle, 0.0], Easing::Linear);
+                }
+                animation.looping = true;
+            }
+            Preset::Bob => {
+                let height = (range as f32 / 8.0).max(1.0);
+                animation.add_translation(0.0, [0.0; 3], Easing::Smooth);
+                animation.add_translation(1.0, [0.0, height, 0.0], Easing::Smooth);
+                animation.add_translation(2.0, [0.0; 3], Easing::Smooth);
+                animation.looping = true;
+            }
+            Preset::Pulse => {
+                animation.add_visibility(0.0, true);
+                animation.add_visibility(0.5, false);
+                animation.add_visibility(1.0, true);
+                animation.looping = true;
+            }
+            Preset::SlideIn => {
+                let distance = range as f32 * 2.0;
+                animation.add_translation(0.0, [0.0, 0.0, -distance], Easing::Smooth);
+                animation.add_translation(1.5, [0.0; 3], Easing::Smooth);
+            }
+        }
+        animation
+    }
+}
+
+/// The play head shared by every layer animation.
+pub struct Timeline {
+    /// Seconds since the start of the animation.
+    pub time: f32,
+    /// Is the time moving?
+    pub playing: bool,
+    /// When the time was last moved on.
+    last_tick: Option<Instant>,
+}
+
+impl Timeline {
+    /// Create a paused timeline at the start.
+    pub const fn new() -> Timeline {
+        Timeline {
+            time: 0.0,
+            playing: false,
+            last_tick: None,
+        }
+    }
+
+    /// Start moving the time.
+    pub fn play(&mut self) {
+        self.playing = true;
+        self.last_tick = None;
+    }
+
+    /// Stop moving the time.
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    /// Jump to a time without changing if we are playing.
+    pub fn scrub(&mut self, time: f32) {
+        self.time = time.max(0.0);
+        self.last_tick = None;
+    }
+
+    /// Move the time on by however long it has been since the last tick.
+    pub fn tick(&mut self, now: Instant) -> f32 {
+        if self.playing {
+            if let Some(last) = self.last_tick {
+                self.time += now.duration_since(last).as_secs_f32();
+            }
+            self.last_tick = Some(now);
+        }
+        self.time
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ocnode::{Ocnode, MAX_LEVELS};
+use crate::voxel_state::VoxelState;
+
+/// The furthest a voxel can be from the middle of the clipboard. Copies are measured
+/// from a point in the world, so nothing copied is further than the widest world.
+const MAX_OFFSET: u32 = 2 * Ocnode::range(MAX_LEVELS) as u32;
+
+/// A copied voxel, positioned relative to the middle of the selection it was copied from.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct ClipboardVoxel {
+    pub offset: [i32; 3],
+    pub color: [f32; 4],
+    pub fluid: i32,
+    pub noise: i32,
+}
+
+/// How pasted voxels combine with the voxels already there.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PasteMode {
+    /// Pasted voxels replace filled voxels.
+    Over,
+    /// Pasted voxels only fill empty space.
+    Merge,
+}
+
+/// Voxels that have been copied so they can be pasted somewhere else.
+/// Only filled voxels are kept, so pasting never empties anything.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Clipboard {
+    pub voxels: Vec<ClipboardVoxel>,
+}
+
+impl Clipboard {
+    /// Create an empty clipboard.
+    pub const fn new() -> Clipboard {
+        Clipboard { voxels: Vec::new() }
+    }
+
+    /// Copy the filled voxels from a list, measured from an origin.
+    pub fn copy(states: &[VoxelState], origin: [i32; 3]) -> Clipboard {
+        let voxels = states
+            .iter()
+            .filter(|state| state.active)
+            .map(|state| ClipboardVoxel {
+                offset: [0, 1, 2].map(|axis| state.position[axis] - origin[axis]),
+                color: state.color,
+                fluid: state.fluid,
+                noise: state.noise,
+            })
+            .collect();
+        Clipboard { voxels }
+    }
+
+    /// Is there anything to paste?
+    pub fn is_empty(&self) -> bool {
+        self.voxels.is_empty()
+    }
+
+    /// Where the voxels would go if pasted at an origin, in a world where positions go
+    /// from -range to +range. Voxels that would land outside the world are dropped.
+    pub fn placed(&self, origin: [i32; 3], range: i32) -> Vec<VoxelState> {
+        self.voxels
+            .iter()
+            .map(|voxel| VoxelState {
+                position: [0, 1, 2].map(|axis| origin[axis] + voxel.offset[axis]),
+                active: true,
+                color: voxel.color,
+                fluid: voxel.fluid,
+                noise: voxel.noise,
+            })
+            .filter(|state| {
+                state
+                    .position
+                    .iter()
+                    .all(|value| *value >= -range && *value < range)
+            })
+            .collect()
+    }
+
+    /// Work out the voxels to write when pasting at an origin.
+    /// `filled` tells if there is already a voxel at a position.
+    pub fn paste(
+        &self,
+        origin: [i32; 3],
+        range: i32,
+        mode: PasteMode,
+        filled: impl Fn([i32; 3]) -> bool,
+    ) -> Vec<VoxelState> {
+        self.placed(origin, range)
+            .into_iter()
+            .filter(|state| mode == PasteMode::Over || !filled(state.position))
+            .collect()
+    }
+
+    /// Write the clipboard as JSON so it can be moved to another scene.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Clipboards are always serializable")
+    }
+
+    /// Read a clipboard written by `to_json`.
+    pub fn from_json(json: &str) -> Result<Clipboard, String> {
+        let clipboard: Clipboard = serde_json::from_str(json)
+            .map_err(|error| format!("The clipboard could not be read: {error}"))?;
+        let invalid = clipboard.voxels.iter().any(|voxel| {
+            voxel
+                .color
+                .iter()
+                .any(|channel| !(0.0..=1.0).contains(channel))
+                || !(0..=1).contains(&voxel.fluid)
+                || !(0..=1).contains(&voxel.noise)
+        });
+        if invalid {
+            return Err(
+                "The clipboard is invalid: colours must be between 0 and 1 and fluid and noise 0 or 1"
+                    .to_string(),
+            );
+        }
+        let too_far = clipboard.voxels.iter().any(|voxel| {
+            voxel
+                .offset
+                .iter()
+                .any(|value| value.unsigned_abs() > MAX_OFFSET)
+        });
+        if too_far {
+            return Err(format!(
+                "The clipboard is invalid: voxels must be at most {MAX_OFFSET} from the middle"
+            ));
+        }
+        let mut offsets: HashSet<[i32; 3]> = HashSet::new();
+        if let Some(voxel) = clipboard
+            .voxels
+            .iter()
+            .find(|voxel| !offsets.insert(voxel.offset))
+        {
+            return Err(format!(
+                "The clipboard is invalid: there is more than one voxel at {:?}",
+                voxel.offset
+            ));
+        }
+        Ok(clipboard)
+    }
+}
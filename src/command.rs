@@ -1,3 +1,8 @@
+/// Set in `data2` of a key command when control (or meta) is held.
+pub const MODIFIER_CTRL: u32 = 1;
+/// Set in `data2` of a key command when shift is held.
+pub const MODIFIER_SHIFT: u32 = 2;
+
 /// A list of command types that we know how to process
 #[derive(Copy, Clone, Debug)]
 pub enum CommandType {
@@ -15,6 +20,6 @@ pub struct Command {
     pub command_type: CommandType,
     /// A data point that may be useful - e.g. X for mouse position.
     pub data1: u32,
-    /// A data point that may be useful - e.g. Y for mouse position.
+    /// A data point that may be useful - e.g. Y for mouse position or key modifiers.
     pub data2: u32,
 }
@@ -52,8 +52,8 @@ impl Drawable for Cube {
     fn init(&mut self) {}
 
     /// A cube always has the same number of vertices minus oclusion
-    fn count_vertices(&self) -> u16 {
-        let mut occluded = self.vertices_count;
+    fn count_vertices(&self) -> u32 {
+        let mut occluded = self.vertices_count as u32;
         if self.bottom_occluded {
             occluded -= 36;
         }
@@ -1,7 +1,7 @@
 /// Drawable objects can provide whats need to render themselves in WebGL.
 pub trait Drawable {
     fn init(&mut self);
-    fn count_vertices(&self) -> u16;
+    fn count_vertices(&self) -> u32;
     fn translation(&self) -> &[f32; 3];
     fn rotation(&self) -> &[f32; 3];
     fn translate(&mut self, amount: [f32; 3]);
@@ -12,4 +12,10 @@ pub trait Drawable {
     fn depth(&self, camera: [f32; 3]) -> f32;
     fn fluid(&self) -> i32;
     fn noise(&self) -> i32;
+
+    /// Drawables whose vertices are kept on the GPU give an id that is only shared
+    /// by copies with the same vertices. Everything else is uploaded every draw.
+    fn buffer_id(&self) -> Option<u64> {
+        None
+    }
 }
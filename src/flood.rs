@@ -0,0 +1,154 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+
+use crate::voxel_state::VoxelState;
+
+/// Which neighbours a flood can spread to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Connectivity {
+    /// Only voxels that share a face, 6 neighbours.
+    Faces,
+    /// Voxels that share a face, edge or corner, 26 neighbours.
+    All,
+}
+
+impl Connectivity {
+    /// Find a connectivity from the number of neighbours, 6 or 26.
+    pub fn from_neighbours(neighbours: u32) -> Result<Connectivity, FloodError> {
+        match neighbours {
+            6 => Ok(Connectivity::Faces),
+            26 => Ok(Connectivity::All),
+            _ => Err(FloodError::UnknownConnectivity(neighbours)),
+        }
+    }
+
+    /// The offsets to every neighbour.
+    fn offsets(&self) -> Vec<[i32; 3]> {
+        let mut offsets = vec![];
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let distance = x * x + y * y + z * z;
+                    let included = match self {
+                        Connectivity::Faces => distance == 1,
+                        Connectivity::All => distance > 0,
+                    };
+                    if included {
+                        offsets.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        offsets
+    }
+}
+
+/// What a voxel must have in common with the start voxel for the flood to spread to it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Matching {
+    /// Any filled voxel.
+    Occupancy,
+    /// Every colour channel, including alpha, is within the tolerance of the start colour.
+    Color(f32),
+    /// The same fluid and noise flags.
+    Material,
+}
+
+impl Matching {
+    /// Find a matching rule by name. The tolerance is only used by "color".
+    pub fn from_name(name: &str, tolerance: f32) -> Result<Matching, FloodError> {
+        match name.to_lowercase().as_str() {
+            "occupancy" => Ok(Matching::Occupancy),
+            "color" | "colour" => {
+                if tolerance.is_nan() || tolerance < 0.0 {
+                    return Err(FloodError::InvalidTolerance(tolerance));
+                }
+                Ok(Matching::Color(tolerance))
+            }
+            "material" => Ok(Matching::Material),
+            _ => Err(FloodError::UnknownMatching(name.to_string())),
+        }
+    }
+
+    /// Does a voxel match the one the flood started from?
+    fn matches(&self, start: &VoxelState, voxel: &VoxelState) -> bool {
+        if !voxel.active {
+            return false;
+        }
+        match self {
+            Matching::Occupancy => true,
+            Matching::Color(tolerance) => start
+                .color
+                .iter()
+                .zip(voxel.color.iter())
+                .all(|(a, b)| (a - b).abs() <= *tolerance),
+            Matching::Material => start.fluid == voxel.fluid && start.noise == voxel.noise,
+        }
+    }
+}
+
+/// Things that can go wrong starting a flood.
+#[derive(Debug, PartialEq)]
+pub enum FloodError {
+    /// Only 6 and 26 neighbours are supported.
+    UnknownConnectivity(u32),
+    /// The name of the matching rule was not recognised.
+    UnknownMatching(String),
+    /// Colour tolerance must be a positive number.
+    InvalidTolerance(f32),
+}
+
+impl fmt::Display for FloodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FloodError::UnknownConnectivity(neighbours) => write!(
+                f,
+                "Unknown connectivity {neighbours}, expected 6 or 26 neighbours"
+            ),
+            FloodError::UnknownMatching(name) => write!(
+                f,
+                "Unknown matching {name}, expected occupancy, color or material"
+            ),
+            FloodError::InvalidTolerance(tolerance) => {
+                write!(f, "The colour tolerance {tolerance} must not be negative")
+            }
+        }
+    }
+}
+
+/// Find every voxel connected to the start voxel that matches it, in a world where
+/// positions go from -range to +range. Returns an empty list if the start voxel is empty.
+/// The list is sorted so the same flood always gives the same selection.
+pub fn flood(
+    start: [i32; 3],
+    range: i32,
+    connectivity: Connectivity,
+    matching: Matching,
+    voxel_at: impl Fn([i32; 3]) -> Option<VoxelState>,
+) -> Vec<[i32; 3]> {
+    let Some(first) = voxel_at(start).filter(|state| state.active) else {
+        return vec![];
+    };
+    let offsets = connectivity.offsets();
+    let mut visited: HashSet<[i32; 3]> = HashSet::from([start]);
+    let mut queue: VecDeque<[i32; 3]> = VecDeque::from([start]);
+    let mut selected = vec![];
+
+    while let Some(position) = queue.pop_front() {
+        selected.push(position);
+        for offset in &offsets {
+            let next = [0, 1, 2].map(|axis| position[axis] + offset[axis]);
+            if next.iter().any(|value| *value < -range || *value >= range) {
+                continue;
+            }
+            if !visited.insert(next) {
+                continue;
+            }
+            if voxel_at(next).is_some_and(|voxel| matching.matches(&first, &voxel)) {
+                queue.push_back(next);
+            }
+        }
+    }
+    selected.sort();
+    selected
+}
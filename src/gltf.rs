@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::mesh_export::to_byte;
+use crate::mesher::Quad;
+use crate::voxel_state::MaterialKey;
+
+/// "glTF" read as a little endian number.
+const GLB_MAGIC: u32 = 0x4654_6C67;
+/// The kind of a chunk holding JSON.
+const CHUNK_JSON: u32 = 0x4E4F_534A;
+/// The kind of a chunk holding binary data.
+const CHUNK_BIN: u32 = 0x004E_4942;
+/// Buffer views holding vertex attributes.
+const ARRAY_BUFFER: u32 = 34962;
+/// Buffer views holding triangle indexes.
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+/// Accessor component types.
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+/// glTF colours are linear but our colours are sRGB like the screen.
+fn to_linear(channel: f32) -> f32 {
+    let channel = channel.clamp(0.0, 1.0);
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// A name for a material that says what it is, like `color_ff8000ff_fluid`.
+fn material_name(color: [f32; 4], fluid: i32, noise: i32) -> String {
+    let mut name = String::from("color_");
+    for channel in color {
+        name.push_str(&format!("{:02x}", to_byte(channel)));
+    }
+    if fluid != 0 {
+        name.push_str("_fluid");
+    }
+    if noise != 0 {
+        name.push_str("_noise");
+    }
+    name
+}
+
+/// Collects the JSON and binary data for a glTF file as meshes are added.
+struct Builder {
+    binary: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    materials: Vec<Value>,
+    material_indexes: BTreeMap<MaterialKey, usize>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+}
+
+impl Builder {
+    /// Add a block of binary data and an accessor that reads it. Returns the accessor index.
+    fn push_accessor(&mut self, bytes: &[u8], target: u32, accessor: Value) -> usize {
+        let mut accessor = accessor;
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.binary.len(),
+            "byteLength": bytes.len(),
+            "target": target,
+        }));
+        self.binary.extend_from_slice(bytes);
+        accessor["bufferView"] = json!(self.buffer_views.len() - 1);
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    /// Find or add the material for a quad. Returns the material index.
+    fn material(&mut self, quad: &Quad) -> usize {
+        let key = quad.material_key();
+        if let Some(index) = self.material_indexes.get(&key) {
+            return *index;
+        }
+        let [red, green, blue, alpha] = quad.color;
+        let alpha = alpha.clamp(0.0, 1.0);
+        self.materials.push(json!({
+            "name": material_name(quad.color, quad.fluid, quad.noise),
+            "pbrMetallicRoughness": {
+                "baseColorFactor": [to_linear(red), to_linear(green), to_linear(blue), alpha],
+                "metallicFactor": 0.0,
+                "roughnessFactor": 1.0,
+            },
+            "alphaMode": if alpha < 1.0 { "BLEND" } else { "OPAQUE" },
+            "extras": { "fluid": quad.fluid, "noise": quad.noise },
+        }));
+        self.material_indexes.insert(key, self.materials.len() - 1);
+        self.materials.len() - 1
+    }
+
+    /// Add a node with a mesh holding one primitive per material. Nodes without quads
+    /// are skipped because a glTF mesh needs at least one primitive.
+    fn push_node(&mut self, name: &str, quads: &[Quad]) {
+        let mut groups: BTreeMap<usize, Vec<&Quad>> = BTreeMap::new();
+        for quad in quads {
+            let material = self.material(quad);
+            groups.entry(material).or_default().push(quad);
+        }
+        if groups.is_empty() {
+            return;
+        }
+
+        let mut primitives = vec![];
+        for (material, quads) in groups {
+            let mut positions: Vec<u8> = vec![];
+            let mut normals: Vec<u8> = vec![];
+            let mut indexes: Vec<u8> = vec![];
+            let mut min = [f32::INFINITY; 3];
+            let mut max = [f32::NEG_INFINITY; 3];
+            for (number, quad) in quads.iter().enumerate() {
+                for corner in quad.corners {
+                    for axis in 0..3 {
+                        min[axis] = min[axis].min(corner[axis]);
+                        max[axis] = max[axis].max(corner[axis]);
+                        positions.extend_from_slice(&corner[axis].to_le_bytes());
+                        normals.extend_from_slice(&quad.normal[axis].to_le_bytes());
+                    }
+                }
+                let first = number as u32 * 4;
+                for index in [0, 1, 2, 0, 2, 3] {
+                    indexes.extend_from_slice(&(first + index).to_le_bytes());
+                }
+            }
+            let vertex_count = quads.len() * 4;
+            let position = self.push_accessor(
+                &positions,
+                ARRAY_BUFFER,
+                json!({
+                    "componentType": FLOAT,
+                    "count": vertex_count,
+                    "type": "VEC3",
+                    "min": min,
+                    "max": max,
+                }),
+            );
+            let normal = self.push_accessor(
+                &normals,
+                ARRAY_BUFFER,
+                json!({ "componentType": FLOAT, "count": vertex_count, "type": "VEC3" }),
+            );
+            let indices = self.push_accessor(
+                &indexes,
+                ELEMENT_ARRAY_BUFFER,
+                json!({ "componentType": UNSIGNED_INT, "count": quads.len() * 6, "type": "SCALAR" }),
+            );
+            primitives.push(json!({
+                "attributes": { "POSITION": position, "NORMAL": normal },
+                "indices": indices,
+                "material": material,
+            }));
+        }
+
+        self.meshes
+            .push(json!({ "name": name, "primitives": primitives }));
+        self.nodes
+            .push(json!({ "name": name, "mesh": self.meshes.len() - 1 }));
+    }
+}
+
+/// Write named groups of quads as a binary glTF file with one node per group. Every
+/// material gets its own primitive, and fluid and noisy voxels get their own named
+/// materials with the flags kept in the material extras.
+pub fn glb(nodes: &[(&str, Vec<Quad>)]) -> Vec<u8> {
+    let mut builder = Builder {
+        binary: vec![],
+        buffer_views: vec![],
+        accessors: vec![],
+        materials: vec![],
+        material_indexes: BTreeMap::new(),
+        meshes: vec![],
+        nodes: vec![],
+    };
+    for (name, quads) in nodes {
+        builder.push_node(name, quads);
+    }
+
+    let mut document = json!({
+        "asset": { "version": "2.0", "generator": "creator" },
+        "scene": 0,
+        "scenes": [{}],
+    });
+    // Empty lists aren't allowed, so an empty model is a scene without nodes.
+    if !builder.nodes.is_empty() {
+        document["scenes"][0]["nodes"] = json!((0..builder.nodes.len()).collect::<Vec<usize>>());
+        document["nodes"] = json!(builder.nodes);
+        document["buffers"] = json!([{ "byteLength": builder.binary.len() }]);
+        document["bufferViews"] = json!(builder.buffer_views);
+        document["accessors"] = json!(builder.accessors);
+        document["materials"] = json!(builder.materials);
+        document["meshes"] = json!(builder.meshes);
+    }
+
+    let mut json_chunk = document.to_string().into_bytes();
+    while !json_chunk.len().is_multiple_of(4) {
+        json_chunk.push(b' ');
+    }
+    let mut binary = builder.binary;
+    while !binary.len().is_multiple_of(4) {
+        binary.push(0);
+    }
+
+    let mut length = 12 + 8 + json_chunk.len();
+    if !binary.is_empty() {
+        length += 8 + binary.len();
+    }
+    let mut bytes = Vec::with_capacity(length);
+    bytes.extend_from_slice(&GLB_MAGIC.to_le_bytes());
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+    bytes.extend_from_slice(&(length as u32).to_le_bytes());
+    bytes.extend_from_slice(&(json_chunk.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&CHUNK_JSON.to_le_bytes());
+    bytes.extend_from_slice(&json_chunk);
+    if !binary.is_empty() {
+        bytes.extend_from_slice(&(binary.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&CHUNK_BIN.to_le_bytes());
+        bytes.extend_from_slice(&binary);
+    }
+    bytes
+}
@@ -1,44 +1,70 @@
+use std::cell::RefCell;
 use std::cmp::min;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 
 use crate::camera::Camera;
 use crate::drawable::Drawable;
+use gloo::events::EventListener;
 use nalgebra::*;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use web_sys::WebGlFramebuffer;
 use web_sys::WebGlTexture;
+use web_sys::{HtmlCanvasElement, WebGlBuffer, WebGlRenderbuffer};
 use web_sys::{WebGlProgram, WebGlRenderingContext, WebGlShader};
 
+/// The vertices and normals of a mesh, kept on the GPU until the mesh is rebuilt.
+struct MeshBuffers {
+    vertices: WebGlBuffer,
+    normals: WebGlBuffer,
+}
+
 /// All the things we need to know to render to the screen.
+/// GPU resources are created once and kept until the context is lost or we are dropped.
 pub struct Graphics {
     pub gl: WebGlRenderingContext,
+    canvas: HtmlCanvasElement,
     pub canvas_width: i32,
     pub canvas_height: i32,
     pub camera_program: Option<WebGlProgram>,
     pub light_program: Option<WebGlProgram>,
     pub shadow_frame_buffer: Option<WebGlFramebuffer>,
     pub shadow_depth_texture: Option<WebGlTexture>,
+    shadow_render_buffer: Option<WebGlRenderbuffer>,
+    vertex_buffer: Option<WebGlBuffer>,
+    normal_buffer: Option<WebGlBuffer>,
+    /// Buffers for every mesh drawn, by mesh id.
+    mesh_buffers: RefCell<HashMap<u64, MeshBuffers>>,
+    /// The meshes drawn since the last frame started.
+    drawn: RefCell<HashSet<u64>>,
     pub shadow_texture_size: i32,
     pub swap_shaders: bool,
     pub swap_cameras: bool,
     vertex_buffer_limit: i32,
+    /// Has the context been lost since the resources were created?
+    context_lost: bool,
+    /// Keeps the context lost handler registered while we are alive.
+    _context_lost_listener: EventListener,
 }
 
 impl Graphics {
-    /// Create a new Graphics container with default values.
+    /// Create a new Graphics container and all the GPU resources it needs.
     pub fn new() -> Graphics {
         let canvas_id = "scene";
         let document = web_sys::window().unwrap().document().unwrap();
         let canvas_element = document.get_element_by_id(canvas_id).unwrap();
-        let canvas: web_sys::HtmlCanvasElement =
-            match canvas_element.dyn_into::<web_sys::HtmlCanvasElement>() {
-                Ok(canvas) => canvas,
-                Err(_) => {
-                    panic!("Could not find the canvas element");
-                }
-            };
+        let canvas: HtmlCanvasElement = match canvas_element.dyn_into::<HtmlCanvasElement>() {
+            Ok(canvas) => canvas,
+            Err(_) => {
+                panic!("Could not find the canvas element");
+            }
+        };
         let canvas_width = canvas.client_width();
         let canvas_height = canvas.client_height();
+        // The drawing buffer matches the size the canvas is shown at.
+        canvas.set_width(canvas_width.max(0) as u32);
+        canvas.set_height(canvas_height.max(0) as u32);
         let gl_element = canvas.get_context("webgl").unwrap();
         let gl: WebGlRenderingContext = match gl_element
             .expect("Found webgl")
@@ -50,28 +76,109 @@ impl Graphics {
             }
         };
 
-        gl.enable(WebGlRenderingContext::DEPTH_TEST);
-        gl.depth_func(WebGlRenderingContext::LEQUAL);
-        gl.enable(WebGlRenderingContext::BLEND);
-        gl.blend_func(
-            WebGlRenderingContext::ONE,
-            WebGlRenderingContext::ONE_MINUS_SRC_ALPHA,
-        );
-        gl.enable(WebGlRenderingContext::CULL_FACE);
+        // The browser will only restore a lost context if we ask for it.
+        let context_lost_listener = EventListener::new(&canvas, "webglcontextlost", |event| {
+            event.prevent_default();
+        });
 
-        Graphics {
+        let mut graphics = Graphics {
             gl,
+            canvas,
             canvas_width,
             canvas_height,
             camera_program: None,
             light_program: None,
             shadow_frame_buffer: None,
             shadow_depth_texture: None,
+            shadow_render_buffer: None,
+            vertex_buffer: None,
+            normal_buffer: None,
+            mesh_buffers: RefCell::new(HashMap::new()),
+            drawn: RefCell::new(HashSet::new()),
             shadow_texture_size: 4096,
             swap_shaders: false,
             swap_cameras: false,
-            vertex_buffer_limit: 2048,
+            // A multiple of 6 so chunks never split a triangle or a line.
+            vertex_buffer_limit: 65532,
+            context_lost: false,
+            _context_lost_listener: context_lost_listener,
+        };
+        graphics.setup_resources();
+        graphics
+    }
+
+    /// Set the context state and create the programs, textures and buffers.
+    pub fn setup_resources(&mut self) {
+        self.gl.enable(WebGlRenderingContext::DEPTH_TEST);
+        self.gl.depth_func(WebGlRenderingContext::LEQUAL);
+        self.gl.enable(WebGlRenderingContext::BLEND);
+        self.gl.blend_func(
+            WebGlRenderingContext::ONE,
+            WebGlRenderingContext::ONE_MINUS_SRC_ALPHA,
+        );
+        self.gl.enable(WebGlRenderingContext::CULL_FACE);
+
+        self.setup_shaders();
+        self.vertex_buffer = self.gl.create_buffer();
+        self.normal_buffer = self.gl.create_buffer();
+    }
+
+    /// Free all the GPU resources we created.
+    pub fn release(&mut self) {
+        self.gl.delete_program(self.camera_program.take().as_ref());
+        self.gl.delete_program(self.light_program.take().as_ref());
+        self.gl
+            .delete_framebuffer(self.shadow_frame_buffer.take().as_ref());
+        self.gl
+            .delete_texture(self.shadow_depth_texture.take().as_ref());
+        self.gl
+            .delete_renderbuffer(self.shadow_render_buffer.take().as_ref());
+        self.gl.delete_buffer(self.vertex_buffer.take().as_ref());
+        self.gl.delete_buffer(self.normal_buffer.take().as_ref());
+        self.drawn.get_mut().clear();
+        self.release_unused_meshes();
+    }
+
+    /// Check the context and the canvas size before drawing a frame.
+    /// Returns false when the context is lost and nothing can be drawn.
+    pub fn begin_frame(&mut self) -> bool {
+        if self.gl.is_context_lost() {
+            if !self.context_lost {
+                log::error!("WebGL context lost");
+            }
+            self.context_lost = true;
+            return false;
+        }
+
+        if self.context_lost {
+            log::info!("WebGL context restored, recreating resources");
+            // Everything from the old context is already gone, so just forget it.
+            self.camera_program = None;
+            self.light_program = None;
+            self.shadow_frame_buffer = None;
+            self.shadow_depth_texture = None;
+            self.shadow_render_buffer = None;
+            self.vertex_buffer = None;
+            self.normal_buffer = None;
+            self.mesh_buffers.get_mut().clear();
+            self.drawn.get_mut().clear();
+            self.setup_resources();
+            self.context_lost = false;
+        }
+
+        let canvas_width = self.canvas.client_width();
+        let canvas_height = self.canvas.client_height();
+        if canvas_width != self.canvas_width || canvas_height != self.canvas_height {
+            log::debug!("Canvas resized to {canvas_width}x{canvas_height}");
+            self.canvas_width = canvas_width;
+            self.canvas_height = canvas_height;
+            // Otherwise the drawing buffer keeps its old size and is stretched to fit.
+            self.canvas.set_width(canvas_width.max(0) as u32);
+            self.canvas.set_height(canvas_height.max(0) as u32);
+            self.gl.viewport(0, 0, canvas_width, canvas_height);
         }
+        self.release_unused_meshes();
+        true
     }
 
     /// Compile a shader and link it to the graphics card.
@@ -174,9 +281,11 @@ impl Graphics {
             panic!("exit");
         }
 
-        let render_buffer = self.gl.create_renderbuffer();
-        self.gl
-            .bind_renderbuffer(WebGlRenderingContext::RENDERBUFFER, render_buffer.as_ref());
+        self.shadow_render_buffer = self.gl.create_renderbuffer();
+        self.gl.bind_renderbuffer(
+            WebGlRenderingContext::RENDERBUFFER,
+            self.shadow_render_buffer.as_ref(),
+        );
 
         self.gl.renderbuffer_storage(
             WebGlRenderingContext::RENDERBUFFER,
@@ -196,7 +305,7 @@ impl Graphics {
             WebGlRenderingContext::FRAMEBUFFER,
             WebGlRenderingContext::DEPTH_ATTACHMENT,
             WebGlRenderingContext::RENDERBUFFER,
-            render_buffer.as_ref(),
+            self.shadow_render_buffer.as_ref(),
         );
 
         // Unbind now the buffers are created
@@ -237,72 +346,103 @@ impl Graphics {
         }
     }
 
-    /// Format the vertices for WebGL.
+    /// Point the shader at the vertices of a drawable. Meshes are uploaded the first
+    /// time they are drawn and kept, everything else is uploaded every time.
     pub fn setup_vertices(
         &self,
-        vertices: Vec<f32>,
-        normals: Vec<f32>,
+        drawable: &impl Drawable,
         shader_program: &WebGlProgram,
         is_camera: bool,
     ) {
-        let a_position: u32 = self.gl.get_attrib_location(shader_program, "a_position") as u32;
+        if let Some(id) = drawable.buffer_id() {
+            let mut mesh_buffers = self.mesh_buffers.borrow_mut();
+            let buffers = match mesh_buffers.entry(id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let (Some(vertices), Some(normals)) =
+                        (self.gl.create_buffer(), self.gl.create_buffer())
+                    else {
+                        log::error!("Could not create mesh buffers");
+                        return;
+                    };
+                    self.upload(&vertices, &drawable.vertices());
+                    self.upload(&normals, &drawable.normals());
+                    entry.insert(MeshBuffers { vertices, normals })
+                }
+            };
+            self.drawn.borrow_mut().insert(id);
+            self.bind_attribute(shader_program, "a_position", Some(&buffers.vertices));
+            if is_camera {
+                self.bind_attribute(shader_program, "a_normal", Some(&buffers.normals));
+            }
+            return;
+        }
 
-        let vertex_buffer = self.gl.create_buffer().unwrap();
-        self.gl
-            .bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(&vertex_buffer));
+        let vertex_buffer = self.vertex_buffer.as_ref();
+        if let Some(buffer) = vertex_buffer {
+            self.upload(buffer, &drawable.vertices());
+        }
+        self.bind_attribute(shader_program, "a_position", vertex_buffer);
 
+        // Normals
+        if is_camera {
+            let normal_buffer = self.normal_buffer.as_ref();
+            if let Some(buffer) = normal_buffer {
+                self.upload(buffer, &drawable.normals());
+            }
+            self.bind_attribute(shader_program, "a_normal", normal_buffer);
+        }
+    }
+
+    /// Copy values into a buffer on the GPU.
+    fn upload(&self, buffer: &WebGlBuffer, values: &[f32]) {
+        self.gl
+            .bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(buffer));
         unsafe {
-            let vertices_array = js_sys::Float32Array::view(vertices.as_slice());
+            let array = js_sys::Float32Array::view(values);
 
             self.gl.buffer_data_with_array_buffer_view(
                 WebGlRenderingContext::ARRAY_BUFFER,
-                &vertices_array,
+                &array,
                 WebGlRenderingContext::STATIC_DRAW,
             );
         }
+    }
 
+    /// Read a shader attribute as 3 floats per vertex from a buffer.
+    fn bind_attribute(
+        &self,
+        shader_program: &WebGlProgram,
+        name: &str,
+        buffer: Option<&WebGlBuffer>,
+    ) {
+        let location = self.gl.get_attrib_location(shader_program, name) as u32;
         self.gl
-            .bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(&vertex_buffer));
+            .bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, buffer);
         self.gl.vertex_attrib_pointer_with_i32(
-            a_position,
+            location,
             3,
             WebGlRenderingContext::FLOAT,
             false,
             0,
             0,
         );
-        self.gl.enable_vertex_attrib_array(a_position);
-
-        // Normals
-        if is_camera {
-            let a_normal: u32 = self.gl.get_attrib_location(shader_program, "a_normal") as u32;
-            let normal_buffer = self.gl.create_buffer().unwrap();
-
-            self.gl
-                .bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(&normal_buffer));
-
-            unsafe {
-                let normals_array = js_sys::Float32Array::view(normals.as_slice());
+        self.gl.enable_vertex_attrib_array(location);
+    }
 
-                self.gl.buffer_data_with_array_buffer_view(
-                    WebGlRenderingContext::ARRAY_BUFFER,
-                    &normals_array,
-                    WebGlRenderingContext::STATIC_DRAW,
-                );
+    /// Free the buffers of meshes that were not drawn since the last call, they
+    /// have been rebuilt or removed.
+    fn release_unused_meshes(&mut self) {
+        let drawn = self.drawn.take();
+        let gl = &self.gl;
+        self.mesh_buffers.get_mut().retain(|id, buffers| {
+            let keep = drawn.contains(id);
+            if !keep {
+                gl.delete_buffer(Some(&buffers.vertices));
+                gl.delete_buffer(Some(&buffers.normals));
             }
-
-            self.gl
-                .bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(&normal_buffer));
-            self.gl.vertex_attrib_pointer_with_i32(
-                a_normal,
-                3,
-                WebGlRenderingContext::FLOAT,
-                false,
-                0,
-                0,
-            );
-            self.gl.enable_vertex_attrib_array(a_normal);
-        }
+            keep
+        });
     }
 
     /// Compile the various shaders.
@@ -578,12 +718,7 @@ impl Graphics {
             self.light_program.as_ref()
         };
         self.use_light_shader();
-        self.setup_vertices(
-            drawable.vertices(),
-            drawable.normals(),
-            shader.expect("fail"),
-            false,
-        );
+        self.setup_vertices(drawable, shader.expect("fail"), false);
 
         // We want a model / view and a projection matrix
         // Compute the matrices
@@ -626,17 +761,7 @@ impl Graphics {
                 .uniform_matrix4fv_with_f32_array(Some(&u), false, projection.as_slice());
         }
 
-        let chunk_size: i32 = self.vertex_buffer_limit;
-        let upper: i32 = ((drawable.count_vertices() as i32) + (chunk_size - 1)) / chunk_size;
-        for chunk in 0i32..upper {
-            let count = min(
-                chunk_size,
-                drawable.count_vertices() as i32 - (chunk * chunk_size),
-            );
-            let reduced_count = count / 3;
-            self.gl
-                .draw_arrays(render_mode, chunk * chunk_size, reduced_count);
-        }
+        self.draw_chunks(drawable, render_mode);
         self.gl.flush();
     }
 
@@ -655,12 +780,7 @@ impl Graphics {
             self.camera_program.as_ref()
         };
         self.use_camera_shader();
-        self.setup_vertices(
-            drawable.vertices(),
-            drawable.normals(),
-            shader.expect("fail"),
-            true,
-        );
+        self.setup_vertices(drawable, shader.expect("fail"), true);
 
         let color_location_opt = self
             .gl
@@ -787,19 +907,21 @@ impl Graphics {
 
         self.gl.line_width(2.0);
 
-        let chunk_size: i32 = self.vertex_buffer_limit;
-        let upper = ((drawable.count_vertices() as i32) + (chunk_size - 1)) / chunk_size;
+        self.draw_chunks(drawable, render_mode);
+        self.gl.flush();
+    }
 
-        for chunk in 0..upper {
-            let count = min(
-                chunk_size,
-                drawable.count_vertices() as i32 - (chunk * chunk_size),
-            );
+    /// Draw the uploaded vertices in chunks no bigger than the vertex buffer limit.
+    fn draw_chunks(&self, drawable: &impl Drawable, render_mode: u32) {
+        // There are 3 values for each vertex.
+        let vertex_count = drawable.count_vertices() as i32 / 3;
+        let chunk_size: i32 = self.vertex_buffer_limit;
+        let upper: i32 = (vertex_count + (chunk_size - 1)) / chunk_size;
 
-            let reduced_count = count / 3;
-            self.gl.draw_arrays(render_mode, 0, reduced_count);
+        for chunk in 0i32..upper {
+            let count = min(chunk_size, vertex_count - (chunk * chunk_size));
+            self.gl.draw_arrays(render_mode, chunk * chunk_size, count);
         }
-        self.gl.flush();
     }
 
     /// Prepare to draw the shadow.
@@ -857,3 +979,10 @@ impl Graphics {
     /// We are done with the camera frame.
     pub fn finish_camera_frame(&self) {}
 }
+
+impl Drop for Graphics {
+    /// Give the GPU resources back when the renderer goes away.
+    fn drop(&mut self) {
+        self.release();
+    }
+}
@@ -1,11 +1,11 @@
 /// A Grid is a drawable thing too.
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Grid {
     pub scale: u16,
     pub square_count: u16,
     pub vertices_count: u16,
-    pub vertices: [f32; 1548],
-    pub normals: [f32; 1548],
+    pub vertices: Vec<f32>,
+    pub normals: Vec<f32>,
     pub max_scale: u16,
     pub translation: [f32; 3],
     pub rotation: [f32; 3],
@@ -23,9 +23,9 @@ impl Grid {
             scale: 128,
             square_count: 16384,  // self.scale * self.scale
             vertices_count: 1548, // 2 * (6 * (self.scale+1))
-            vertices: [0.0; 1548],
-            normals: [0.0; 1548],
-            max_scale: 200,
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            max_scale: 512,
             translation: [0.0; 3],
             rotation: [0.0; 3],
             color: [0.5, 0.5, 0.5, 0.2],
@@ -33,11 +33,25 @@ impl Grid {
             noise: 0,
         }
     }
+
+    /// Change the number of squares along each side and rebuild the lines.
+    pub fn set_scale(&mut self, scale: u16) {
+        self.scale = scale;
+        self.init();
+    }
 }
 
 impl Drawable for Grid {
     /// Init a grid once it is created.
     fn init(&mut self) {
+        if self.scale > self.max_scale {
+            panic!("Scale for grid is out of bounds");
+        }
+        // We want one pair of vertices for each row +1 and one for each column + 1
+        let size = 2 * (6 * (self.scale as usize + 1));
+        self.vertices = vec![0.0; size];
+        self.normals = vec![0.0; size];
+
         let mut index = 0;
         let mut increment = || -> usize {
             let result = index;
@@ -60,11 +74,6 @@ impl Drawable for Grid {
             -1.0, -1.0, 0.0, // bottom left
         ];
 
-        if self.scale > self.max_scale {
-            panic!("Scale for grid is out of bounds");
-        }
-        // We want one pair of vertices for each row +1 and one for each column + 1
-
         let scale_f = self.scale as f32;
         for row in 0..=self.scale {
             self.vertices[increment()] = row_vertices[0] * scale_f / 2.0;
@@ -98,13 +107,13 @@ impl Drawable for Grid {
             self.normals[normal_increment()] = 0.0;
         }
 
-        self.square_count = self.scale * self.scale;
+        self.square_count = self.scale.saturating_mul(self.scale);
         self.vertices_count = 2 * (6 * (self.scale + 1));
     }
 
     /// We calculated the number of vertices after we created it.
-    fn count_vertices(&self) -> u16 {
-        self.vertices_count
+    fn count_vertices(&self) -> u32 {
+        self.vertices_count as u32
     }
 
     /// Where is the grid.
@@ -133,7 +142,7 @@ impl Drawable for Grid {
 
     /// Tell me the vertices to draw.
     fn vertices(&self) -> Vec<f32> {
-        self.vertices.to_vec()
+        self.vertices.clone()
     }
 
     /// What color are the lines?
@@ -151,7 +160,7 @@ impl Drawable for Grid {
 
     /// Where are the normals facing.
     fn normals(&self) -> Vec<f32> {
-        self.normals.to_vec()
+        self.normals.clone()
     }
 
     /// Calculate the distance from the camera to the grid.
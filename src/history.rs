@@ -0,0 +1,76 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::voxel_state::VoxelState;
+
+/// The most edits we remember before dropping the oldest.
+const HISTORY_LIMIT: usize = 100;
+
+/// The voxel states in one layer before an edit was applied.
+pub struct Edit {
+    /// The id of the layer that was edited.
+    pub layer: u32,
+    pub states: Vec<VoxelState>,
+}
+
+/// A bounded list of edits that can be undone and redone.
+pub struct History {
+    /// Edits that can be undone, newest at the back.
+    undo_stack: VecDeque<Edit>,
+    /// Edits that were undone and can be applied again, newest at the back.
+    redo_stack: Vec<Edit>,
+}
+
+impl History {
+    /// Create a new empty history.
+    pub const fn new() -> History {
+        History {
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+        }
+    }
+
+    /// Remember a new edit. Any edits that were undone can no longer be redone.
+    /// An edit can write a position more than once, so only the first state replaced at
+    /// each position is kept: that is what it held before the edit.
+    pub fn record(&mut self, layer: u32, mut previous: Vec<VoxelState>) {
+        let mut seen: HashSet<[i32; 3]> = HashSet::new();
+        previous.retain(|state| seen.insert(state.position));
+        if previous.is_empty() {
+            return;
+        }
+        self.redo_stack.clear();
+        self.push_undo(Edit {
+            layer,
+            states: previous,
+        });
+    }
+
+    /// Remember an edit that can be undone without forgetting the redo list.
+    pub fn push_undo(&mut self, previous: Edit) {
+        if self.undo_stack.len() >= HISTORY_LIMIT {
+            self.undo_stack.pop_front();
+        }
+        self.undo_stack.push_back(previous);
+    }
+
+    /// Remember an edit that was undone so it can be redone.
+    pub fn push_redo(&mut self, next: Edit) {
+        self.redo_stack.push(next);
+    }
+
+    /// Take the most recent edit to undo.
+    pub fn undo(&mut self) -> Option<Edit> {
+        self.undo_stack.pop_back()
+    }
+
+    /// Take the most recently undone edit to apply again.
+    pub fn redo(&mut self) -> Option<Edit> {
+        self.redo_stack.pop()
+    }
+
+    /// Forget everything.
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+}
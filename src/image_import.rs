@@ -0,0 +1,249 @@
+use std::fmt;
+
+use png::{BitDepth, ColorType, Decoder, Encoder, Limits, Transformations};
+
+use crate::voxel_state::VoxelState;
+
+/// The most memory a decoded image may use.
+const MAX_IMAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Things that can go wrong importing an image.
+#[derive(Debug)]
+pub enum ImageError {
+    /// The PNG could not be decoded.
+    Decode(String),
+    /// The PNG could not be encoded.
+    Encode(String),
+    /// The PNG uses a pixel layout we can't read.
+    UnsupportedFormat(String),
+    /// A setting has a value we can't use.
+    InvalidValue(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Decode(reason) => write!(f, "The image could not be read: {reason}"),
+            ImageError::Encode(reason) => write!(f, "The image could not be written: {reason}"),
+            ImageError::UnsupportedFormat(format) => {
+                write!(f, "The image format {format} is not supported")
+            }
+            ImageError::InvalidValue(reason) => write!(f, "The image can't be imported: {reason}"),
+        }
+    }
+}
+
+/// A decoded image with one RGBA colour per pixel, top row first.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl Image {
+    /// Decode a PNG. Every pixel layout is converted to 8 bit RGBA.
+    pub fn decode_png(bytes: &[u8]) -> Result<Image, ImageError> {
+        let mut decoder = Decoder::new_with_limits(
+            bytes,
+            Limits {
+                bytes: MAX_IMAGE_BYTES,
+            },
+        );
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        let mut reader = decoder
+            .read_info()
+            .map_err(|error| ImageError::Decode(error.to_string()))?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buffer)
+            .map_err(|error| ImageError::Decode(error.to_string()))?;
+        if info.bit_depth != BitDepth::Eight {
+            return Err(ImageError::UnsupportedFormat(format!(
+                "{:?} bit",
+                info.bit_depth
+            )));
+        }
+
+        let data = &buffer[..info.buffer_size()];
+        let pixels = match info.color_type {
+            ColorType::Grayscale => data.iter().map(|&gray| [gray, gray, gray, 255]).collect(),
+            ColorType::GrayscaleAlpha => data
+                .chunks_exact(2)
+                .map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]])
+                .collect(),
+            ColorType::Rgb => data
+                .chunks_exact(3)
+                .map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+                .collect(),
+            ColorType::Rgba => data
+                .chunks_exact(4)
+                .map(|pixel| [pixel[0], pixel[1], pixel[2], pixel[3]])
+                .collect(),
+            ColorType::Indexed => {
+                return Err(ImageError::UnsupportedFormat("indexed".to_string()));
+            }
+        };
+        Ok(Image {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    /// Encode the image as an 8 bit RGBA PNG.
+    pub fn encode_png(&self) -> Result<Vec<u8>, ImageError> {
+        let mut bytes = vec![];
+        let mut encoder = Encoder::new(&mut bytes, self.width, self.height);
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder
+            .write_header()
+            .map_err(|error| ImageError::Encode(error.to_string()))?;
+        writer
+            .write_image_data(self.pixels.as_flattened())
+            .map_err(|error| ImageError::Encode(error.to_string()))?;
+        writer
+            .finish()
+            .map_err(|error| ImageError::Encode(error.to_string()))?;
+        Ok(bytes)
+    }
+
+    /// The colour of a pixel.
+    fn pixel(&self, column: u32, row: u32) -> [u8; 4] {
+        self.pixels[(row * self.width + column) as usize]
+    }
+}
+
+/// A flat plane through the world, named after the two axes it spreads along
+/// like the square selection shapes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Plane {
+    XY,
+    XZ,
+    YZ,
+}
+
+impl Plane {
+    /// Find a plane by name.
+    pub fn from_name(name: &str) -> Result<Plane, ImageError> {
+        match name.to_lowercase().as_str() {
+            "xy" => Ok(Plane::XY),
+            "xz" => Ok(Plane::XZ),
+            "yz" => Ok(Plane::YZ),
+            _ => Err(ImageError::InvalidValue(format!(
+                "unknown plane {name}, expected xy, xz or yz"
+            ))),
+        }
+    }
+
+    /// Place a pixel on the plane. Across is to the right of the image, up is towards
+    /// the top of the image and depth goes through the plane. On the flat XZ plane
+    /// the top of the image points away from the default camera.
+    fn position(&self, across: i32, up: i32, depth: i32) -> [i32; 3] {
+        match self {
+            Plane::XY => [across, up, depth],
+            Plane::XZ => [across, depth, -up],
+            Plane::YZ => [depth, up, -across],
+        }
+    }
+}
+
+/// Turn 8 bit channels into a voxel colour.
+fn to_color(pixel: [u8; 4]) -> [f32; 4] {
+    pixel.map(|channel| channel as f32 / 255.0)
+}
+
+/// Is a position inside a world where positions go from -range to +range?
+fn in_range(position: [i32; 3], range: i32) -> bool {
+    position
+        .iter()
+        .all(|value| *value >= -range && *value < range)
+}
+
+/// Build terrain from a grayscale heightmap. The image lies flat, centred on the origin
+/// column, with black at the base height and white max_height voxels above it.
+/// Every voxel is filled with one colour. Fully transparent pixels are skipped
+/// and voxels outside the world are dropped.
+pub fn heightmap(
+    image: &Image,
+    origin: [i32; 3],
+    max_height: u32,
+    color: [f32; 4],
+    range: i32,
+) -> Result<Vec<VoxelState>, ImageError> {
+    if max_height == 0 {
+        return Err(ImageError::InvalidValue(
+            "the maximum height must be at least 1".to_string(),
+        ));
+    }
+    let mut voxels = vec![];
+    for row in 0..image.height {
+        for column in 0..image.width {
+            let pixel = image.pixel(column, row);
+            if pixel[3] == 0 {
+                continue;
+            }
+            let [red, green, blue, _alpha] = pixel.map(|channel| channel as f32);
+            let brightness = (0.299 * red + 0.587 * green + 0.114 * blue) / 255.0;
+            let height = (brightness * max_height as f32).round() as i32;
+            let x = origin[0] + column as i32 - image.width as i32 / 2;
+            let z = origin[2] + row as i32 - image.height as i32 / 2;
+            let top = origin[1].saturating_add(height).min(range - 1);
+            for y in origin[1]..=top {
+                let position = [x, y, z];
+                if in_range(position, range) {
+                    voxels.push(VoxelState {
+                        position,
+                        active: true,
+                        color,
+                        fluid: 0,
+                        noise: 0,
+                    });
+                }
+            }
+        }
+    }
+    Ok(voxels)
+}
+
+/// Build a relief from a sprite on a plane, centred on the origin and thickness voxels deep.
+/// Every pixel keeps its colour and alpha. Fully transparent pixels are skipped and
+/// voxels outside the world are dropped.
+pub fn sprite(
+    image: &Image,
+    origin: [i32; 3],
+    plane: Plane,
+    thickness: u32,
+    range: i32,
+) -> Result<Vec<VoxelState>, ImageError> {
+    if thickness == 0 {
+        return Err(ImageError::InvalidValue(
+            "the thickness must be at least 1".to_string(),
+        ));
+    }
+    let mut voxels = vec![];
+    for row in 0..image.height {
+        for column in 0..image.width {
+            let pixel = image.pixel(column, row);
+            if pixel[3] == 0 {
+                continue;
+            }
+            let across = column as i32 - image.width as i32 / 2;
+            let up = (image.height - 1 - row) as i32 - image.height as i32 / 2;
+            for depth in 0..thickness.min(range as u32 * 2) as i32 {
+                let offset = plane.position(across, up, depth);
+                let position = [0, 1, 2].map(|axis| origin[axis] + offset[axis]);
+                if in_range(position, range) {
+                    voxels.push(VoxelState {
+                        position,
+                        active: true,
+                        color: to_color(pixel),
+                        fluid: 0,
+                        noise: 0,
+                    });
+                }
+            }
+        }
+    }
+    Ok(voxels)
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+
+use crate::animation::{LayerAnimation, Pose};
+use crate::mesh::Mesh;
+use crate::octree::Octree;
+use crate::scene_format::SceneFormatError;
+use crate::stored_octree::StoredLayer;
+use crate::voxel_state::VoxelState;
+
+/// Things that can go wrong changing layers.
+#[derive(Debug, PartialEq)]
+pub enum LayerError {
+    /// There is no layer at this index.
+    NoSuchLayer(usize),
+    /// A model always has at least one layer.
+    LastLayer,
+    /// The layer can't be edited until it is unlocked.
+    Locked(String),
+}
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayerError::NoSuchLayer(index) => write!(f, "There is no layer {index}"),
+            LayerError::LastLayer => write!(f, "The last layer can't be removed"),
+            LayerError::Locked(name) => write!(f, "The layer {name} is locked"),
+        }
+    }
+}
+
+/// A named part of the model with its own voxels.
+/// Layers can be hidden, locked against edits and faded.
+#[derive(Clone)]
+pub struct Layer {
+    /// Stays the same when layers are moved or renamed, so edits can find their layer.
+    pub id: u32,
+    pub name: String,
+    /// Hidden layers are not drawn or exported.
+    pub visible: bool,
+    /// Locked layers can't be edited.
+    pub locked: bool,
+    /// Multiplied with the alpha of every voxel in the layer.
+    opacity: f32,
+    /// Keyframes that move the layer over time.
+    pub animation: LayerAnimation,
+    /// The voxels in this layer.
+    voxels: Octree,
+    /// Meshes with the opacity applied, None when they need to be rebuilt.
+    faded: Option<Vec<Mesh>>,
+}
+
+impl Layer {
+    /// Create a new empty layer with a world of this many levels.
+    pub fn new(id: u32, name: String, levels: u32) -> Layer {
+        let mut voxels = Octree::new();
+        voxels.decimate(levels);
+        Layer {
+            id,
+            name,
+            visible: true,
+            locked: false,
+            opacity: 1.0,
+            animation: LayerAnimation::default(),
+            voxels,
+            faded: None,
+        }
+    }
+
+    /// Create a layer from a saved one.
+    pub fn from_stored(id: u32, stored: &StoredLayer) -> Result<Layer, SceneFormatError> {
+        let mut voxels = Octree::new();
+        voxels.load_encoded(&stored.data)?;
+        let mut layer = Layer {
+            id,
+            name: stored.name.clone(),
+            visible: stored.visible,
+            locked: stored.locked,
+            opacity: 1.0,
+            animation: stored.animation.clone(),
+            voxels,
+            faded: None,
+        };
+        layer.set_opacity(stored.opacity);
+        Ok(layer)
+    }
+
+    /// Create a layer from an existing tree of voxels.
+    pub fn from_octree(id: u32, name: String, voxels: Octree) -> Layer {
+        let mut layer = Layer::new(id, name, voxels.levels());
+        layer.voxels = voxels;
+        layer
+    }
+
+    /// Serialize the layer.
+    pub fn prepare(&self) -> StoredLayer {
+        StoredLayer {
+            name: self.name.clone(),
+            visible: self.visible,
+            locked: self.locked,
+            opacity: self.opacity,
+            animation: self.animation.clone(),
+            data: self.voxels.encode(),
+        }
+    }
+
+    /// The voxels in this layer.
+    pub fn voxels(&self) -> &Octree {
+        &self.voxels
+    }
+
+    /// How see through the layer is, from 0 (invisible) to 1 (as painted).
+    pub fn opacity(&self) -> f32 {
+        self.opacity
+    }
+
+    /// Fade the layer.
+    pub fn set_opacity(&mut self, opacity: f32) {
+        self.opacity = if opacity.is_nan() {
+            1.0
+        } else {
+            opacity.clamp(0.0, 1.0)
+        };
+        self.faded = None;
+    }
+
+    /// Get the meshes for the layer with the opacity applied, moved to a pose.
+    pub fn meshes(&mut self, pose: &Pose) -> &Vec<Mesh> {
+        let meshes = if self.opacity >= 1.0 {
+            self.voxels.meshes()
+        } else {
+            let opacity = self.opacity;
+            let voxels = &mut self.voxels;
+            self.faded.get_or_insert_with(|| {
+                voxels
+                    .meshes()
+                    .iter()
+                    .map(|mesh| {
+                        let mut mesh = mesh.clone();
+                        mesh.color[3] *= opacity;
+                        mesh
+                    })
+                    .collect()
+            })
+        };
+        for mesh in meshes.iter_mut() {
+            mesh.translation = pose.translation;
+            mesh.rotation = pose.rotation;
+        }
+        meshes
+    }
+
+    /// Hide all voxels in the layer.
+    pub fn clear(&mut self) {
+        self.voxels.clear();
+        self.faded = None;
+    }
+
+    /// Set the state of a list of voxels and return the states they replaced.
+    pub fn toggle_voxels(
+        &mut self,
+        positions: Vec<[i32; 3]>,
+        value: bool,
+        color: [f32; 4],
+        camera_eye: [f32; 3],
+        fluid: i32,
+        noise: i32,
+    ) -> Vec<VoxelState> {
+        self.faded = None;
+        self.voxels
+            .toggle_voxels(positions, value, color, camera_eye, fluid, noise)
+    }
+
+    /// Restore a list of saved voxel states and return the states they replaced.
+    pub fn set_voxels(&mut self, states: &[VoxelState], camera_eye: [f32; 3]) -> Vec<VoxelState> {
+        self.faded = None;
+        self.voxels.set_voxels(states, camera_eye)
+    }
+
+    /// Call optimize on the nested OcNodes.
+    pub fn optimize(&mut self, camera_eye: [f32; 3]) {
+        self.voxels.optimize(camera_eye);
+    }
+}
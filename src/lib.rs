@@ -8,24 +8,57 @@ use std::panic;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
+mod animation;
 mod camera;
+mod clipboard;
 mod command;
 mod command_queue;
 mod cube;
 mod drawable;
+mod flood;
+mod gltf;
 mod graphics;
 mod grid;
+mod history;
+mod image_import;
+mod layer;
+mod mesh;
+mod mesh_export;
+mod mesh_import;
+mod mesher;
 mod model;
 mod mouse;
 mod ocnode;
 mod octree;
+mod overlay;
+mod picking;
+mod print;
+mod reader;
+mod render;
 mod scene;
+mod scene_file;
+mod scene_format;
+mod settings;
+mod sprite_sheet;
 mod storage;
 mod stored_octree;
+mod symmetry;
+mod terrain;
+mod transform;
+mod vox;
+mod voxel_state;
 
 use crate::graphics::Graphics;
 use crate::scene::Scene;
 use crate::storage::Storage;
+use crate::symmetry::Symmetry;
+use crate::transform::Transform;
+
+thread_local! {
+    /// The renderer lives next to the scene for as long as the page is drawing.
+    /// It can't live inside the scene because WebGL objects can't be shared between threads.
+    static GRAPHICS: RefCell<Option<Graphics>> = const { RefCell::new(None) };
+}
 
 /// Init the scene for the first time.
 #[wasm_bindgen]
@@ -123,16 +156,377 @@ pub fn set_scene_name(name: &str) -> Result<bool, JsValue> {
     Ok(true)
 }
 
+/// Start a new empty scene with a world that is size voxels wide.
+#[wasm_bindgen]
+pub fn new_scene(name: &str, size: u32) -> Result<bool, JsValue> {
+    Scene::new_scene(name.to_string(), size).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Add an empty layer above the active one and make it active. Returns the index of the layer.
+#[wasm_bindgen]
+pub fn add_layer(name: &str) -> Result<usize, JsValue> {
+    Ok(Scene::add_layer(name.to_string()))
+}
+
+/// Remove a layer and all of its voxels.
+#[wasm_bindgen]
+pub fn remove_layer(index: usize) -> Result<bool, JsValue> {
+    Scene::remove_layer(index).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Change the name of a layer.
+#[wasm_bindgen]
+pub fn rename_layer(index: usize, name: &str) -> Result<bool, JsValue> {
+    Scene::rename_layer(index, name.to_string())
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Move a layer to a new position, 0 is the bottom layer.
+#[wasm_bindgen]
+pub fn move_layer(from: usize, to: usize) -> Result<bool, JsValue> {
+    Scene::move_layer(from, to).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Choose the layer that edits go to.
+#[wasm_bindgen]
+pub fn select_layer(index: usize) -> Result<bool, JsValue> {
+    Scene::select_layer(index).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Show or hide a layer.
+#[wasm_bindgen]
+pub fn set_layer_visible(index: usize, visible: bool) -> Result<bool, JsValue> {
+    Scene::set_layer_visible(index, visible)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Lock a layer so it can't be edited, or unlock it.
+#[wasm_bindgen]
+pub fn set_layer_locked(index: usize, locked: bool) -> Result<bool, JsValue> {
+    Scene::set_layer_locked(index, locked)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Set how see through a layer is, from 0 to 1.
+#[wasm_bindgen]
+pub fn set_layer_opacity(index: usize, opacity: f32) -> Result<bool, JsValue> {
+    Scene::set_layer_opacity(index, opacity)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Start playing the layer animations.
+#[wasm_bindgen]
+pub fn play_animation() -> Result<bool, JsValue> {
+    Scene::play_animation();
+    Ok(true)
+}
+
+/// Pause the layer animations.
+#[wasm_bindgen]
+pub fn pause_animation() -> Result<bool, JsValue> {
+    Scene::pause_animation();
+    Ok(true)
+}
+
+/// Jump to a time in seconds.
+#[wasm_bindgen]
+pub fn scrub_animation(time: f32) -> Result<bool, JsValue> {
+    Scene::scrub_animation(time);
+    Ok(true)
+}
+
+/// Get the current animation time in seconds.
+#[wasm_bindgen]
+pub fn animation_time() -> Result<f32, JsValue> {
+    Ok(Scene::animation_time())
+}
+
+/// Get the length of the longest layer animation in seconds.
+#[wasm_bindgen]
+pub fn animation_duration() -> Result<f32, JsValue> {
+    Ok(Scene::animation_duration())
+}
+
+/// Replace the animation of a layer with spin, bob, pulse or slide-in.
+#[wasm_bindgen]
+pub fn apply_animation_preset(layer: usize, preset: &str) -> Result<bool, JsValue> {
+    Scene::apply_animation_preset(layer, preset).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Move a layer to a position at a time in seconds.
+#[wasm_bindgen]
+pub fn add_translation_keyframe(
+    layer: usize,
+    time: f32,
+    x: f32,
+    y: f32,
+    z: f32,
+) -> Result<bool, JsValue> {
+    Scene::add_translation_keyframe(layer, time, [x, y, z])
+        .map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Rotate a layer around the centre of the world at a time in seconds. Angles are in degrees.
+/// The layer turns the shortest way from the keyframe before, so add keyframes less than
+/// half a turn apart for bigger turns.
+#[wasm_bindgen]
+pub fn add_rotation_keyframe(
+    layer: usize,
+    time: f32,
+    x: f32,
+    y: f32,
+    z: f32,
+) -> Result<bool, JsValue> {
+    let rotation = [x.to_radians(), y.to_radians(), z.to_radians()];
+    Scene::add_rotation_keyframe(layer, time, rotation)
+        .map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Show or hide a layer from a time in seconds.
+#[wasm_bindgen]
+pub fn add_visibility_keyframe(layer: usize, time: f32, visible: bool) -> Result<bool, JsValue> {
+    Scene::add_visibility_keyframe(layer, time, visible)
+        .map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Choose if a layer animation starts again after the last keyframe.
+#[wasm_bindgen]
+pub fn set_animation_looping(layer: usize, looping: bool) -> Result<bool, JsValue> {
+    Scene::animate_layer(layer, |animation| animation.looping = looping)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Remove all keyframes from a layer.
+#[wasm_bindgen]
+pub fn clear_animation(layer: usize) -> Result<bool, JsValue> {
+    Scene::animate_layer(layer, |animation| *animation = Default::default())
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Get the layers as a JSON list from bottom to top.
+#[wasm_bindgen]
+pub fn layers() -> Result<JsValue, JsValue> {
+    Ok(JsValue::from_str(&Scene::layers_json()))
+}
+
+/// Export the current scene with its cameras and material settings as a file.
+#[wasm_bindgen]
+pub fn export_scene() -> Result<js_sys::Uint8Array, JsValue> {
+    let bytes = Scene::export_scene();
+    Ok(js_sys::Uint8Array::from(bytes.as_slice()))
+}
+
+/// Replace the current scene with one from a file made by export_scene.
+#[wasm_bindgen]
+pub fn import_scene(bytes: &[u8]) -> Result<bool, JsValue> {
+    Scene::import_scene(bytes).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Export the current scene as a MagicaVoxel .vox file.
+#[wasm_bindgen]
+pub fn export_vox() -> Result<js_sys::Uint8Array, JsValue> {
+    let bytes = Scene::export_vox().map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(js_sys::Uint8Array::from(bytes.as_slice()))
+}
+
+/// Export the visible layers as a Wavefront OBJ mesh. Returns the OBJ text and the
+/// text of the MTL file it uses, which should be saved as `{name}.mtl`.
+#[wasm_bindgen]
+pub fn export_obj(name: &str) -> js_sys::Array {
+    let (obj, mtl) = Scene::export_obj(name);
+    js_sys::Array::of2(&JsValue::from_str(&obj), &JsValue::from_str(&mtl))
+}
+
+/// Export the visible layers as a watertight binary PLY mesh with a colour on every face.
+#[wasm_bindgen]
+pub fn export_ply() -> js_sys::Uint8Array {
+    js_sys::Uint8Array::from(Scene::export_ply().as_slice())
+}
+
+/// Export the visible layers as a binary glTF (.glb) file. If per_layer is true
+/// every layer gets its own node, otherwise the layers are merged into one.
+#[wasm_bindgen]
+pub fn export_glb(per_layer: bool) -> js_sys::Uint8Array {
+    js_sys::Uint8Array::from(Scene::export_glb(per_layer).as_slice())
+}
+
+/// Check how the visible layers would print with JSON print settings. Returns a JSON
+/// report with the separate parts, the non-manifold edges and corners found and
+/// how many voxels fixing and hollowing would change.
+#[wasm_bindgen]
+pub fn print_report(settings: &str) -> Result<String, JsValue> {
+    Scene::print_report(settings).map_err(|error| JsValue::from_str(&error))
+}
+
+/// Export the visible layers as a manifold binary STL file in mm, ready to slice.
+/// The settings are the same JSON as `print_report`.
+#[wasm_bindgen]
+pub fn export_print_stl(settings: &str) -> Result<js_sys::Uint8Array, JsValue> {
+    let bytes = Scene::export_print_stl(settings).map_err(|error| JsValue::from_str(&error))?;
+    Ok(js_sys::Uint8Array::from(bytes.as_slice()))
+}
+
+/// Render the visible layers from the camera as a PNG without using the graphics card.
+/// The background is the editor's background colour, or clear if transparent is true.
+#[wasm_bindgen]
+pub fn render_png(
+    width: u32,
+    height: u32,
+    transparent: bool,
+) -> Result<js_sys::Uint8Array, JsValue> {
+    let bytes =
+        Scene::render_png(width, height, transparent).map_err(|error| JsValue::from_str(&error))?;
+    Ok(js_sys::Uint8Array::from(bytes.as_slice()))
+}
+
+/// Draw the visible layers from several directions into an isometric sprite sheet using
+/// JSON settings. Returns the sheet as a PNG and a JSON atlas of where every frame is.
+#[wasm_bindgen]
+pub fn export_sprite_sheet(settings: &str) -> Result<js_sys::Array, JsValue> {
+    let (png, atlas) =
+        Scene::export_sprite_sheet(settings).map_err(|error| JsValue::from_str(&error))?;
+    Ok(js_sys::Array::of2(
+        &js_sys::Uint8Array::from(png.as_slice()),
+        &JsValue::from_str(&atlas),
+    ))
+}
+
+/// Add the voxels from a MagicaVoxel .vox file to the current scene.
+#[wasm_bindgen]
+pub fn import_vox(bytes: &[u8]) -> Result<bool, JsValue> {
+    Scene::import_vox(bytes).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    Ok(true)
+}
+
+/// Fill the active layer with terrain. The settings are JSON with any of seed, min_height,
+/// max_height, scale, octaves, persistence, lacunarity, caves, cave_scale, cave_threshold,
+/// water_level, water_color, bands (a list of up_to and color), cliff_slope and cliff_color.
+/// Returns how many voxels were added.
+#[wasm_bindgen]
+pub fn generate_terrain(settings: &str) -> Result<usize, JsValue> {
+    Scene::generate_terrain(settings).map_err(|error| JsValue::from_str(&error))
+}
+
+/// Add terrain from a grayscale PNG heightmap, centred on the selection.
+/// White pixels are max_height voxels above the selection. Returns how many voxels were added.
+#[wasm_bindgen]
+pub fn import_heightmap(bytes: &[u8], max_height: u32) -> Result<usize, JsValue> {
+    Scene::import_heightmap(bytes, max_height).map_err(|error| JsValue::from_str(&error))
+}
+
+/// Add a PNG sprite as a relief thickness voxels deep on the "xy", "xz" or "yz" plane
+/// through the selection. Returns how many voxels were added.
+#[wasm_bindgen]
+pub fn import_sprite(bytes: &[u8], plane: &str, thickness: u32) -> Result<usize, JsValue> {
+    Scene::import_sprite(bytes, plane, thickness).map_err(|error| JsValue::from_str(&error))
+}
+
+/// Voxelize an OBJ mesh, with colours from its vertices or an MTL file, scaled to fill the world.
+/// If solid is true the inside of closed meshes is filled too. Returns how many voxels were added.
+#[wasm_bindgen]
+pub fn import_obj(obj: &str, mtl: &str, solid: bool) -> Result<usize, JsValue> {
+    Scene::import_obj(obj, mtl, solid).map_err(|error| JsValue::from_str(&error))
+}
+
+/// Voxelize a binary or ASCII STL mesh scaled to fill the world.
+/// If solid is true the inside of closed meshes is filled too. Returns how many voxels were added.
+#[wasm_bindgen]
+pub fn import_stl(bytes: &[u8], solid: bool) -> Result<usize, JsValue> {
+    Scene::import_stl(bytes, solid).map_err(|error| JsValue::from_str(&error))
+}
+
+/// Undo the last voxel edit.
+#[wasm_bindgen]
+pub fn undo() -> Result<bool, JsValue> {
+    Scene::undo();
+    Ok(true)
+}
+
+/// Redo the last undone voxel edit.
+#[wasm_bindgen]
+pub fn redo() -> Result<bool, JsValue> {
+    Scene::redo();
+    Ok(true)
+}
+
+/// Select the voxels in the active layer connected to the one in the middle of the selection.
+/// Neighbours are 6 (faces) or 26 (faces, edges and corners). Matching is "occupancy",
+/// "color" (within the tolerance on every channel) or "material" (same fluid and noise).
+/// Returns how many voxels were selected.
+#[wasm_bindgen]
+pub fn select_connected(neighbours: u32, matching: &str, tolerance: f32) -> Result<usize, JsValue> {
+    Scene::select_connected(neighbours, matching, tolerance)
+        .map_err(|error| JsValue::from_str(&error))
+}
+
+/// Forget the connected voxel selection.
+#[wasm_bindgen]
+pub fn clear_selected() -> Result<bool, JsValue> {
+    Scene::clear_selected();
+    Ok(true)
+}
+
+/// Paint the selected voxels with the current material.
+#[wasm_bindgen]
+pub fn recolor_selected() -> Result<bool, JsValue> {
+    Scene::recolor_selected();
+    Ok(true)
+}
+
+/// Remove the selected voxels.
+#[wasm_bindgen]
+pub fn delete_selected() -> Result<bool, JsValue> {
+    Scene::delete_selected();
+    Ok(true)
+}
+
+/// Move the selected voxels. Fails if any would leave the world.
+#[wasm_bindgen]
+pub fn move_selected(x: i32, y: i32, z: i32) -> Result<bool, JsValue> {
+    Scene::move_selected([x, y, z]).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Choose what clicking picks, "adjacent" for the empty cell in front of a voxel
+/// or "voxel" for the voxel itself.
+#[wasm_bindgen]
+pub fn set_pick_target(target: &str) -> Result<bool, JsValue> {
+    Scene::set_pick_target(target).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
 /// Load the default scene when the page loads.
 #[wasm_bindgen]
 pub async fn load_first_scene() -> Result<JsValue, JsValue> {
     Scene::load_first_scene().await;
+    GRAPHICS.with_borrow_mut(|graphics| {
+        graphics.get_or_insert_with(Graphics::new);
+    });
     type DynFunc = Rc<RefCell<Option<Closure<dyn FnMut()>>>>;
     let f: DynFunc = Rc::new(RefCell::new(None));
     let outer_f = f.clone();
 
     let window = web_sys::window().unwrap();
     *outer_f.borrow_mut() = Some(Closure::wrap(Box::new(move || {
+        // Stop the loop once the renderer has been released.
+        if GRAPHICS.with_borrow(|graphics| graphics.is_none()) {
+            return;
+        }
         draw_scene();
 
         // We choose to render when idle, not as fast as possible so we don't overload the browser.
@@ -152,15 +546,28 @@ pub async fn load_first_scene() -> Result<JsValue, JsValue> {
 /// Draw a new frame for the current scene.
 pub fn draw_scene() {
     if !Scene::throttle() {
-        let mut graphics: Graphics = Graphics::new();
-        graphics.setup_shaders();
-        Scene::process_commands();
-        graphics.clear();
+        GRAPHICS.with_borrow_mut(|graphics_opt| {
+            if let Some(graphics) = graphics_opt {
+                Scene::process_commands();
+                if graphics.begin_frame() {
+                    graphics.clear();
 
-        Scene::draw(&graphics);
+                    Scene::draw(graphics);
+                }
+            }
+        });
     }
 }
 
+/// Stop drawing and free all the GPU resources.
+#[wasm_bindgen]
+pub fn release_graphics() -> Result<bool, JsValue> {
+    GRAPHICS.with_borrow_mut(|graphics| {
+        graphics.take();
+    });
+    Ok(true)
+}
+
 /// Change the selection shape.
 #[wasm_bindgen]
 pub fn toggle_selection_shape() -> Result<bool, JsValue> {
@@ -169,6 +576,97 @@ pub fn toggle_selection_shape() -> Result<bool, JsValue> {
     Ok(true)
 }
 
+/// Choose what using the selection does: "toggle", "add", "erase", "paint" or "eyedropper".
+#[wasm_bindgen]
+pub fn set_paint_mode(mode: &str) -> Result<bool, JsValue> {
+    Scene::set_paint_mode(mode).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// What using the selection does.
+#[wasm_bindgen]
+pub fn paint_mode() -> Result<String, JsValue> {
+    Ok(Scene::paint_mode())
+}
+
+/// Mirror every edit across a plane at right angles to the "x", "y" or "z" axis.
+/// The plane is at a position along the axis, in voxels from the world origin.
+#[wasm_bindgen]
+pub fn set_mirror(axis: &str, enabled: bool, position: f32) -> Result<bool, JsValue> {
+    Scene::set_mirror(axis, enabled, position).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Copy the voxels in the selection of the active layer.
+#[wasm_bindgen]
+pub fn copy_selection() -> Result<bool, JsValue> {
+    Scene::copy();
+    Ok(true)
+}
+
+/// Copy the voxels in the selection of the active layer and remove them.
+#[wasm_bindgen]
+pub fn cut_selection() -> Result<bool, JsValue> {
+    Scene::cut();
+    Ok(true)
+}
+
+/// Paste the copied voxels at the selection. Merging keeps the voxels already there.
+#[wasm_bindgen]
+pub fn paste(merge: bool) -> Result<bool, JsValue> {
+    Scene::paste(merge);
+    Ok(true)
+}
+
+/// The copied voxels as JSON so they can be pasted into another scene.
+#[wasm_bindgen]
+pub fn clipboard() -> Result<String, JsValue> {
+    Ok(Scene::clipboard())
+}
+
+/// Replace the copied voxels with JSON from `clipboard`.
+#[wasm_bindgen]
+pub fn set_clipboard(json: &str) -> Result<bool, JsValue> {
+    Scene::set_clipboard(json).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
+/// Move the voxels in the box around the selection.
+#[wasm_bindgen]
+pub fn translate_region(x: i32, y: i32, z: i32) -> Result<bool, JsValue> {
+    transform_region(Transform::Translate([x, y, z]))
+}
+
+/// Turn the voxels in the box around the selection by quarter turns around the "x", "y" or "z" axis.
+#[wasm_bindgen]
+pub fn rotate_region(axis: &str, turns: i32) -> Result<bool, JsValue> {
+    let axis = Symmetry::axis_from_name(axis).map_err(|error| JsValue::from_str(&error))?;
+    transform_region(Transform::Rotate { axis, turns })
+}
+
+/// Mirror the voxels in the box around the selection along the "x", "y" or "z" axis.
+#[wasm_bindgen]
+pub fn flip_region(axis: &str) -> Result<bool, JsValue> {
+    let axis = Symmetry::axis_from_name(axis).map_err(|error| JsValue::from_str(&error))?;
+    transform_region(Transform::Flip(axis))
+}
+
+/// Scale the voxels in the box around the selection up or down by a whole number.
+#[wasm_bindgen]
+pub fn scale_region(factor: u32, shrink: bool) -> Result<bool, JsValue> {
+    if shrink {
+        transform_region(Transform::Shrink(factor))
+    } else {
+        transform_region(Transform::Grow(factor))
+    }
+}
+
+/// Apply a transform and report why it could not be applied.
+fn transform_region(transform: Transform) -> Result<bool, JsValue> {
+    Scene::transform(transform).map_err(|error| JsValue::from_str(&error))?;
+    Ok(true)
+}
+
 /// Change what colour we use.
 #[wasm_bindgen]
 pub fn set_material_color(
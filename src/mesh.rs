@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::drawable::Drawable;
+
+/// The id for the next mesh that is created.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A mesh is a drawable list of triangles that all share one material.
+/// Meshes are built before they are drawn, once drawn the triangles stay on the GPU.
+#[derive(Clone)]
+pub struct Mesh {
+    /// Copies keep the id, they have the same triangles.
+    id: u64,
+    pub vertices: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub translation: [f32; 3],
+    pub rotation: [f32; 3],
+    pub color: [f32; 4],
+    pub fluid: i32,
+    pub noise: i32,
+}
+
+impl Mesh {
+    /// Create a new empty mesh with a material.
+    pub fn new(color: [f32; 4], fluid: i32, noise: i32) -> Mesh {
+        Mesh {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            color,
+            fluid,
+            noise,
+        }
+    }
+
+    /// Add a quad as 2 triangles. The corners must be counter clockwise when
+    /// viewed from the side the normal points to.
+    pub fn push_quad(&mut self, corners: &[[f32; 3]; 4], normal: [f32; 3]) {
+        for index in [0, 1, 2, 0, 2, 3] {
+            self.vertices.extend_from_slice(&corners[index]);
+            self.normals.extend_from_slice(&normal);
+        }
+    }
+}
+
+impl Drawable for Mesh {
+    /// The vertices are built before the mesh is drawn.
+    fn init(&mut self) {}
+
+    /// One value per coordinate, same as the other drawables.
+    fn count_vertices(&self) -> u32 {
+        self.vertices.len() as u32
+    }
+
+    /// Where is the mesh.
+    fn translation(&self) -> &[f32; 3] {
+        &self.translation
+    }
+
+    /// How is the mesh rotated?
+    fn rotation(&self) -> &[f32; 3] {
+        &self.rotation
+    }
+
+    /// Move the mesh.
+    fn translate(&mut self, amount: [f32; 3]) {
+        self.translation[0] += amount[0];
+        self.translation[1] += amount[1];
+        self.translation[2] += amount[2];
+    }
+
+    /// Rotate the mesh.
+    fn rotate(&mut self, amount: [f32; 3]) {
+        self.rotation[0] += amount[0];
+        self.rotation[1] += amount[1];
+        self.rotation[2] += amount[2];
+    }
+
+    /// Get the triangles.
+    fn vertices(&self) -> Vec<f32> {
+        self.vertices.clone()
+    }
+
+    /// Get a normal for every vertex.
+    fn normals(&self) -> Vec<f32> {
+        self.normals.clone()
+    }
+
+    /// Every triangle in the mesh has the same colour.
+    fn color(&self) -> &[f32; 4] {
+        &self.color
+    }
+
+    /// Calculate the distance from the camera to the mesh.
+    fn depth(&self, camera: [f32; 3]) -> f32 {
+        ((self.translation[0] - camera[0]).powi(2)
+            + (self.translation[1] - camera[1]).powi(2)
+            + (self.translation[2] - camera[2]).powi(2))
+        .sqrt()
+    }
+
+    fn fluid(&self) -> i32 {
+        self.fluid
+    }
+
+    fn noise(&self) -> i32 {
+        self.noise
+    }
+
+    fn buffer_id(&self) -> Option<u64> {
+        Some(self.id)
+    }
+}
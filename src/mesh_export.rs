@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::mesher::{Quad, DIRECTIONS};
+
+/// Turn a colour channel into a byte.
+pub fn to_byte(channel: f32) -> u8 {
+    (channel.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// The index of the direction a normal points in, matching `DIRECTIONS`.
+fn direction_index(normal: [f32; 3]) -> usize {
+    DIRECTIONS
+        .iter()
+        .position(|(axis, sign)| normal[*axis] == *sign as f32)
+        .unwrap_or(0)
+}
+
+/// Write quads as a Wavefront OBJ file and the MTL file it uses, named mtl_name.
+/// Corners at the same place are shared so the surface is watertight, and each
+/// distinct colour gets its own material. Returns (obj, mtl).
+pub fn obj(quads: &[Quad], mtl_name: &str) -> (String, String) {
+    let mut positions: Vec<[f32; 3]> = vec![];
+    let mut position_indexes: HashMap<[u32; 3], usize> = HashMap::new();
+    let mut colors: Vec<[f32; 4]> = vec![];
+    let mut faces: HashMap<[u32; 4], Vec<([usize; 4], usize)>> = HashMap::new();
+
+    for quad in quads {
+        let corners = quad.corners.map(|corner| {
+            *position_indexes
+                .entry(corner.map(f32::to_bits))
+                .or_insert_with(|| {
+                    positions.push(corner);
+                    positions.len()
+                })
+        });
+        let key = quad.color.map(f32::to_bits);
+        if !faces.contains_key(&key) {
+            colors.push(quad.color);
+        }
+        faces
+            .entry(key)
+            .or_default()
+            .push((corners, direction_index(quad.normal) + 1));
+    }
+
+    let mut obj = String::new();
+    let mut mtl = String::new();
+    writeln!(obj, "mtllib {mtl_name}").unwrap();
+    for [x, y, z] in &positions {
+        writeln!(obj, "v {x} {y} {z}").unwrap();
+    }
+    for (axis, sign) in DIRECTIONS {
+        let mut normal = [0; 3];
+        normal[axis] = sign;
+        writeln!(obj, "vn {} {} {}", normal[0], normal[1], normal[2]).unwrap();
+    }
+    for (index, color) in colors.iter().enumerate() {
+        writeln!(obj, "usemtl color_{index}").unwrap();
+        for ([a, b, c, d], normal) in &faces[&color.map(f32::to_bits)] {
+            writeln!(
+                obj,
+                "f {a}//{normal} {b}//{normal} {c}//{normal} {d}//{normal}"
+            )
+            .unwrap();
+        }
+
+        let [red, green, blue, alpha] = color.map(|channel| channel.clamp(0.0, 1.0));
+        writeln!(mtl, "newmtl color_{index}").unwrap();
+        writeln!(mtl, "Kd {red} {green} {blue}").unwrap();
+        writeln!(mtl, "d {alpha}").unwrap();
+        writeln!(mtl, "illum 1").unwrap();
+    }
+    (obj, mtl)
+}
+
+/// Write quads as a binary PLY file with an RGBA colour on every face.
+/// Corners at the same place are shared, even between colours, so the surface
+/// is watertight.
+pub fn ply(quads: &[Quad]) -> Vec<u8> {
+    let mut vertices: Vec<[f32; 3]> = vec![];
+    let mut vertex_indexes: HashMap<[u32; 3], u32> = HashMap::new();
+    let mut faces: Vec<([u32; 4], [u8; 4])> = vec![];
+
+    for quad in quads {
+        let corners = quad.corners.map(|corner| {
+            *vertex_indexes
+                .entry(corner.map(f32::to_bits))
+                .or_insert_with(|| {
+                    vertices.push(corner);
+                    vertices.len() as u32 - 1
+                })
+        });
+        faces.push((corners, quad.color.map(to_byte)));
+    }
+
+    let header = format!(
+        "ply\n\
+         format binary_little_endian 1.0\n\
+         comment exported from creator\n\
+         element vertex {}\n\
+         property float x\n\
+         property float y\n\
+         property float z\n\
+         element face {}\n\
+         property list uchar uint vertex_indices\n\
+         property uchar red\n\
+         property uchar green\n\
+         property uchar blue\n\
+         property uchar alpha\n\
+         end_header\n",
+        vertices.len(),
+        faces.len()
+    );
+    let mut bytes = header.into_bytes();
+    for position in &vertices {
+        for value in position {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    for (face, color) in &faces {
+        bytes.push(4);
+        for index in face {
+            bytes.extend_from_slice(&index.to_le_bytes());
+        }
+        bytes.extend_from_slice(color);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesher;
+    use crate::ocnode::Ocnode;
+    use crate::voxel_state::VoxelState;
+
+    /// The quads of one voxel with its smallest corner at the origin.
+    fn cube_quads(colors: &[[f32; 4]]) -> Vec<Quad> {
+        let mut root = Ocnode::new(3);
+        let voxels: Vec<VoxelState> = colors
+            .iter()
+            .enumerate()
+            .map(|(index, color)| VoxelState {
+                position: [index as i32, 0, 0],
+                active: true,
+                color: *color,
+                fluid: 0,
+                noise: 0,
+            })
+            .collect();
+        root.set_voxels(&voxels);
+        mesher::face_quads(&root)
+    }
+
+    #[test]
+    fn a_cube_is_8_vertices_and_6_faces() {
+        let (obj, mtl) = obj(&cube_quads(&[[1.0, 0.5, 0.0, 1.0]]), "cube.mtl");
+        let lines: Vec<&str> = obj.lines().collect();
+        assert_eq!(lines[0], "mtllib cube.mtl");
+        let positions: Vec<[f32; 3]> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("v "))
+            .map(|line| {
+                let values: Vec<f32> = line
+                    .split(' ')
+                    .map(|value| value.parse().unwrap())
+                    .collect();
+                [values[0], values[1], values[2]]
+            })
+            .collect();
+        assert_eq!(positions.len(), 8);
+        assert_eq!(
+            lines.iter().filter(|line| line.starts_with("vn ")).count(),
+            6
+        );
+
+        let faces: Vec<&&str> = lines.iter().filter(|line| line.starts_with("f ")).collect();
+        assert_eq!(faces.len(), 6);
+        let mut normals = vec![];
+        for face in faces {
+            let corners: Vec<(usize, usize)> = face[2..]
+                .split(' ')
+                .map(|corner| {
+                    let (vertex, normal) = corner.split_once("//").unwrap();
+                    (vertex.parse().unwrap(), normal.parse().unwrap())
+                })
+                .collect();
+            let normal = corners[0].1;
+            assert!(corners.iter().all(|(_, other)| *other == normal));
+            // Every corner lies on the side of the cube the normal points to.
+            let (axis, sign) = DIRECTIONS[normal - 1];
+            let side = if sign > 0 { 1.0 } else { 0.0 };
+            assert!(corners
+                .iter()
+                .all(|(vertex, _)| positions[vertex - 1][axis] == side));
+            normals.push(normal);
+        }
+        normals.sort();
+        assert_eq!(normals, [1, 2, 3, 4, 5, 6]);
+        assert!(mtl.contains("newmtl color_0\nKd 1 0.5 0\nd 1\n"));
+    }
+
+    #[test]
+    fn ply_shares_corners_between_colours() {
+        let header_end = |bytes: &[u8]| {
+            let end = b"end_header\n";
+            bytes
+                .windows(end.len())
+                .position(|window| window == end)
+                .unwrap()
+                + end.len()
+        };
+        let bytes = ply(&cube_quads(&[[1.0, 0.0, 0.0, 1.0]]));
+        let start = header_end(&bytes);
+        let header = std::str::from_utf8(&bytes[..start]).unwrap();
+        assert!(header.contains("element vertex 8\n"));
+        assert!(header.contains("element face 6\n"));
+        assert_eq!(bytes.len(), start + 8 * 12 + 6 * (1 + 4 * 4 + 4));
+        assert_eq!(&bytes[bytes.len() - 4..], [255, 0, 0, 255]);
+
+        // Two voxels of different colours side by side share the 4 corners between them.
+        let bytes = ply(&cube_quads(&[[1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]]));
+        let header = std::str::from_utf8(&bytes[..header_end(&bytes)]).unwrap();
+        assert!(header.contains("element vertex 12\n"));
+        assert!(header.contains("element face 10\n"));
+    }
+}
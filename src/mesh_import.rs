@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use nalgebra::Vector3;
+
+use crate::voxel_state::VoxelState;
+
+/// Things that can go wrong importing a polygon mesh.
+#[derive(Debug, PartialEq)]
+pub enum MeshImportError {
+    /// The file could not be parsed.
+    Malformed(String),
+    /// There are no triangles, or they are all at one point.
+    Empty,
+}
+
+impl fmt::Display for MeshImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeshImportError::Malformed(reason) => write!(f, "The mesh is malformed: {reason}"),
+            MeshImportError::Empty => write!(f, "The mesh has no triangles with any size"),
+        }
+    }
+}
+
+/// One triangle of an imported mesh.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Triangle {
+    pub vertices: [[f32; 3]; 3],
+    pub color: [f32; 4],
+}
+
+/// Parse a number from a file, naming what it was for if it is not a number.
+fn parse_number(text: Option<&str>, what: &str) -> Result<f32, MeshImportError> {
+    text.and_then(|text| text.parse::<f32>().ok())
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| MeshImportError::Malformed(format!("expected a number for {what}")))
+}
+
+/// Read the diffuse colours of the materials in an MTL file.
+fn parse_mtl(mtl: &str) -> Result<HashMap<String, [f32; 4]>, MeshImportError> {
+    let mut materials: HashMap<String, [f32; 4]> = HashMap::new();
+    let mut current: Option<String> = None;
+    for line in mtl.lines() {
+        let mut words = line.split_whitespace();
+        match (words.next(), current.as_ref()) {
+            (Some("newmtl"), _) => {
+                let name = words.collect::<Vec<&str>>().join(" ");
+                materials.insert(name.clone(), [1.0; 4]);
+                current = Some(name);
+            }
+            (Some("Kd"), Some(name)) => {
+                let color = materials
+                    .get_mut(name)
+                    .expect("The material was just added");
+                for channel in color.iter_mut().take(3) {
+                    *channel = parse_number(words.next(), "Kd")?.clamp(0.0, 1.0);
+                }
+            }
+            (Some("d"), Some(name)) => {
+                let color = materials
+                    .get_mut(name)
+                    .expect("The material was just added");
+                color[3] = parse_number(words.next(), "d")?.clamp(0.0, 1.0);
+            }
+            (Some("Tr"), Some(name)) => {
+                let color = materials
+                    .get_mut(name)
+                    .expect("The material was just added");
+                color[3] = 1.0 - parse_number(words.next(), "Tr")?.clamp(0.0, 1.0);
+            }
+            _ => {}
+        }
+    }
+    Ok(materials)
+}
+
+/// Find a vertex from an OBJ face index, which counts from 1 or back from the end if negative.
+fn obj_vertex(index: &str, count: usize) -> Result<usize, MeshImportError> {
+    let position = index.split('/').next().unwrap_or("");
+    let value: i64 = position
+        .parse()
+        .map_err(|_| MeshImportError::Malformed(format!("bad face index {index}")))?;
+    let resolved = if value < 0 {
+        count as i64 + value
+    } else {
+        value - 1
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(MeshImportError::Malformed(format!(
+            "face index {index} is out of range"
+        )));
+    }
+    Ok(resolved as usize)
+}
+
+/// Parse the triangles of an OBJ file. Faces use the diffuse colour of their MTL material,
+/// or the average of their vertex colours if the vertices have them, or the default colour.
+/// Polygons with more than 3 corners are split into a fan of triangles.
+pub fn parse_obj(
+    obj: &str,
+    mtl: &str,
+    default_color: [f32; 4],
+) -> Result<Vec<Triangle>, MeshImportError> {
+    let materials = parse_mtl(mtl)?;
+    let mut positions: Vec<[f32; 3]> = vec![];
+    let mut colors: Vec<Option<[f32; 3]>> = vec![];
+    let mut material: Option<[f32; 4]> = None;
+    let mut triangles = vec![];
+
+    for line in obj.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("v") => {
+                let values: Vec<&str> = words.collect();
+                let mut position = [0.0; 3];
+                for (axis, value) in position.iter_mut().enumerate() {
+                    *value = parse_number(values.get(axis).copied(), "a vertex")?;
+                }
+                positions.push(position);
+                colors.push(if values.len() >= 6 {
+                    let mut color = [0.0; 3];
+                    for (channel, value) in color.iter_mut().enumerate() {
+                        *value = parse_number(values.get(3 + channel).copied(), "a vertex colour")?
+                            .clamp(0.0, 1.0);
+                    }
+                    Some(color)
+                } else {
+                    None
+                });
+            }
+            Some("usemtl") => {
+                let name = words.collect::<Vec<&str>>().join(" ");
+                material = materials.get(&name).copied();
+            }
+            Some("f") => {
+                let corners = words
+                    .map(|index| obj_vertex(index, positions.len()))
+                    .collect::<Result<Vec<usize>, MeshImportError>>()?;
+                if corners.len() < 3 {
+                    return Err(MeshImportError::Malformed(
+                        "a face has fewer than 3 corners".to_string(),
+                    ));
+                }
+                for fan in 1..corners.len() - 1 {
+                    let indexes = [corners[0], corners[fan], corners[fan + 1]];
+                    let vertex_colors: Option<Vec<[f32; 3]>> =
+                        indexes.iter().map(|index| colors[*index]).collect();
+                    let color = match (material, vertex_colors) {
+                        (Some(color), _) => color,
+                        (None, Some(vertex_colors)) => {
+                            let mut average = [0.0, 0.0, 0.0, 1.0];
+                            for color in vertex_colors {
+                                for channel in 0..3 {
+                                    average[channel] += color[channel] / 3.0;
+                                }
+                            }
+                            average
+                        }
+                        (None, None) => default_color,
+                    };
+                    triangles.push(Triangle {
+                        vertices: indexes.map(|index| positions[index]),
+                        color,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(triangles)
+}
+
+/// Read a little endian float from binary STL data.
+fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Parse the triangles of a binary or ASCII STL file. Binary files may colour
+/// triangles with the 15 bit colours used by VisCAM and SolidView.
+pub fn parse_stl(bytes: &[u8], default_color: [f32; 4]) -> Result<Vec<Triangle>, MeshImportError> {
+    if bytes.len() >= 84 {
+        let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+        if count
+            .checked_mul(50)
+            .and_then(|size| size.checked_add(84))
+            .is_some_and(|size| size == bytes.len())
+        {
+            return Ok(parse_binary_stl(&bytes[84..], default_color));
+        }
+    }
+    let text = std::str::from_utf8(bytes).map_err(|_| {
+        MeshImportError::Malformed("the STL file is not text or binary".to_string())
+    })?;
+    if !text.trim_start().starts_with("solid") {
+        return Err(MeshImportError::Malformed(
+            "the STL file is truncated".to_string(),
+        ));
+    }
+    parse_ascii_stl(text, default_color)
+}
+
+/// Parse the 50 byte triangle records of a binary STL file.
+fn parse_binary_stl(records: &[u8], default_color: [f32; 4]) -> Vec<Triangle> {
+    records
+        .chunks_exact(50)
+        .map(|record| {
+            // Skip the 12 bytes of normal, the winding gives the same thing.
+            let vertices = [0, 1, 2]
+                .map(|corner| [0, 1, 2].map(|axis| read_f32(record, 12 + corner * 12 + axis * 4)));
+            let attribute = u16::from_le_bytes([record[48], record[49]]);
+            let color = if attribute & 0x8000 != 0 {
+                let channel = |shift: u16| ((attribute >> shift) & 31) as f32 / 31.0;
+                [channel(10), channel(5), channel(0), 1.0]
+            } else {
+                default_color
+            };
+            Triangle { vertices, color }
+        })
+        .filter(|triangle| {
+            triangle
+                .vertices
+                .iter()
+                .flatten()
+                .all(|value| value.is_finite())
+        })
+        .collect()
+}
+
+/// Parse the facets of an ASCII STL file.
+fn parse_ascii_stl(text: &str, default_color: [f32; 4]) -> Result<Vec<Triangle>, MeshImportError> {
+    let mut triangles = vec![];
+    let mut corners: Vec<[f32; 3]> = vec![];
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("vertex") => {
+                let mut corner = [0.0; 3];
+                for value in corner.iter_mut() {
+                    *value = parse_number(words.next(), "a vertex")?;
+                }
+                corners.push(corner);
+            }
+            Some("endfacet") => {
+                if corners.len() != 3 {
+                    return Err(MeshImportError::Malformed(
+                        "a facet does not have 3 vertices".to_string(),
+                    ));
+                }
+                triangles.push(Triangle {
+                    vertices: [corners[0], corners[1], corners[2]],
+                    color: default_color,
+                });
+                corners.clear();
+            }
+            _ => {}
+        }
+    }
+    Ok(triangles)
+}
+
+/// Does a triangle touch a box? Uses the separating axis test from Akenine-Möller.
+fn triangle_box_overlap(center: Vector3<f32>, half: f32, triangle: &[Vector3<f32>; 3]) -> bool {
+    let [v0, v1, v2] = triangle.map(|vertex| vertex - center);
+    let edges = [v1 - v0, v2 - v1, v0 - v2];
+    let extent = |axis: &Vector3<f32>| half * (axis.x.abs() + axis.y.abs() + axis.z.abs());
+    let separated = |axis: &Vector3<f32>| {
+        let projected = [v0.dot(axis), v1.dot(axis), v2.dot(axis)];
+        let min = projected.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = projected.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let radius = extent(axis);
+        min > radius || max < -radius
+    };
+
+    // The faces of the box.
+    for axis in [Vector3::x(), Vector3::y(), Vector3::z()] {
+        if separated(&axis) {
+            return false;
+        }
+    }
+    // The plane of the triangle.
+    let normal = edges[0].cross(&edges[1]);
+    if normal.dot(&v0).abs() > extent(&normal) {
+        return false;
+    }
+    // Every edge of the triangle crossed with every edge of the box.
+    for edge in &edges {
+        for axis in [Vector3::x(), Vector3::y(), Vector3::z()] {
+            let cross = axis.cross(edge);
+            if cross.norm_squared() > f32::EPSILON && separated(&cross) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Scale and move triangles so the largest side fits a world where positions
+/// go from -range to +range, centred on the origin.
+fn fit(triangles: &[Triangle], range: i32) -> Result<Vec<[Vector3<f32>; 3]>, MeshImportError> {
+    let mut min = Vector3::repeat(f32::INFINITY);
+    let mut max = Vector3::repeat(f32::NEG_INFINITY);
+    for vertex in triangles.iter().flat_map(|triangle| triangle.vertices) {
+        let vertex = Vector3::from(vertex);
+        min = min.inf(&vertex);
+        max = max.sup(&vertex);
+    }
+    let largest = (max - min).max();
+    if triangles.is_empty() || largest <= 0.0 {
+        return Err(MeshImportError::Empty);
+    }
+    // Stay a tiny bit inside so the far side does not land on the next voxel.
+    let scale = (range as f32 * 2.0 - 0.001) / largest;
+    let center = (min + max) / 2.0;
+    Ok(triangles
+        .iter()
+        .map(|triangle| {
+            triangle
+                .vertices
+                .map(|vertex| (Vector3::from(vertex) - center) * scale)
+        })
+        .collect())
+}
+
+/// Turn triangles into voxels scaled to fit a world where positions go from -range to +range.
+/// Every voxel a triangle touches is filled with its colour. If solid is true the inside
+/// of closed meshes is filled too, using the colour of the surface above each gap.
+pub fn voxelize(
+    triangles: &[Triangle],
+    range: i32,
+    solid: bool,
+) -> Result<Vec<VoxelState>, MeshImportError> {
+    let fitted = fit(triangles, range)?;
+    let cell_of = |value: f32| (value.floor() as i32).clamp(-range, range - 1);
+    let mut filled: HashMap<[i32; 3], [f32; 4]> = HashMap::new();
+
+    for (corners, triangle) in fitted.iter().zip(triangles) {
+        let low = [0, 1, 2].map(|axis| {
+            cell_of(
+                corners
+                    .iter()
+                    .map(|c| c[axis])
+                    .fold(f32::INFINITY, f32::min),
+            )
+        });
+        let high = [0, 1, 2].map(|axis| {
+            cell_of(
+                corners
+                    .iter()
+                    .map(|c| c[axis])
+                    .fold(f32::NEG_INFINITY, f32::max),
+            )
+        });
+        for x in low[0]..=high[0] {
+            for y in low[1]..=high[1] {
+                for z in low[2]..=high[2] {
+                    let center = Vector3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5);
+                    if triangle_box_overlap(center, 0.5, corners) {
+                        filled.entry([x, y, z]).or_insert(triangle.color);
+                    }
+                }
+            }
+        }
+    }
+
+    if solid {
+        fill_inside(&fitted, triangles, range, &mut filled);
+    }
+
+    let mut voxels: Vec<VoxelState> = filled
+        .into_iter()
+        .map(|(position, color)| VoxelState {
+            position,
+            active: true,
+            color,
+            fluid: 0,
+            noise: 0,
+        })
+        .collect();
+    voxels.sort_by_key(|voxel| voxel.position);
+    Ok(voxels)
+}
+
+/// Fill the inside of a closed mesh. A ray goes up through the middle of every column
+/// and the voxels between each crossing into the mesh and the next crossing out are filled.
+fn fill_inside(
+    fitted: &[[Vector3<f32>; 3]],
+    triangles: &[Triangle],
+    range: i32,
+    filled: &mut HashMap<[i32; 3], [f32; 4]>,
+) {
+    // Nudge the rays off the middle of the voxels so they don't run along shared edges.
+    const NUDGE: [f32; 2] = [0.5001, 0.4999];
+    let mut crossings: HashMap<[i32; 2], Vec<(f32, [f32; 4])>> = HashMap::new();
+    for (corners, triangle) in fitted.iter().zip(triangles) {
+        let low_x = corners.iter().map(|c| c.x).fold(f32::INFINITY, f32::min);
+        let high_x = corners
+            .iter()
+            .map(|c| c.x)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let low_z = corners.iter().map(|c| c.z).fold(f32::INFINITY, f32::min);
+        let high_z = corners
+            .iter()
+            .map(|c| c.z)
+            .fold(f32::NEG_INFINITY, f32::max);
+        for x in (low_x - NUDGE[0]).ceil() as i32..=(high_x - NUDGE[0]).floor() as i32 {
+            for z in (low_z - NUDGE[1]).ceil() as i32..=(high_z - NUDGE[1]).floor() as i32 {
+                let (px, pz) = (x as f32 + NUDGE[0], z as f32 + NUDGE[1]);
+                if let Some(height) = column_crossing(corners, px, pz) {
+                    crossings
+                        .entry([x, z])
+                        .or_default()
+                        .push((height, triangle.color));
+                }
+            }
+        }
+    }
+
+    for ([x, z], mut heights) in crossings {
+        heights.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for pair in heights.chunks_exact(2) {
+            let (bottom, top) = (pair[0].0, pair[1].0);
+            let color = pair[1].1;
+            let first = ((bottom - 0.5).ceil() as i32).max(-range);
+            let last = ((top - 0.5).floor() as i32).min(range - 1);
+            for y in first..=last {
+                filled.entry([x, y, z]).or_insert(color);
+            }
+        }
+    }
+}
+
+/// Where a vertical line at x, z crosses a triangle, if it does.
+fn column_crossing(corners: &[Vector3<f32>; 3], x: f32, z: f32) -> Option<f32> {
+    let [a, b, c] = corners;
+    // Barycentric coordinates of the point in the triangle seen from above.
+    let denominator = (b.z - c.z) * (a.x - c.x) + (c.x - b.x) * (a.z - c.z);
+    if denominator.abs() < f32::EPSILON {
+        return None;
+    }
+    let first = ((b.z - c.z) * (x - c.x) + (c.x - b.x) * (z - c.z)) / denominator;
+    let second = ((c.z - a.z) * (x - c.x) + (a.x - c.x) * (z - c.z)) / denominator;
+    let third = 1.0 - first - second;
+    if first < 0.0 || second < 0.0 || third < 0.0 {
+        return None;
+    }
+    Some(first * a.y + second * b.y + third * c.y)
+}
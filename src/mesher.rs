@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+
+use crate::mesh::Mesh;
+use crate::ocnode::Ocnode;
+use crate::voxel_state::{self, MaterialKey, VoxelState};
+
+/// The axis and sign of the 6 directions a face can point.
+/// In order: left (-x), right (+x), bottom (-y), top (+y), front (-z), back (+z).
+pub const DIRECTIONS: [(usize, i32); 6] = [(0, -1), (0, 1), (1, -1), (1, 1), (2, -1), (2, 1)];
+
+/// One rectangle on the surface of the model.
+#[derive(Copy, Clone)]
+pub struct Quad {
+    /// Counter clockwise when viewed from the side the normal points to.
+    pub corners: [[f32; 3]; 4],
+    pub normal: [f32; 3],
+    pub color: [f32; 4],
+    pub fluid: i32,
+    pub noise: i32,
+}
+
+impl Quad {
+    /// Get the material of the quad in a form that can be compared and used as a key.
+    pub fn material_key(&self) -> MaterialKey {
+        voxel_state::material_key(self.color, self.fluid, self.noise)
+    }
+}
+
+/// A rectangle of voxel faces that can be seen, all on one side of a filled cube.
+pub struct Face {
+    /// The material of the cube. The position is the cube's smallest corner.
+    pub state: VoxelState,
+    /// Which way the face points, an index into `DIRECTIONS`.
+    pub direction: usize,
+    /// The layer of voxels the face belongs to, along the axis it points along.
+    pub layer: i32,
+    /// (u, v, width, height) in voxels, where u and v are the 2 axes after the one
+    /// the face points along.
+    pub rectangle: [i32; 4],
+}
+
+/// List the parts of the faces of every filled cube in the tree that can be seen.
+/// Big cubes are not split into voxels, each face looks up the cubes next to it and
+/// is hidden wherever `hidden(cube, neighbour)` says so.
+pub fn visible_faces(
+    root: &Ocnode,
+    hidden: impl Fn(&VoxelState, &VoxelState) -> bool,
+) -> Vec<Face> {
+    let mut faces: Vec<Face> = vec![];
+    for (state, level) in root.leaves() {
+        if !state.active {
+            continue;
+        }
+        let size = root.resolution(level) as i32;
+        let corner = state.position;
+        for (direction, (axis, sign)) in DIRECTIONS.iter().enumerate() {
+            let u_axis = (axis + 1) % 3;
+            let v_axis = (axis + 2) % 3;
+            let layer = if *sign > 0 {
+                corner[*axis] + size - 1
+            } else {
+                corner[*axis]
+            };
+            // The slice of voxels just past the face.
+            let mut min = corner;
+            let mut max = corner.map(|value| value + size);
+            min[*axis] = layer + sign;
+            max[*axis] = layer + sign + 1;
+            let neighbours = root.leaves_in(min, max);
+            if neighbours.is_empty() {
+                // The face is on the edge of the world.
+                faces.push(Face {
+                    state,
+                    direction,
+                    layer,
+                    rectangle: [corner[u_axis], corner[v_axis], size, size],
+                });
+                continue;
+            }
+            for (neighbour, neighbour_level) in neighbours {
+                if hidden(&state, &neighbour) {
+                    continue;
+                }
+                // The part of the face this neighbour covers.
+                let neighbour_size = root.resolution(neighbour_level) as i32;
+                let [(u, width), (v, height)] = [u_axis, v_axis].map(|index| {
+                    let start = corner[index].max(neighbour.position[index]);
+                    let end =
+                        (corner[index] + size).min(neighbour.position[index] + neighbour_size);
+                    (start, end - start)
+                });
+                faces.push(Face {
+                    state,
+                    direction,
+                    layer,
+                    rectangle: [u, v, width, height],
+                });
+            }
+        }
+    }
+    faces
+}
+
+/// Merge a set of rectangles in a plane into as few rectangles as we can.
+/// Rectangles are (u, v, width, height).
+fn merge_rectangles(cells: &[[i32; 4]]) -> Vec<[i32; 4]> {
+    let mut min = [i32::MAX; 2];
+    let mut max = [i32::MIN; 2];
+    for cell in cells {
+        for axis in 0..2 {
+            min[axis] = min[axis].min(cell[axis]);
+            max[axis] = max[axis].max(cell[axis] + cell[axis + 2] - 1);
+        }
+    }
+    let width = (max[0] - min[0] + 1) as usize;
+    let height = (max[1] - min[1] + 1) as usize;
+    let mut filled = vec![false; width * height];
+    for [u, v, cell_width, cell_height] in cells {
+        for row in v - min[1]..v - min[1] + cell_height {
+            for column in u - min[0]..u - min[0] + cell_width {
+                filled[row as usize * width + column as usize] = true;
+            }
+        }
+    }
+
+    let mut rectangles: Vec<[i32; 4]> = vec![];
+    for v in 0..height {
+        let mut u = 0;
+        while u < width {
+            if !filled[v * width + u] {
+                u += 1;
+                continue;
+            }
+            // Grow along u, then grow along v while the whole row is filled.
+            let mut quad_width = 1;
+            while u + quad_width < width && filled[v * width + u + quad_width] {
+                quad_width += 1;
+            }
+            let mut quad_height = 1;
+            while v + quad_height < height
+                && (u..u + quad_width).all(|column| filled[(v + quad_height) * width + column])
+            {
+                quad_height += 1;
+            }
+            for row in v..v + quad_height {
+                for column in u..u + quad_width {
+                    filled[row * width + column] = false;
+                }
+            }
+            rectangles.push([
+                min[0] + u as i32,
+                min[1] + v as i32,
+                quad_width as i32,
+                quad_height as i32,
+            ]);
+            u += quad_width;
+        }
+    }
+    rectangles
+}
+
+/// Make the quad for a rectangle of faces pointing in a direction from a layer of voxels.
+fn quad(direction: usize, layer: i32, rectangle: [i32; 4], material: &VoxelState) -> Quad {
+    let (axis, sign) = DIRECTIONS[direction];
+    let u_axis = (axis + 1) % 3;
+    let v_axis = (axis + 2) % 3;
+    let plane = if sign > 0 { layer + 1 } else { layer } as f32;
+    let mut normal = [0.0; 3];
+    normal[axis] = sign as f32;
+
+    let [u, v, width, height] = rectangle;
+    let corner = |u: i32, v: i32| -> [f32; 3] {
+        let mut point = [0.0; 3];
+        point[axis] = plane;
+        point[u_axis] = u as f32;
+        point[v_axis] = v as f32;
+        point
+    };
+    let (u1, v1) = (u + width, v + height);
+    // u x v points along +axis so this order is counter clockwise from outside.
+    let corners = if sign > 0 {
+        [corner(u, v), corner(u1, v), corner(u1, v1), corner(u, v1)]
+    } else {
+        [corner(u, v), corner(u, v1), corner(u1, v1), corner(u1, v)]
+    };
+    Quad {
+        corners,
+        normal,
+        color: material.color,
+        fluid: material.fluid,
+        noise: material.noise,
+    }
+}
+
+/// Turn the visible faces of the tree into merged quads. Faces are only merged
+/// when they point the same way, lie in the same plane and share a material.
+/// Faces between filled voxels of the same material are hidden.
+pub fn greedy_quads(root: &Ocnode) -> Vec<Quad> {
+    let mut slices: BTreeMap<(usize, i32, MaterialKey), Vec<[i32; 4]>> = BTreeMap::new();
+    let mut materials: BTreeMap<MaterialKey, VoxelState> = BTreeMap::new();
+
+    let faces = visible_faces(root, |state, neighbour| {
+        neighbour.active && state.material_key() == neighbour.material_key()
+    });
+    for face in faces {
+        let key = face.state.material_key();
+        materials.entry(key).or_insert(face.state);
+        slices
+            .entry((face.direction, face.layer, key))
+            .or_default()
+            .push(face.rectangle);
+    }
+
+    let mut quads: Vec<Quad> = vec![];
+    for ((direction, layer, key), rectangles) in slices {
+        for rectangle in merge_rectangles(&rectangles) {
+            quads.push(quad(direction, layer, rectangle, &materials[&key]));
+        }
+    }
+    quads
+}
+
+/// Turn the visible faces of the tree into one quad per voxel face. Unlike
+/// `greedy_quads` no faces are merged, so neighbouring quads always share whole
+/// edges and the surface has no cracks when vertices are shared. Faces between
+/// voxels of different materials are inside the model so they are dropped too.
+pub fn face_quads(root: &Ocnode) -> Vec<Quad> {
+    let mut quads: Vec<Quad> = vec![];
+    for face in visible_faces(root, |_, neighbour| neighbour.active) {
+        let [u, v, width, height] = face.rectangle;
+        for cell_v in v..v + height {
+            for cell_u in u..u + width {
+                quads.push(quad(
+                    face.direction,
+                    face.layer,
+                    [cell_u, cell_v, 1, 1],
+                    &face.state,
+                ));
+            }
+        }
+    }
+    quads
+}
+
+/// Group quads into one mesh per material so each can be drawn in one call.
+pub fn build_meshes(quads: &[Quad]) -> Vec<Mesh> {
+    let mut meshes: Vec<Mesh> = vec![];
+    let mut indexes: BTreeMap<MaterialKey, usize> = BTreeMap::new();
+
+    for quad in quads {
+        let key = quad.material_key();
+        let index = *indexes.entry(key).or_insert_with(|| {
+            meshes.push(Mesh::new(quad.color, quad.fluid, quad.noise));
+            meshes.len() - 1
+        });
+        meshes[index].push_quad(&quad.corners, quad.normal);
+    }
+    meshes
+}
@@ -1,41 +1,263 @@
-use crate::cube::Cube;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::animation::LayerAnimation;
+use crate::flood::{Connectivity, Matching};
+use crate::layer::{Layer, LayerError};
+use crate::mesh::Mesh;
+use crate::ocnode::{Ocnode, LEVELS};
 use crate::octree::Octree;
+use crate::scene_format::SceneFormatError;
 use crate::storage::Storage;
+use crate::stored_octree::{
+    StoredLayer, StoredOctree, BINARY_VERSION, LAYERED_VERSION, LEGACY_VERSION,
+};
+use crate::transform::Region;
+use crate::vox::{self, VoxError};
+use crate::voxel_state::VoxelState;
+
+/// The most voxels the visible layers can be listed as one by one. Big filled cubes
+/// are stored whole, listing a full world voxel by voxel would not fit in memory.
+pub const MAX_VISIBLE_VOXELS: u64 = 1 << 22;
 
-/// A model contains an Octree of voxels.
+/// The visible layers have more filled voxels than can be listed one by one.
+#[derive(Debug, PartialEq)]
+pub struct TooManyVoxels(pub u64);
+
+impl fmt::Display for TooManyVoxels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The visible layers have {} filled voxels but only {MAX_VISIBLE_VOXELS} can be used",
+            self.0
+        )
+    }
+}
+
+/// A model contains a list of layers, each with an Octree of voxels.
+/// Layers are listed from bottom to top and edits go to the active layer.
 #[derive(Clone)]
 pub struct Model {
-    pub voxels: Octree,
+    /// The name of the scene. Used to save/restore.
+    pub name: String,
+    layers: Vec<Layer>,
+    /// Index of the layer being edited.
+    active_layer: usize,
+    /// The id for the next layer that is created.
+    next_layer_id: u32,
 }
 
 impl Model {
     /// Create new empty model.
     pub const fn new() -> Model {
         Model {
-            voxels: Octree::new(),
+            name: String::new(),
+            layers: Vec::new(),
+            active_layer: 0,
+            next_layer_id: 1,
         }
     }
 
-    /// Get the list of drawables from the OcTree
-    pub fn drawables(&mut self) -> Vec<Cube> {
-        self.voxels.drawables()
+    /// Get the meshes of every visible layer, posed by their animations at a time in seconds.
+    pub fn meshes(&mut self, time: f32) -> Vec<&Mesh> {
+        self.layers
+            .iter_mut()
+            .filter(|layer| layer.visible)
+            .filter_map(|layer| {
+                let pose = layer.animation.pose(time);
+                pose.visible.then(|| layer.meshes(&pose).iter())
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// The length of the longest layer animation in seconds.
+    pub fn animation_duration(&self) -> f32 {
+        self.layers
+            .iter()
+            .map(|layer| layer.animation.duration())
+            .fold(0.0, f32::max)
+    }
+
+    /// Change the animation of a layer.
+    pub fn animate_layer(
+        &mut self,
+        index: usize,
+        change: impl FnOnce(&mut LayerAnimation),
+    ) -> Result<(), LayerError> {
+        change(&mut self.layer_mut(index)?.animation);
+        Ok(())
     }
 
     /// Call optimize on the nested OcNodes
     pub fn optimize(&mut self, camera_eye: [f32; 3]) {
-        self.voxels.optimize(camera_eye);
+        for layer in self.layers.iter_mut() {
+            layer.optimize(camera_eye);
+        }
     }
 
     /// Initialise
     pub fn init(&mut self) {
-        self.voxels.init();
+        self.reset(LEVELS);
+    }
+
+    /// Start again with a single empty layer in a world of this many levels.
+    pub fn reset(&mut self, levels: u32) {
+        let id = self.next_id();
+        self.layers = vec![Layer::new(id, format!("Layer {id}"), levels)];
+        self.active_layer = 0;
+    }
+
+    /// Take the id for a new layer.
+    fn next_id(&mut self) -> u32 {
+        let id = self.next_layer_id;
+        self.next_layer_id += 1;
+        id
+    }
+
+    /// Hide all voxels in every layer.
+    pub fn clear(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear();
+        }
     }
 
     /// Set the name of the scene. Used to save/restore.
     pub fn set_name(&mut self, name: String) {
-        self.voxels.set_name(name);
+        self.name = name;
+    }
+
+    /// How many levels the trees have. Every layer is the same size.
+    pub fn levels(&self) -> u32 {
+        self.layers
+            .first()
+            .map(|layer| layer.voxels().levels())
+            .unwrap_or(LEVELS)
+    }
+
+    /// The actual positions go from -range to +range.
+    pub fn range(&self) -> i32 {
+        Ocnode::range(self.levels())
+    }
+
+    /// The layers from bottom to top.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// The index of the layer being edited.
+    pub fn active_layer(&self) -> usize {
+        self.active_layer
+    }
+
+    /// The id of the layer being edited.
+    pub fn active_layer_id(&mut self) -> u32 {
+        self.active_mut().id
+    }
+
+    /// Get the layer being edited, creating one if there are none yet.
+    fn active_mut(&mut self) -> &mut Layer {
+        if self.layers.is_empty() {
+            self.init();
+        }
+        &mut self.layers[self.active_layer]
+    }
+
+    /// Get the layer being edited if it can be changed.
+    fn editable_mut(&mut self) -> Result<&mut Layer, LayerError> {
+        let layer = self.active_mut();
+        if layer.locked {
+            return Err(LayerError::Locked(layer.name.clone()));
+        }
+        Ok(layer)
+    }
+
+    /// Get a layer by index.
+    fn layer_mut(&mut self, index: usize) -> Result<&mut Layer, LayerError> {
+        self.layers
+            .get_mut(index)
+            .ok_or(LayerError::NoSuchLayer(index))
+    }
+
+    /// Add an empty layer above the active one and make it active.
+    pub fn add_layer(&mut self, name: String) -> usize {
+        let id = self.next_id();
+        let name = if name.is_empty() {
+            format!("Layer {id}")
+        } else {
+            name
+        };
+        let layer = Layer::new(id, name, self.levels());
+        let index = if self.layers.is_empty() {
+            0
+        } else {
+            self.active_layer + 1
+        };
+        self.layers.insert(index, layer);
+        self.active_layer = index;
+        index
+    }
+
+    /// Remove a layer and all of its voxels.
+    pub fn remove_layer(&mut self, index: usize) -> Result<(), LayerError> {
+        self.layer_mut(index)?;
+        if self.layers.len() == 1 {
+            return Err(LayerError::LastLayer);
+        }
+        self.layers.remove(index);
+        if self.active_layer > index || self.active_layer == self.layers.len() {
+            self.active_layer -= 1;
+        }
+        Ok(())
+    }
+
+    /// Change the name of a layer.
+    pub fn rename_layer(&mut self, index: usize, name: String) -> Result<(), LayerError> {
+        self.layer_mut(index)?.name = name;
+        Ok(())
+    }
+
+    /// Move a layer to a new position in the list. The active layer stays active.
+    pub fn move_layer(&mut self, from: usize, to: usize) -> Result<(), LayerError> {
+        self.layer_mut(from)?;
+        self.layer_mut(to)?;
+        let active_id = self.layers[self.active_layer].id;
+        let layer = self.layers.remove(from);
+        self.layers.insert(to, layer);
+        self.active_layer = self
+            .layers
+            .iter()
+            .position(|layer| layer.id == active_id)
+            .unwrap_or(to);
+        Ok(())
+    }
+
+    /// Choose the layer that will be edited.
+    pub fn select_layer(&mut self, index: usize) -> Result<(), LayerError> {
+        self.layer_mut(index)?;
+        self.active_layer = index;
+        Ok(())
+    }
+
+    /// Show or hide a layer.
+    pub fn set_layer_visible(&mut self, index: usize, visible: bool) -> Result<(), LayerError> {
+        self.layer_mut(index)?.visible = visible;
+        Ok(())
+    }
+
+    /// Lock or unlock a layer for editing.
+    pub fn set_layer_locked(&mut self, index: usize, locked: bool) -> Result<(), LayerError> {
+        self.layer_mut(index)?.locked = locked;
+        Ok(())
+    }
+
+    /// Fade a layer.
+    pub fn set_layer_opacity(&mut self, index: usize, opacity: f32) -> Result<(), LayerError> {
+        self.layer_mut(index)?.set_opacity(opacity);
+        Ok(())
     }
 
+    /// Set the state of a list of voxels in the active layer and return the states they replaced.
     pub fn toggle_voxels(
         &mut self,
         positions: Vec<[i32; 3]>,
@@ -44,21 +266,71 @@ impl Model {
         camera_eye: [f32; 3],
         fluid: i32,
         noise: i32,
-    ) {
-        self.voxels
-            .toggle_voxels(positions, value, color, camera_eye, fluid, noise);
+    ) -> Result<Vec<VoxelState>, LayerError> {
+        Ok(self
+            .editable_mut()?
+            .toggle_voxels(positions, value, color, camera_eye, fluid, noise))
     }
 
-    /// Determine if all voxels in the list are active.
-    pub fn all_voxels_active(&self, positions: &Vec<[i32; 3]>) -> bool {
-        self.voxels.all_voxels_active(positions)
+    /// Restore a list of saved voxel states in a layer and return the states they replaced.
+    /// Nothing changes if the layer has been removed.
+    pub fn set_voxels(
+        &mut self,
+        layer_id: u32,
+        states: &[VoxelState],
+        camera_eye: [f32; 3],
+    ) -> Vec<VoxelState> {
+        match self.layers.iter_mut().find(|layer| layer.id == layer_id) {
+            Some(layer) => layer.set_voxels(states, camera_eye),
+            None => vec![],
+        }
+    }
+
+    /// Change voxels in the active layer to saved states and return the states they replaced.
+    pub fn edit_voxels(
+        &mut self,
+        states: &[VoxelState],
+        camera_eye: [f32; 3],
+    ) -> Result<Vec<VoxelState>, LayerError> {
+        Ok(self.editable_mut()?.set_voxels(states, camera_eye))
+    }
+
+    /// The state of voxels in the active layer. Positions outside the world are skipped.
+    pub fn active_voxels_at(&mut self, positions: &[[i32; 3]]) -> Vec<VoxelState> {
+        let voxels = self.active_mut().voxels();
+        positions
+            .iter()
+            .filter_map(|position| voxels.voxel_at(*position))
+            .collect()
+    }
+
+    /// The filled voxels inside a region of the active layer.
+    pub fn active_voxels_in(&mut self, region: &Region) -> Vec<VoxelState> {
+        self.active_mut().voxels().voxels_in(region)
+    }
+
+    /// Find the voxels in the active layer connected to a start voxel that match it.
+    pub fn flood(
+        &mut self,
+        start: [i32; 3],
+        connectivity: Connectivity,
+        matching: Matching,
+    ) -> Vec<[i32; 3]> {
+        self.active_mut()
+            .voxels()
+            .flood(start, connectivity, matching)
+    }
+
+    /// Determine if all voxels in the list are active in the active layer.
+    pub fn all_voxels_active(&mut self, positions: &Vec<[i32; 3]>) -> bool {
+        self.active_mut().voxels().all_voxels_active(positions)
     }
 
     /// Delete a scene from browser indexeddb
     pub async fn delete_scene(&self) {
         let storage = Storage::new();
-        if self.voxels.name != "Default" {
-            _ = storage.delete_scene(self.voxels.name.to_string()).await;
+        if self.name != "Default" {
+            _ = storage.delete_scene(self.name.to_string()).await;
         }
     }
 
@@ -66,7 +338,180 @@ impl Model {
     pub async fn save(&self) {
         let storage = Storage::new();
 
-        let serial = self.voxels.prepare();
+        let serial = self.prepare();
         _ = storage.save(serial).await;
     }
+
+    /// Serialize the model.
+    pub fn prepare(&self) -> StoredOctree {
+        StoredOctree {
+            name: self.name.clone(),
+            version: LAYERED_VERSION,
+            levels: self.levels(),
+            data: vec![],
+            active_nodes: vec![],
+            layers: self.prepare_layers(),
+            active_layer: self.active_layer,
+        }
+    }
+
+    /// Serialize every layer.
+    pub fn prepare_layers(&self) -> Vec<StoredLayer> {
+        self.layers.iter().map(Layer::prepare).collect()
+    }
+
+    /// Load the scene from browser indexeddb.
+    /// Records saved by older versions are converted as they are loaded.
+    pub fn load_from_serial(
+        &mut self,
+        source: StoredOctree,
+        camera_eye: [f32; 3],
+    ) -> Result<(), SceneFormatError> {
+        match source.version {
+            LEGACY_VERSION => {
+                let mut voxels = Octree::new();
+                voxels.load_legacy(source.levels, &source.active_nodes);
+                self.replace_layers(vec![voxels]);
+            }
+            BINARY_VERSION => {
+                let mut voxels = Octree::new();
+                voxels.load_encoded(&source.data)?;
+                self.replace_layers(vec![voxels]);
+            }
+            _ => self.load_layers(&source.layers, source.active_layer)?,
+        }
+        self.name = source.name;
+        self.optimize(camera_eye);
+        Ok(())
+    }
+
+    /// Replace the layers with one layer for each tree.
+    fn replace_layers(&mut self, trees: Vec<Octree>) {
+        self.layers.clear();
+        self.active_layer = 0;
+        for voxels in trees {
+            let id = self.next_id();
+            self.layers
+                .push(Layer::from_octree(id, format!("Layer {id}"), voxels));
+        }
+    }
+
+    /// Replace the layers with saved ones. Nothing changes if any layer can't be decoded.
+    pub fn load_layers(
+        &mut self,
+        stored: &[StoredLayer],
+        active_layer: usize,
+    ) -> Result<(), SceneFormatError> {
+        let mut layers: Vec<Layer> = vec![];
+        for (index, stored_layer) in stored.iter().enumerate() {
+            layers.push(Layer::from_stored(
+                self.next_layer_id + index as u32,
+                stored_layer,
+            )?);
+        }
+        let Some(first) = layers.first() else {
+            return Err(SceneFormatError::Malformed(
+                "there are no layers".to_string(),
+            ));
+        };
+        let levels = first.voxels().levels();
+        if layers.iter().any(|layer| layer.voxels().levels() != levels) {
+            return Err(SceneFormatError::Malformed(
+                "the layers are not all the same size".to_string(),
+            ));
+        }
+        self.next_layer_id += layers.len() as u32;
+        self.active_layer = active_layer.min(layers.len() - 1);
+        self.layers = layers;
+        Ok(())
+    }
+
+    /// Every filled voxel in the visible layers. Higher layers win where layers overlap.
+    /// The filled cubes are counted first so a mostly full world is never expanded.
+    pub fn visible_voxels(&self) -> Result<Vec<VoxelState>, TooManyVoxels> {
+        let root = self.visible_tree();
+        let count: u64 = root
+            .leaves()
+            .iter()
+            .filter(|(state, _)| state.active)
+            .map(|(_, level)| u64::from(root.resolution(*level)).pow(3))
+            .sum();
+        if count > MAX_VISIBLE_VOXELS {
+            return Err(TooManyVoxels(count));
+        }
+        let mut voxels = root.active_voxels();
+        voxels.sort_by_key(|state| state.position);
+        Ok(voxels)
+    }
+
+    /// A tree holding every filled voxel in the visible layers, so they can be meshed together.
+    /// Filled cubes are copied whole, higher layers on top.
+    pub fn visible_tree(&self) -> Ocnode {
+        let mut root = Ocnode::new(self.levels());
+        for layer in self.layers.iter().filter(|layer| layer.visible) {
+            for (state, level) in layer.voxels().root().leaves() {
+                if state.active {
+                    root.fill(&state, level);
+                }
+            }
+        }
+        root
+    }
+
+    /// The name and voxel tree of every visible layer, bottom layer first.
+    pub fn visible_layer_trees(&self) -> Vec<(&str, &Ocnode)> {
+        self.layers
+            .iter()
+            .filter(|layer| layer.visible)
+            .map(|layer| (layer.name.as_str(), layer.voxels().root()))
+            .collect()
+    }
+
+    /// The filled voxel at a position in the highest visible layer that has one.
+    pub fn visible_voxel_at(&self, position: [i32; 3]) -> Option<VoxelState> {
+        self.layers
+            .iter()
+            .rev()
+            .filter(|layer| layer.visible)
+            .filter_map(|layer| layer.voxels().voxel_at(position))
+            .find(|state| state.active)
+    }
+
+    /// Encode the visible layers as a MagicaVoxel .vox file.
+    pub fn export_vox(&self) -> Result<Vec<u8>, VoxError> {
+        vox::export(&self.visible_voxels()?)
+    }
+
+    /// Add the voxels from a MagicaVoxel .vox file to the active layer and return the states they replaced.
+    pub fn import_vox(
+        &mut self,
+        bytes: &[u8],
+        camera_eye: [f32; 3],
+    ) -> Result<Vec<VoxelState>, String> {
+        let range = self.range();
+        let layer = self.editable_mut().map_err(|error| error.to_string())?;
+        let states = vox::import(bytes, range).map_err(|error| error.to_string())?;
+
+        // Toggle all the voxels of the same colour together.
+        let mut by_color: HashMap<[u32; 4], Vec<[i32; 3]>> = HashMap::new();
+        for state in &states {
+            by_color
+                .entry(state.color.map(f32::to_bits))
+                .or_default()
+                .push(state.position);
+        }
+
+        let mut previous: Vec<VoxelState> = vec![];
+        for (color, positions) in by_color {
+            previous.extend(layer.toggle_voxels(
+                positions,
+                true,
+                color.map(f32::from_bits),
+                camera_eye,
+                0,
+                0,
+            ));
+        }
+        Ok(previous)
+    }
 }
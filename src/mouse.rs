@@ -6,14 +6,41 @@ pub struct Mouse {
     pub last_position: Point2<i32>,
     /// The button state of the mouse.
     pub is_pressed: bool,
+    /// Where the button was pressed.
+    pub press_position: Point2<i32>,
+    /// Has the mouse moved far enough since the button was pressed to be a drag?
+    pub dragged: bool,
 }
 
+/// How many pixels the mouse can move while pressed and still count as a click.
+pub const DRAG_THRESHOLD: i32 = 4;
+
 impl Mouse {
     /// Create a new mouse struct.
     pub const fn new() -> Mouse {
         Mouse {
             last_position: Point2::new(0, 0),
             is_pressed: false,
+            press_position: Point2::new(0, 0),
+            dragged: false,
+        }
+    }
+
+    /// Remember where the button was pressed.
+    pub fn press(&mut self, position: Point2<i32>) {
+        self.is_pressed = true;
+        self.press_position = position;
+        self.last_position = position;
+        self.dragged = false;
+    }
+
+    /// Check if the mouse has moved far enough from where it was pressed to be a drag.
+    pub fn update_drag(&mut self, position: Point2<i32>) -> bool {
+        let moved =
+            (position.x - self.press_position.x).abs() + (position.y - self.press_position.y).abs();
+        if self.is_pressed && moved > DRAG_THRESHOLD {
+            self.dragged = true;
         }
+        self.dragged
     }
 }
@@ -1,4 +1,4 @@
-use crate::{cube::Cube, drawable::Drawable};
+use crate::voxel_state::VoxelState;
 use serde::{Deserialize, Serialize};
 
 /// Helper function to create an empty list.
@@ -7,10 +7,22 @@ fn empty_list() -> [Option<Box<Ocnode>>; 8] {
     [None, None, None, None, None, None, None, None]
 }
 
+/// The number of levels in a new tree, giving a 128 voxel wide world.
 pub const LEVELS: u32 = 8;
+/// The smallest tree we support, a 16 voxel wide world.
+pub const MIN_LEVELS: u32 = 5;
+/// The biggest tree we support, a 512 voxel wide world.
+pub const MAX_LEVELS: u32 = 10;
+
+/// Used when deserializing nodes that were saved before the tree size was stored.
+fn default_levels() -> u32 {
+    LEVELS
+}
 
 /// A struct representing a single cube for the octree.
-/// Cubes contain children which are smaller cubes.
+/// Cubes contain children which are smaller cubes. The tree is sparse, a cube
+/// without children has the same state for every voxel inside it. Children are
+/// only created when part of a cube changes and are merged again once they match.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Ocnode {
     /// the x index of the cube.
@@ -39,15 +51,19 @@ pub struct Ocnode {
     fluid: i32,
     /// Render this node with a noisy texture.
     noise: i32,
+    /// How many levels the whole tree has, this decides the size of the smallest cube.
+    #[serde(skip)]
+    #[serde(default = "default_levels")]
+    levels: u32,
 }
 
 impl Ocnode {
-    /// Create a new empty cube.
-    pub const fn new() -> Ocnode {
+    /// Create a new empty cube that is the root of a tree with this many levels.
+    pub const fn new(levels: u32) -> Ocnode {
         Ocnode {
-            x_index: -Ocnode::range(),
-            y_index: -Ocnode::range(),
-            z_index: -Ocnode::range(),
+            x_index: -Ocnode::range(levels),
+            y_index: -Ocnode::range(levels),
+            z_index: -Ocnode::range(levels),
             sub_division_level: 1,
             active: false,
             children: [None, None, None, None, None, None, None, None],
@@ -55,268 +71,276 @@ impl Ocnode {
             color: [0.8, 0.8, 0.8, 0.8],
             fluid: 0,
             noise: 0,
+            levels,
         }
     }
 
+    /// Find the cube at an index and level. If the tree has no cube that small
+    /// there, the bigger cube covering it is returned since it has the same state.
     pub fn find_by_index(&self, x: i32, y: i32, z: i32, level: u32) -> Option<&Ocnode> {
         if level == self.sub_division_level {
             if self.x_index == x && self.y_index == y && self.z_index == z {
                 return Some(self);
-            } else {
-                return None;
-            }
-        } else {
-            if x >= self.x_index
-                && (x <= self.x_index + self.resolution(self.sub_division_level) as i32)
-                && y >= self.y_index
-                && (y <= self.y_index + self.resolution(self.sub_division_level) as i32)
-                && z >= self.z_index
-                && (z <= self.z_index + self.resolution(self.sub_division_level) as i32)
-            {
-                if self.has_children {
-                    let squirts = self.children.each_ref();
-
-                    for node_opt in squirts {
-                        match node_opt {
-                            None => {
-                                log::debug!("Should not get here")
-                            }
-                            Some(node) => {
-                                let child = node.find_by_index(x, y, z, level);
-                                if child.is_some() {
-                                    return child;
-                                }
-                            }
-                        };
-                    }
-                    return None;
-                }
             }
             return None;
         }
+        if level < self.sub_division_level || !self.contains(x, y, z) {
+            return None;
+        }
+        if !self.has_children {
+            return Some(self);
+        }
+        match &self.children[self.child_index(x, y, z)] {
+            Some(child) => child.find_by_index(x, y, z, level),
+            None => None,
+        }
     }
 
-    pub fn uniform(&self, compare: &Ocnode) -> bool {
-        let compare_color = compare.color;
-        let compare_fluid = compare.fluid;
-        let compare_noise = compare.noise;
-        !(compare_color[0] != self.color[0]
-            || compare_color[1] != self.color[1]
-            || compare_color[2] != self.color[2]
-            || compare_color[3] != self.color[3]
-            || compare_fluid != self.fluid
-            || compare_noise != self.noise)
+    /// Is the index inside this cube?
+    fn contains(&self, x: i32, y: i32, z: i32) -> bool {
+        let size = self.resolution(self.sub_division_level) as i32;
+        x >= self.x_index
+            && x < self.x_index + size
+            && y >= self.y_index
+            && y < self.y_index + size
+            && z >= self.z_index
+            && z < self.z_index + size
     }
 
-    pub fn bottom_occluded(&self, root: &Ocnode) -> bool {
-        let maybe_bottom = root.find_by_index(
-            self.x_index,
-            self.y_index - self.resolution(self.sub_division_level) as i32,
-            self.z_index,
-            self.sub_division_level,
-        );
-        if maybe_bottom.is_some() {
-            let bottom = maybe_bottom.unwrap();
-            if bottom.active {
-                return self.uniform(bottom);
-            }
+    /// Which child contains the index. Bit 0 is x, bit 1 is y and bit 2 is z.
+    fn child_index(&self, x: i32, y: i32, z: i32) -> usize {
+        let half = self.resolution(self.sub_division_level + 1) as i32;
+        let mut index = 0;
+        if x >= self.x_index + half {
+            index |= 1;
+        }
+        if y >= self.y_index + half {
+            index |= 2;
         }
-        false
+        if z >= self.z_index + half {
+            index |= 4;
+        }
+        index
     }
 
-    pub fn left_occluded(&self, root: &Ocnode) -> bool {
-        let maybe_left = root.find_by_index(
-            self.x_index - self.resolution(self.sub_division_level) as i32,
-            self.y_index,
-            self.z_index,
-            self.sub_division_level,
-        );
-        if maybe_left.is_some() {
-            let left = maybe_left.unwrap();
-            if left.active {
-                return self.uniform(left);
+    /// Change the state of the cube at an index and level. Bigger cubes are split on the
+    /// way down and children that end up matching are merged on the way back up.
+    /// Returns the state that was replaced, or None if the index is outside the tree.
+    fn write(
+        &mut self,
+        x: i32,
+        y: i32,
+        z: i32,
+        level: u32,
+        state: &VoxelState,
+    ) -> Option<VoxelState> {
+        if level == self.sub_division_level {
+            if self.x_index != x || self.y_index != y || self.z_index != z {
+                return None;
             }
+            let previous = self.voxel_state();
+            self.children = empty_list();
+            self.has_children = false;
+            self.active = state.active;
+            self.color = state.color;
+            self.fluid = state.fluid;
+            self.noise = state.noise;
+            return Some(previous);
         }
-        false
-    }
-
-    pub fn right_occluded(&self, root: &Ocnode) -> bool {
-        let maybe_right = root.find_by_index(
-            self.x_index + self.resolution(self.sub_division_level) as i32,
-            self.y_index,
-            self.z_index,
-            self.sub_division_level,
-        );
-        if maybe_right.is_some() {
-            let right = maybe_right.unwrap();
-            if right.active {
-                return self.uniform(right);
+        if level < self.sub_division_level || !self.contains(x, y, z) {
+            return None;
+        }
+        if !self.has_children {
+            if self.same_state(state) {
+                // Nothing changes, but report the state at this index.
+                let mut previous = self.voxel_state();
+                previous.position = [x, y, z];
+                return Some(previous);
             }
+            self.subdivide();
         }
-        false
+
+        let index = self.child_index(x, y, z);
+        let previous = match &mut self.children[index] {
+            Some(child) => child.write(x, y, z, level, state),
+            None => None,
+        };
+        self.collapse();
+        previous
     }
 
-    pub fn front_occluded(&self, root: &Ocnode) -> bool {
-        let maybe_front = root.find_by_index(
-            self.x_index,
-            self.y_index,
-            self.z_index - self.resolution(self.sub_division_level) as i32,
-            self.sub_division_level,
-        );
-        if maybe_front.is_some() {
-            let front = maybe_front.unwrap();
-            if front.active {
-                return self.uniform(front);
-            }
+    /// Would setting this state leave a cube without children unchanged?
+    fn same_state(&self, state: &VoxelState) -> bool {
+        if self.active != state.active {
+            return false;
         }
-        false
+        // The material of empty space does not matter.
+        !self.active
+            || (self.color == state.color && self.fluid == state.fluid && self.noise == state.noise)
     }
 
-    pub fn back_occluded(&self, root: &Ocnode) -> bool {
-        let maybe_back = root.find_by_index(
-            self.x_index,
-            self.y_index,
-            self.z_index + self.resolution(self.sub_division_level) as i32,
-            self.sub_division_level,
-        );
-        if maybe_back.is_some() {
-            let back = maybe_back.unwrap();
-            if back.active {
-                return self.uniform(back);
-            }
+    /// Merge the children into this cube if they are all the same and have no children.
+    fn collapse(&mut self) {
+        if !self.has_children {
+            return;
+        }
+        let first = match &self.children[0] {
+            Some(child) if !child.has_children => child.voxel_state(),
+            _ => return,
+        };
+        let matching = self.children.iter().all(|child| match child {
+            Some(child) => !child.has_children && child.same_state(&first),
+            None => false,
+        });
+        if matching {
+            self.children = empty_list();
+            self.has_children = false;
+            self.active = first.active;
+            self.color = first.color;
+            self.fluid = first.fluid;
+            self.noise = first.noise;
         }
-        false
     }
 
-    pub fn top_occluded(&self, root: &Ocnode) -> bool {
-        let maybe_top = root.find_by_index(
-            self.x_index,
-            self.y_index + self.resolution(self.sub_division_level) as i32,
-            self.z_index,
-            self.sub_division_level,
-        );
-        if maybe_top.is_some() {
-            let top = maybe_top.unwrap();
-            if top.active {
-                return self.uniform(top);
+    /// Return the coordinate range for a tree with this many levels.
+    /// The actual positions go from -range to +range
+    pub const fn range(levels: u32) -> i32 {
+        2i32.pow(levels - 1) / 2
+    }
+
+    /// Calculate the width of a cube at this subdivision level
+    pub fn resolution(&self, sub_division_level: u32) -> u32 {
+        let power = self.levels.checked_sub(sub_division_level).expect("");
+        2u32.pow(power)
+    }
+
+    /// Get the list of active voxels inside this cube and all it's children.
+    /// Big cubes without children are listed as every voxel they cover.
+    pub fn active_nodes(&self) -> Vec<Ocnode> {
+        let mut found: Vec<Ocnode> = vec![];
+        self.collect_active_nodes(&mut found);
+        found
+    }
+
+    /// Add the active voxels in this cube to the list.
+    fn collect_active_nodes(&self, found: &mut Vec<Ocnode>) {
+        if self.has_children {
+            for child in self.children.iter().flatten() {
+                child.collect_active_nodes(found);
+            }
+        } else if self.active {
+            let size = self.resolution(self.sub_division_level) as i32;
+            for x in self.x_index..self.x_index + size {
+                for y in self.y_index..self.y_index + size {
+                    for z in self.z_index..self.z_index + size {
+                        found.push(Ocnode {
+                            x_index: x,
+                            y_index: y,
+                            z_index: z,
+                            sub_division_level: self.levels,
+                            active: true,
+                            children: empty_list(),
+                            has_children: false,
+                            color: self.color,
+                            fluid: self.fluid,
+                            noise: self.noise,
+                            levels: self.levels,
+                        });
+                    }
+                }
             }
         }
-        false
     }
 
-    pub fn find_mut_by_index(&mut self, x: i32, y: i32, z: i32, level: u32) -> Option<&mut Ocnode> {
-        if level == self.sub_division_level {
-            if self.x_index == x && self.y_index == y && self.z_index == z {
-                return Some(self);
-            } else {
-                return None;
+    /// The state of every filled voxel inside this cube. Unlike `active_nodes` only the
+    /// states are made, big cubes without children are listed as every voxel they cover.
+    pub fn active_voxels(&self) -> Vec<VoxelState> {
+        let mut found: Vec<VoxelState> = vec![];
+        for (state, level) in self.leaves() {
+            if !state.active {
+                continue;
             }
-        } else {
-            if x >= self.x_index
-                && (x <= self.x_index + self.resolution(self.sub_division_level) as i32)
-                && y >= self.y_index
-                && (y <= self.y_index + self.resolution(self.sub_division_level) as i32)
-                && z >= self.z_index
-                && (z <= self.z_index + self.resolution(self.sub_division_level) as i32)
-            {
-                if self.has_children {
-                    let squirts = self.children.each_mut();
-
-                    for node_opt in squirts {
-                        match node_opt {
-                            None => {
-                                log::debug!("Should not get here")
-                            }
-                            Some(node) => {
-                                let child = node.find_mut_by_index(x, y, z, level);
-                                if child.is_some() {
-                                    return child;
-                                }
-                            }
-                        };
+            let size = self.resolution(level) as i32;
+            let [x_index, y_index, z_index] = state.position;
+            for x in x_index..x_index + size {
+                for y in y_index..y_index + size {
+                    for z in z_index..z_index + size {
+                        found.push(VoxelState {
+                            position: [x, y, z],
+                            ..state
+                        });
                     }
-                    return None;
                 }
             }
-            return None;
         }
+        found
     }
 
-    /// Return the coordinate range. The actual positions go from -range to +range
-    pub const fn range() -> i32 {
-        2i32.pow(LEVELS - 1) / 2
+    /// List every cube without children that overlaps a box, and its level.
+    /// The box starts at min and stops just before max.
+    pub fn leaves_in(&self, min: [i32; 3], max: [i32; 3]) -> Vec<(VoxelState, u32)> {
+        let mut found = vec![];
+        self.collect_leaves_in(min, max, &mut found);
+        found
     }
 
-    /// Calculate the width of a cube at this subdivision level
-    pub fn resolution(&self, sub_division_level: u32) -> u32 {
-        let power = LEVELS.checked_sub(sub_division_level).expect("");
-        2u32.pow(power)
+    /// Recursive part of leaves_in.
+    fn collect_leaves_in(&self, min: [i32; 3], max: [i32; 3], found: &mut Vec<(VoxelState, u32)>) {
+        let size = self.resolution(self.sub_division_level) as i32;
+        let corner = [self.x_index, self.y_index, self.z_index];
+        if (0..3).any(|axis| corner[axis] >= max[axis] || corner[axis] + size <= min[axis]) {
+            return;
+        }
+        if self.has_children {
+            for child in self.children.iter().flatten() {
+                child.collect_leaves_in(min, max, found);
+            }
+        } else {
+            found.push((self.voxel_state(), self.sub_division_level));
+        }
     }
 
-    /// Get the list of active cubes including this one and all it's children.
-    pub fn active_nodes(&self) -> Vec<Ocnode> {
-        let mut found: Vec<Ocnode> = vec![];
+    /// List every cube without children and its level. Children are visited in
+    /// index order so the cubes are in Morton order and cover the whole tree.
+    pub fn leaves(&self) -> Vec<(VoxelState, u32)> {
+        let mut found = vec![];
+        self.collect_leaves(&mut found);
+        found
+    }
 
-        if self.active {
-            found.push(self.clone());
-        }
+    /// Recursive part of leaves.
+    fn collect_leaves(&self, found: &mut Vec<(VoxelState, u32)>) {
         if self.has_children {
-            let squirts = self.children.each_ref();
-
-            for node_opt in squirts {
-                match node_opt {
-                    None => {
-                        log::debug!("Should not get here")
-                    }
-                    Some(node) => {
-                        found.extend(node.active_nodes());
-                    }
-                };
+            for child in self.children.iter().flatten() {
+                child.collect_leaves(found);
             }
+        } else {
+            found.push((self.voxel_state(), self.sub_division_level));
         }
+    }
 
-        found
+    /// Set a whole cube at a level of the tree to a state.
+    pub fn fill(&mut self, state: &VoxelState, level: u32) {
+        let [x, y, z] = state.position;
+        self.write(x, y, z, level, state);
     }
 
     /// Set this cube and all it's children to hidden.
     pub fn clear(&mut self) {
         self.active = false;
-
-        let squirts = self.children.each_mut();
-
-        for node_opt in squirts {
-            match node_opt {
-                None => {}
-                Some(squirt) => {
-                    squirt.clear();
-                }
-            };
-        }
+        self.children = empty_list();
+        self.has_children = false;
     }
 
     /// Used when restoring from serial form.
     pub fn apply(&mut self, node: &Ocnode) {
-        if node.x_index == self.x_index
-            && node.y_index == self.y_index
-            && node.z_index == self.z_index
-            && node.sub_division_level == self.sub_division_level
-        {
-            // We got a match. Apply it.
-            self.active = node.active;
-            self.color = node.color;
-            self.fluid = node.fluid;
-            self.noise = node.noise;
-        }
-        let squirts = self.children.each_mut();
-
-        for node_opt in squirts {
-            match node_opt {
-                None => {}
-                Some(squirt) => {
-                    squirt.apply(node);
-                }
-            };
-        }
+        self.write(
+            node.x_index,
+            node.y_index,
+            node.z_index,
+            node.sub_division_level,
+            &node.voxel_state(),
+        );
     }
 
     /// Determine the distance between this cube and the camera.
@@ -376,7 +400,7 @@ impl Ocnode {
                     || compare_noise != noise
             });
 
-            let res = LEVELS.checked_sub(self.sub_division_level).expect("");
+            let res = self.levels.checked_sub(self.sub_division_level).expect("");
             let depth = self.depth(camera_eye) / res as f32;
             let lod = 60.0;
 
@@ -395,7 +419,7 @@ impl Ocnode {
     /// Are all the nodes in the list of nodes active?
     pub fn all_voxels_active(&self, positions: &Vec<[i32; 3]>) -> bool {
         for position in positions {
-            let found = self.find_by_index(position[0], position[1], position[2], LEVELS);
+            let found = self.find_by_index(position[0], position[1], position[2], self.levels);
             if found.is_some() {
                 if !found.unwrap().active {
                     return false;
@@ -408,6 +432,7 @@ impl Ocnode {
         true
     }
 
+    /// Set the state of every voxel in the list and return the states they replaced.
     pub fn toggle_voxels(
         &mut self,
         positions: &Vec<[i32; 3]>,
@@ -415,212 +440,72 @@ impl Ocnode {
         color: [f32; 4],
         fluid: i32,
         noise: i32,
-    ) {
+    ) -> Vec<VoxelState> {
+        let mut previous: Vec<VoxelState> = vec![];
+
         for position in positions {
-            let maybe = self.find_mut_by_index(position[0], position[1], position[2], LEVELS);
-            if maybe.is_some() {
-                let actual = maybe.unwrap();
-                actual.active = value;
-                actual.color = color;
-                actual.fluid = fluid;
-                actual.noise = noise;
+            let state = VoxelState {
+                position: *position,
+                active: value,
+                color,
+                fluid,
+                noise,
+            };
+            if let Some(replaced) =
+                self.write(position[0], position[1], position[2], self.levels, &state)
+            {
+                previous.push(replaced);
             }
         }
+        previous
     }
 
-    /// Generate a list of drawables from the active cubes in this one.
-    pub fn drawables(&mut self, root: &Ocnode) -> Vec<Cube> {
-        if self.has_children {
-            if self.active {
-                let scale = self.resolution(self.sub_division_level) as f32;
-                let mut cube = Cube::new();
-
-                cube.color = self.color;
-                cube.fluid = self.fluid;
-                cube.noise = self.noise;
-                cube.scale = scale;
-                cube.smooth = true;
-
-                cube.bottom_occluded = self.bottom_occluded(root);
-                cube.left_occluded = self.left_occluded(root);
-                cube.right_occluded = self.right_occluded(root);
-                cube.front_occluded = self.front_occluded(root);
-                cube.back_occluded = self.back_occluded(root);
-                cube.top_occluded = self.top_occluded(root);
-                cube.init();
-
-                let x = self.x_index as f32 * (1.0);
-                let y = self.y_index as f32 * (1.0);
-                let z = self.z_index as f32 * (1.0);
-                cube.translate([x, y, z]);
-
-                vec![cube]
-            } else {
-                let mut child_cubes: Vec<Cube> = vec![];
-                let squirts = self.children.each_mut();
-
-                for node_opt in squirts {
-                    match node_opt {
-                        None => {}
-                        Some(node) => {
-                            let mut cube = node.drawables(root);
-
-                            child_cubes.append(&mut cube);
-                        }
-                    };
-                }
-                child_cubes
-            }
-        } else if self.active {
-            let scale = 1.0;
-            let mut cube = Cube::new();
-
-            cube.color = self.color;
-            cube.fluid = self.fluid;
-            cube.noise = self.noise;
-            cube.scale = scale;
-            cube.smooth = true;
-
-            cube.bottom_occluded = self.bottom_occluded(root);
-            cube.left_occluded = self.left_occluded(root);
-            cube.right_occluded = self.right_occluded(root);
-            cube.front_occluded = self.front_occluded(root);
-            cube.back_occluded = self.back_occluded(root);
-            cube.top_occluded = self.top_occluded(root);
-            cube.init();
-
-            let x = self.x_index as f32 * (scale);
-            let y = self.y_index as f32 * (scale);
-            let z = self.z_index as f32 * (scale);
-
-            cube.translate([x, y, z]);
-
-            vec![cube]
-        } else {
-            vec![]
-        }
-    }
+    /// Restore voxels from a list of saved states and return the states they replaced.
+    pub fn set_voxels(&mut self, states: &[VoxelState]) -> Vec<VoxelState> {
+        let mut previous: Vec<VoxelState> = vec![];
 
-    /// Create smaller children cubes from this outer cube.
-    pub fn decimate(&mut self, sub_division_level: u32) {
-        if sub_division_level - 1 > 0 {
-            self.subdivide();
-
-            let squirts = self.children.each_mut();
-
-            for node_opt in squirts {
-                match node_opt {
-                    None => {
-                        log::debug!("Should not get here")
-                    }
-                    Some(node) => {
-                        node.decimate(sub_division_level - 1);
-                    }
-                };
+        for state in states {
+            let position = state.position;
+            if let Some(replaced) =
+                self.write(position[0], position[1], position[2], self.levels, state)
+            {
+                previous.push(replaced);
             }
         }
+        previous
     }
 
-    /// Used by the decimate function to create smaller cubes.
-    pub fn subdivide(&mut self) {
-        self.has_children = true;
-
-        self.children[0] = Some(Box::new(Ocnode {
-            x_index: self.x_index,
-            y_index: self.y_index,
-            z_index: self.z_index,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
+    /// Take a snapshot of this cube.
+    pub fn voxel_state(&self) -> VoxelState {
+        VoxelState {
+            position: [self.x_index, self.y_index, self.z_index],
+            active: self.active,
             color: self.color,
             fluid: self.fluid,
             noise: self.noise,
-        }));
+        }
+    }
 
-        self.children[1] = Some(Box::new(Ocnode {
-            x_index: self.x_index + self.resolution(self.sub_division_level + 1) as i32,
-            y_index: self.y_index,
-            z_index: self.z_index,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
-            color: self.color,
-            fluid: self.fluid,
-            noise: self.noise,
-        }));
-        self.children[2] = Some(Box::new(Ocnode {
-            x_index: self.x_index,
-            y_index: self.y_index + self.resolution(self.sub_division_level + 1) as i32,
-            z_index: self.z_index,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
-            color: self.color,
-            fluid: self.fluid,
-            noise: self.noise,
-        }));
-        self.children[3] = Some(Box::new(Ocnode {
-            x_index: self.x_index,
-            y_index: self.y_index,
-            z_index: self.z_index + self.resolution(self.sub_division_level + 1) as i32,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
-            color: self.color,
-            fluid: self.fluid,
-            noise: self.noise,
-        }));
-        self.children[4] = Some(Box::new(Ocnode {
-            x_index: self.x_index + self.resolution(self.sub_division_level + 1) as i32,
-            y_index: self.y_index + self.resolution(self.sub_division_level + 1) as i32,
-            z_index: self.z_index,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
-            color: self.color,
-            fluid: self.fluid,
-            noise: self.noise,
-        }));
-        self.children[5] = Some(Box::new(Ocnode {
-            x_index: self.x_index,
-            y_index: self.y_index + self.resolution(self.sub_division_level + 1) as i32,
-            z_index: self.z_index + self.resolution(self.sub_division_level + 1) as i32,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
-            color: self.color,
-            fluid: self.fluid,
-            noise: self.noise,
-        }));
-        self.children[6] = Some(Box::new(Ocnode {
-            x_index: self.x_index + self.resolution(self.sub_division_level + 1) as i32,
-            y_index: self.y_index,
-            z_index: self.z_index + self.resolution(self.sub_division_level + 1) as i32,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
-            color: self.color,
-            fluid: self.fluid,
-            noise: self.noise,
-        }));
-        self.children[7] = Some(Box::new(Ocnode {
-            x_index: self.x_index + self.resolution(self.sub_division_level + 1) as i32,
-            y_index: self.y_index + self.resolution(self.sub_division_level + 1) as i32,
-            z_index: self.z_index + self.resolution(self.sub_division_level + 1) as i32,
-            sub_division_level: self.sub_division_level + 1,
-            active: false,
-            children: [None, None, None, None, None, None, None, None],
-            has_children: false,
-            color: self.color,
-            fluid: self.fluid,
-            noise: self.noise,
-        }));
+    /// Split this cube into 8 smaller cubes with the same state.
+    pub fn subdivide(&mut self) {
+        let half = self.resolution(self.sub_division_level + 1) as i32;
+
+        for (index, child) in self.children.iter_mut().enumerate() {
+            *child = Some(Box::new(Ocnode {
+                x_index: self.x_index + if index & 1 != 0 { half } else { 0 },
+                y_index: self.y_index + if index & 2 != 0 { half } else { 0 },
+                z_index: self.z_index + if index & 4 != 0 { half } else { 0 },
+                sub_division_level: self.sub_division_level + 1,
+                active: self.active,
+                children: empty_list(),
+                has_children: false,
+                color: self.color,
+                fluid: self.fluid,
+                noise: self.noise,
+                levels: self.levels,
+            }));
+        }
+        self.has_children = true;
+        self.active = false;
     }
 }
@@ -1,33 +1,46 @@
-use crate::cube::Cube;
-use crate::ocnode::Ocnode;
-use crate::stored_octree::StoredOctree;
+use crate::flood::{self, Connectivity, Matching};
+use crate::mesh::Mesh;
+use crate::mesher;
+use crate::ocnode::{Ocnode, LEVELS, MAX_LEVELS, MIN_LEVELS};
+use crate::scene_format::{self, SceneFormatError};
+use crate::transform::Region;
+use crate::voxel_state::VoxelState;
 
-/// An octree has a name and a tree of nodes.
+/// An octree is a tree of nodes.
 #[derive(Clone)]
 pub struct Octree {
-    pub name: String,
     root: Ocnode,
     depth: u32,
+    /// Meshes built from the voxels, None when the voxels have changed.
+    meshes: Option<Vec<Mesh>>,
 }
 
 impl Octree {
     /// Create a new Octree
     pub const fn new() -> Octree {
         Octree {
-            name: String::new(),
-            root: Ocnode::new(),
-            depth: 1,
+            root: Ocnode::new(LEVELS),
+            depth: LEVELS,
+            meshes: None,
         }
     }
 
-    /// Get the full list of active nodes from the tree.
+    /// Get the full list of active nodes from the tree. Meshing and exporting use the
+    /// leaves of the tree instead, this is kept for code that wants every voxel as a node.
+    #[allow(dead_code)]
     pub fn active_nodes(&self) -> Vec<Ocnode> {
         self.root.active_nodes()
     }
 
+    /// The top cube of the tree.
+    pub fn root(&self) -> &Ocnode {
+        &self.root
+    }
+
     /// Hide all nodes in the tree.
     pub fn clear(&mut self) {
         self.root.clear();
+        self.meshes = None;
     }
 
     /// Optimize walks the tree and based on the camera position
@@ -36,42 +49,54 @@ impl Octree {
         self.root.optimize(camera_eye);
     }
 
-    /// Subdivide the tree into small cubes.
-    pub fn init(&mut self) {
-        // The LEVELS here is important. It defines the number of sub-divisions
-        // so it exponentially increases the number of nodes.
-        self.decimate(crate::ocnode::LEVELS);
+    /// How many levels the tree has.
+    pub fn levels(&self) -> u32 {
+        self.depth
     }
 
-    /// Change the name of the scene.
-    pub fn set_name(&mut self, name: String) {
-        self.name = name;
-    }
+    /// Replace the voxels with nodes saved before the binary format existed.
+    pub fn load_legacy(&mut self, levels: u32, nodes: &[Ocnode]) {
+        // Don't trust the stored size to be one we can handle.
+        self.decimate(levels.clamp(MIN_LEVELS, MAX_LEVELS));
 
-    /// Load the scene from browser indexeddb.
-    pub fn load_from_serial(&mut self, source: StoredOctree, camera_eye: [f32; 3]) {
-        self.name = source.name;
+        for node in nodes {
+            self.root.apply(node);
+        }
+    }
 
-        self.root.clear();
+    /// Replace the voxels with ones encoded by `encode`.
+    /// The tree is not changed if the data can't be decoded.
+    pub fn load_encoded(&mut self, data: &[u8]) -> Result<(), SceneFormatError> {
+        let (levels, blocks) = scene_format::decode(data)?;
+        self.decimate(levels);
 
-        for node in source.active_nodes {
-            self.root.apply(&node);
+        for (state, level) in blocks {
+            self.root.fill(&state, level);
         }
-        self.root.optimize(camera_eye);
+        Ok(())
+    }
+
+    /// Encode the voxels in the compact binary format.
+    pub fn encode(&self) -> Vec<u8> {
+        scene_format::encode(self.depth, &self.root.leaves())
     }
 
-    /// Generate the list of drawables from the tree of cubes.
-    pub fn drawables(&mut self) -> Vec<Cube> {
-        let borrow = self.root.clone();
-        self.root.drawables(&borrow)
+    /// Get the greedy meshed surface of the tree, one mesh per material.
+    /// The meshes are only rebuilt when the voxels have changed.
+    pub fn meshes(&mut self) -> &mut Vec<Mesh> {
+        self.meshes
+            .get_or_insert_with(|| mesher::build_meshes(&mesher::greedy_quads(&self.root)))
     }
 
-    /// Subdivide the tree into smaller cubes.
+    /// Set how many times the tree can be subdivided. Smaller cubes are
+    /// only created when voxels are changed, so this starts an empty tree.
     pub fn decimate(&mut self, sub_division_level: u32) {
         self.depth = sub_division_level;
-        self.root.decimate(sub_division_level);
+        self.root = Ocnode::new(sub_division_level);
+        self.meshes = None;
     }
 
+    /// Set the state of a list of voxels and return the states they replaced.
     pub fn toggle_voxels(
         &mut self,
         positions: Vec<[i32; 3]>,
@@ -80,18 +105,72 @@ impl Octree {
         camera_eye: [f32; 3],
         fluid: i32,
         noise: i32,
-    ) {
-        self.root
+    ) -> Vec<VoxelState> {
+        let previous = self
+            .root
             .toggle_voxels(&positions, value, color, fluid, noise);
         self.root.optimize(camera_eye);
+        self.meshes = None;
+        previous
+    }
+
+    /// Restore a list of saved voxel states and return the states they replaced.
+    pub fn set_voxels(&mut self, states: &[VoxelState], camera_eye: [f32; 3]) -> Vec<VoxelState> {
+        let previous = self.root.set_voxels(states);
+        self.root.optimize(camera_eye);
+        self.meshes = None;
+        previous
     }
 
-    /// Serialize the tree.
-    pub fn prepare(&self) -> StoredOctree {
-        StoredOctree {
-            name: String::from(self.name.as_str()),
-            active_nodes: self.active_nodes(),
+    /// Get the state of one voxel, None if it is outside the tree.
+    pub fn voxel_at(&self, position: [i32; 3]) -> Option<VoxelState> {
+        let [x, y, z] = position;
+        self.root.find_by_index(x, y, z, self.depth).map(|node| {
+            let mut state = node.voxel_state();
+            state.position = position;
+            state
+        })
+    }
+
+    /// Get every filled voxel inside a region.
+    pub fn voxels_in(&self, region: &Region) -> Vec<VoxelState> {
+        let mut found = vec![];
+        for (state, level) in self.root.leaves() {
+            if !state.active {
+                continue;
+            }
+            let size = 2i32.pow(self.depth - level);
+            let start = [0, 1, 2].map(|axis| state.position[axis].max(region.min[axis]));
+            let end = [0, 1, 2].map(|axis| (state.position[axis] + size).min(region.max[axis]));
+            for x in start[0]..end[0] {
+                for y in start[1]..end[1] {
+                    for z in start[2]..end[2] {
+                        found.push(VoxelState {
+                            position: [x, y, z],
+                            ..state
+                        });
+                    }
+                }
+            }
         }
+        found
+    }
+
+    /// Find the filled voxels connected to a start voxel that match it.
+    /// The search does not leave the world.
+    pub fn flood(
+        &self,
+        start: [i32; 3],
+        connectivity: Connectivity,
+        matching: Matching,
+    ) -> Vec<[i32; 3]> {
+        flood::flood(
+            start,
+            Ocnode::range(self.depth),
+            connectivity,
+            matching,
+            |position| self.voxel_at(position),
+        )
     }
 
     /// Check all indexes and determine if all nodes are active.
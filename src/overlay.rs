@@ -0,0 +1,46 @@
+use crate::mesh::Mesh;
+use crate::mesher;
+use crate::ocnode::Ocnode;
+use crate::voxel_state::VoxelState;
+
+/// How far each face is pushed out along its normal, so the overlay is drawn just
+/// outside the voxels it covers instead of fighting with their faces.
+const LIFT: f32 = 0.01;
+
+/// See through voxels drawn over the model, like a selection. They are meshed together
+/// so they take one draw call per material, and only meshed again when they change.
+pub struct Overlay {
+    /// The voxels the meshes were built from.
+    voxels: Vec<VoxelState>,
+    meshes: Vec<Mesh>,
+}
+
+impl Overlay {
+    /// Create an overlay with nothing in it.
+    pub const fn new() -> Overlay {
+        Overlay {
+            voxels: Vec::new(),
+            meshes: Vec::new(),
+        }
+    }
+
+    /// Get the meshes for a list of voxels in a tree with this many levels.
+    /// They are only rebuilt when the voxels are not the ones meshed last time.
+    pub fn meshes(&mut self, voxels: Vec<VoxelState>, levels: u32) -> &[Mesh] {
+        if voxels != self.voxels {
+            let mut root = Ocnode::new(levels);
+            root.set_voxels(&voxels);
+            let mut quads = mesher::greedy_quads(&root);
+            for quad in &mut quads {
+                for corner in &mut quad.corners {
+                    for (value, normal) in corner.iter_mut().zip(quad.normal) {
+                        *value += normal * LIFT;
+                    }
+                }
+            }
+            self.meshes = mesher::build_meshes(&quads);
+            self.voxels = voxels;
+        }
+        &self.meshes
+    }
+}
@@ -0,0 +1,224 @@
+use nalgebra::{Matrix4, Vector3, Vector4};
+
+/// A line from a point on the screen into the world.
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    /// Normalized.
+    pub direction: Vector3<f32>,
+}
+
+/// What a ray hit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hit {
+    /// The filled voxel that was hit, or the cell on the floor if no voxel was hit.
+    pub voxel: [i32; 3],
+    /// The cell in front of the face that was hit.
+    pub adjacent: [i32; 3],
+}
+
+/// Which cell a picked point moves the selection to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PickTarget {
+    /// The empty cell in front of the face under the cursor, used to add voxels.
+    Adjacent,
+    /// The voxel under the cursor, used to remove voxels.
+    Voxel,
+}
+
+impl PickTarget {
+    /// Find a pick target by name.
+    pub fn from_name(name: &str) -> Result<PickTarget, String> {
+        match name.to_lowercase().as_str() {
+            "adjacent" => Ok(PickTarget::Adjacent),
+            "voxel" => Ok(PickTarget::Voxel),
+            _ => Err(format!(
+                "Unknown pick target {name}, expected adjacent or voxel"
+            )),
+        }
+    }
+}
+
+impl Hit {
+    /// The cell to move the selection to.
+    pub fn cell(&self, target: PickTarget) -> [i32; 3] {
+        match target {
+            PickTarget::Adjacent => self.adjacent,
+            PickTarget::Voxel => self.voxel,
+        }
+    }
+}
+
+/// Remembers how the last frame was drawn so screen positions can be turned into rays.
+#[derive(Copy, Clone)]
+pub struct Viewport {
+    /// Turns normalized device coordinates back into world positions.
+    inverse: Matrix4<f32>,
+    width: f32,
+    height: f32,
+}
+
+impl Viewport {
+    /// Create a viewport from the matrices used to draw the frame.
+    /// Returns None if the matrices can't be inverted.
+    pub fn new(
+        projection: &Matrix4<f32>,
+        view: &Matrix4<f32>,
+        width: i32,
+        height: i32,
+    ) -> Option<Viewport> {
+        if width <= 0 || height <= 0 {
+            return None;
+        }
+        Some(Viewport {
+            inverse: (projection * view).try_inverse()?,
+            width: width as f32,
+            height: height as f32,
+        })
+    }
+
+    /// Unproject a point in normalized device coordinates.
+    fn unproject(&self, x: f32, y: f32, z: f32) -> Vector3<f32> {
+        let point = self.inverse * Vector4::new(x, y, z, 1.0);
+        point.xyz() / point.w
+    }
+
+    /// Make a ray through a pixel, measured from the top left of the canvas.
+    pub fn ray(&self, x: i32, y: i32) -> Ray {
+        let ndc_x = 2.0 * (x as f32 + 0.5) / self.width - 1.0;
+        let ndc_y = 1.0 - 2.0 * (y as f32 + 0.5) / self.height;
+        let near = self.unproject(ndc_x, ndc_y, -1.0);
+        let far = self.unproject(ndc_x, ndc_y, 1.0);
+        Ray {
+            origin: near,
+            direction: (far - near).normalize(),
+        }
+    }
+}
+
+/// Find where a ray enters and leaves a box. Returns None if it misses.
+fn clip_to_box(ray: &Ray, min: f32, max: f32) -> Option<(f32, f32)> {
+    let mut enter = 0.0f32;
+    let mut leave = f32::INFINITY;
+    for axis in 0..3 {
+        let origin = ray.origin[axis];
+        let direction = ray.direction[axis];
+        if direction.abs() < f32::EPSILON {
+            if origin < min || origin > max {
+                return None;
+            }
+            continue;
+        }
+        let first = (min - origin) / direction;
+        let second = (max - origin) / direction;
+        enter = enter.max(first.min(second));
+        leave = leave.min(first.max(second));
+    }
+    (enter <= leave).then_some((enter, leave))
+}
+
+/// Walk the cells along a ray through a world where positions go from -range to +range,
+/// using the voxel traversal from Amanatides and Woo. Every cell is passed to visit with
+/// the axis of the face the ray came in through and how far along the ray that face is.
+/// The walk stops when visit returns true or the ray leaves the world.
+pub fn traverse(ray: &Ray, range: i32, mut visit: impl FnMut([i32; 3], usize, f32) -> bool) {
+    let limit = range as f32;
+    let Some((enter, leave)) = clip_to_box(ray, -limit, limit) else {
+        return;
+    };
+    // Start a tiny bit inside the box so rounding puts us in the right cell.
+    let start = ray.origin + ray.direction * (enter + 1e-4);
+    let mut cell = [0, 1, 2].map(|axis| (start[axis].floor() as i32).clamp(-range, range - 1));
+    let step = [0, 1, 2].map(|axis| ray.direction[axis].signum() as i32);
+    let mut next_crossing = [0.0f32; 3];
+    let mut crossing_step = [f32::INFINITY; 3];
+    for axis in 0..3 {
+        let direction = ray.direction[axis];
+        if direction.abs() < f32::EPSILON {
+            next_crossing[axis] = f32::INFINITY;
+            continue;
+        }
+        let boundary = if step[axis] > 0 {
+            cell[axis] + 1
+        } else {
+            cell[axis]
+        } as f32;
+        next_crossing[axis] = (boundary - ray.origin[axis]) / direction;
+        crossing_step[axis] = 1.0 / direction.abs();
+    }
+
+    // The face we came in through, starting with the side of the box we entered.
+    let mut entered_axis = (0..3)
+        .max_by(|a, b| {
+            let entry = |axis: usize| {
+                let direction = ray.direction[axis];
+                if direction.abs() < f32::EPSILON {
+                    f32::NEG_INFINITY
+                } else {
+                    let side = if direction > 0.0 { -limit } else { limit };
+                    (side - ray.origin[axis]) / direction
+                }
+            };
+            entry(*a).total_cmp(&entry(*b))
+        })
+        .unwrap_or(1);
+    let mut distance = enter;
+
+    loop {
+        if visit(cell, entered_axis, distance) {
+            return;
+        }
+        let axis = (0..3)
+            .min_by(|a, b| next_crossing[*a].total_cmp(&next_crossing[*b]))
+            .unwrap_or(0);
+        if next_crossing[axis] > leave {
+            return;
+        }
+        cell[axis] += step[axis];
+        if cell[axis] < -range || cell[axis] >= range {
+            return;
+        }
+        distance = next_crossing[axis];
+        next_crossing[axis] += crossing_step[axis];
+        entered_axis = axis;
+    }
+}
+
+/// Find the first filled cell along a ray through a world where positions go from
+/// -range to +range. If no voxel is hit the ray is tested against the floor at y = 0.
+pub fn cast(ray: &Ray, range: i32, filled: impl Fn([i32; 3]) -> bool) -> Option<Hit> {
+    let mut found = None;
+    traverse(ray, range, |cell, entered_axis, _| {
+        if !filled(cell) {
+            return false;
+        }
+        let mut adjacent = cell;
+        adjacent[entered_axis] -= ray.direction[entered_axis].signum() as i32;
+        found = Some(Hit {
+            voxel: cell,
+            adjacent,
+        });
+        true
+    });
+    if found.is_some() {
+        return found;
+    }
+
+    // Nothing was hit so try the floor the grid is drawn on.
+    if ray.direction.y.abs() < f32::EPSILON {
+        return None;
+    }
+    let distance = -ray.origin.y / ray.direction.y;
+    if distance < 0.0 {
+        return None;
+    }
+    let point = ray.origin + ray.direction * distance;
+    let cell = [point.x.floor() as i32, 0, point.z.floor() as i32];
+    if cell.iter().any(|value| *value < -range || *value >= range) {
+        return None;
+    }
+    Some(Hit {
+        voxel: cell,
+        adjacent: cell,
+    })
+}
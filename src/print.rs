@@ -0,0 +1,574 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::{Settings, SettingsError};
+
+/// A voxel position.
+type Cell = [i32; 3];
+
+/// The 6 neighbours that share a face.
+const FACES: [Cell; 6] = [
+    [-1, 0, 0],
+    [1, 0, 0],
+    [0, -1, 0],
+    [0, 1, 0],
+    [0, 0, -1],
+    [0, 0, 1],
+];
+
+/// The widest drain hole we drill, in voxels.
+const MAX_DRAIN_HOLE_SIZE: u32 = 64;
+
+/// Things that stop a model being prepared for printing.
+#[derive(Debug, PartialEq)]
+pub enum PrintError {
+    /// There is nothing to print.
+    Empty,
+}
+
+impl fmt::Display for PrintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrintError::Empty => write!(f, "There are no visible voxels to print"),
+        }
+    }
+}
+
+/// How to turn the model into something printable.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PrintSettings {
+    /// How wide a voxel is on the print.
+    pub mm_per_voxel: f32,
+    /// Remove the inside of the model to save material.
+    pub hollow: bool,
+    /// How many voxels thick the walls of a hollowed model are.
+    pub wall_thickness: u32,
+    /// How many voxels wide the hole draining each hollow is. 0 leaves the hollows closed.
+    pub drain_hole_size: u32,
+}
+
+impl Default for PrintSettings {
+    fn default() -> PrintSettings {
+        PrintSettings {
+            mm_per_voxel: 1.0,
+            hollow: false,
+            wall_thickness: 2,
+            drain_hole_size: 2,
+        }
+    }
+}
+
+impl Settings for PrintSettings {
+    fn validate(&self) -> Result<(), SettingsError> {
+        if !self.mm_per_voxel.is_finite() || self.mm_per_voxel <= 0.0 {
+            return Err(SettingsError::InvalidValue(
+                "the size of a voxel must be more than 0".to_string(),
+            ));
+        }
+        if self.hollow && self.wall_thickness == 0 {
+            return Err(SettingsError::InvalidValue(
+                "the wall thickness must be at least 1".to_string(),
+            ));
+        }
+        if self.drain_hole_size > MAX_DRAIN_HOLE_SIZE {
+            return Err(SettingsError::InvalidValue(format!(
+                "drain holes can be at most {MAX_DRAIN_HOLE_SIZE} voxels wide"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// What was found and changed while preparing a model for printing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrintReport {
+    /// How many voxels are printed.
+    pub voxels: usize,
+    /// The size of every separate part, biggest first. Every part after the
+    /// first floats free of the biggest one.
+    pub islands: Vec<usize>,
+    /// Edges where voxels only touch along the edge, found before fixing.
+    pub non_manifold_edges: usize,
+    /// Corners where voxels only touch at the corner, found before fixing.
+    pub non_manifold_vertices: usize,
+    /// Voxels filled in to join voxels that only touched at an edge or corner.
+    pub added: usize,
+    /// Voxels removed by hollowing and drain holes.
+    pub removed: usize,
+    /// How many triangles the STL file has.
+    pub triangles: usize,
+    /// The size of the print in mm along x, y and z, with z up.
+    pub size_mm: [f32; 3],
+}
+
+/// Add two positions.
+fn offset(cell: Cell, by: Cell) -> Cell {
+    [cell[0] + by[0], cell[1] + by[1], cell[2] + by[2]]
+}
+
+/// The 4 voxels around an edge along an axis, starting at a corner point.
+/// Listed so each voxel is diagonal to the one two places along.
+fn edge_cells(point: Cell, axis: usize) -> [Cell; 4] {
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    [(0, 0), (1, 0), (1, 1), (0, 1)].map(|(du, dv)| {
+        let mut cell = point;
+        cell[u] -= 1 - du;
+        cell[v] -= 1 - dv;
+        cell
+    })
+}
+
+/// The 8 voxels around a corner point.
+fn vertex_cells(point: Cell) -> [Cell; 8] {
+    [0, 1, 2, 3, 4, 5, 6, 7].map(|bits| {
+        [
+            point[0] - 1 + (bits & 1),
+            point[1] - 1 + ((bits >> 1) & 1),
+            point[2] - 1 + ((bits >> 2) & 1),
+        ]
+    })
+}
+
+/// Split the voxels of a vertex into groups that share faces. Voxels that share a
+/// face around one corner differ in exactly one bit of their index.
+fn groups(members: u8) -> Vec<u8> {
+    let mut remaining = members;
+    let mut found = vec![];
+    while remaining != 0 {
+        let mut group = remaining & remaining.wrapping_neg();
+        loop {
+            let mut grown = group;
+            for bit in 0..8 {
+                if group & (1 << bit) != 0 {
+                    for flip in [1, 2, 4] {
+                        grown |= (1 << (bit ^ flip)) & remaining;
+                    }
+                }
+            }
+            if grown == group {
+                break;
+            }
+            group = grown;
+        }
+        remaining &= !group;
+        found.push(group);
+    }
+    found
+}
+
+/// Do two voxels touch only along an edge, with empty space on both other sides?
+/// Returns the voxel to fill to join them.
+fn edge_fix(filled: &HashSet<Cell>, point: Cell, axis: usize) -> Option<Cell> {
+    let cells = edge_cells(point, axis);
+    let inside = cells.map(|cell| filled.contains(&cell));
+    match inside {
+        [true, false, true, false] => Some(cells[1]),
+        [false, true, false, true] => Some(cells[0]),
+        _ => None,
+    }
+}
+
+/// Does a corner need a voxel filled to stop voxels touching only at the corner,
+/// or empty space pinching to a point? Returns the voxel to fill.
+fn vertex_fix(filled: &HashSet<Cell>, point: Cell) -> Option<Cell> {
+    let cells = vertex_cells(point);
+    let mut members = 0u8;
+    for (bit, cell) in cells.iter().enumerate() {
+        if filled.contains(cell) {
+            members |= 1 << bit;
+        }
+    }
+    let solid = groups(members);
+    let empty = groups(!members);
+    if solid.len() <= 1 && empty.len() <= 1 {
+        return None;
+    }
+    // Fill the empty voxel touching the most separate parts, joining them up.
+    (0..8)
+        .filter(|bit| members & (1 << bit) == 0)
+        .max_by_key(|bit| {
+            let neighbours = [1, 2, 4].map(|flip| 1u8 << (bit ^ flip));
+            solid
+                .iter()
+                .filter(|group| neighbours.iter().any(|mask| *group & mask != 0))
+                .count()
+        })
+        .map(|bit| cells[bit])
+}
+
+/// Every edge and corner of the filled voxels that have an empty neighbour.
+fn surface_points(filled: &HashSet<Cell>) -> (BTreeSet<(Cell, usize)>, BTreeSet<Cell>) {
+    points_around(filled.iter().filter(|cell| {
+        FACES
+            .iter()
+            .any(|by| !filled.contains(&offset(**cell, *by)))
+    }))
+}
+
+/// Every edge and corner of some voxels, as corner points and the axis along each edge.
+fn points_around<'a>(
+    cells: impl Iterator<Item = &'a Cell>,
+) -> (BTreeSet<(Cell, usize)>, BTreeSet<Cell>) {
+    let mut edges = BTreeSet::new();
+    let mut vertices = BTreeSet::new();
+    for cell in cells {
+        for corner in vertex_cells(offset(*cell, [1, 1, 1])) {
+            vertices.insert(corner);
+            for axis in 0..3 {
+                edges.insert((corner, axis));
+            }
+        }
+    }
+    (edges, vertices)
+}
+
+/// Count the edges and corners where the surface of the voxels is not manifold.
+fn count_problems(filled: &HashSet<Cell>) -> (usize, usize) {
+    let (edges, vertices) = surface_points(filled);
+    let edge_count = edges
+        .iter()
+        .filter(|(point, axis)| edge_fix(filled, *point, *axis).is_some())
+        .count();
+    let vertex_count = vertices
+        .iter()
+        .filter(|point| vertex_fix(filled, **point).is_some())
+        .count();
+    (edge_count, vertex_count)
+}
+
+/// Fill voxels until no voxels touch only at an edge or a corner. Only ever
+/// adding voxels means this always finishes. The whole surface is checked once,
+/// after that only the edges and corners of the voxels just added can change.
+/// Returns how many were added.
+fn make_manifold(filled: &mut HashSet<Cell>) -> usize {
+    let mut added = 0;
+    let (mut edges, mut vertices) = surface_points(filled);
+    while !edges.is_empty() || !vertices.is_empty() {
+        let mut changed: Vec<Cell> = vec![];
+        for (point, axis) in edges {
+            if let Some(cell) = edge_fix(filled, point, axis) {
+                filled.insert(cell);
+                changed.push(cell);
+            }
+        }
+        for point in vertices {
+            if let Some(cell) = vertex_fix(filled, point) {
+                filled.insert(cell);
+                changed.push(cell);
+            }
+        }
+        added += changed.len();
+        (edges, vertices) = points_around(changed.iter());
+    }
+    added
+}
+
+/// Split voxels into parts that share faces, biggest first.
+fn islands(filled: &HashSet<Cell>) -> Vec<Vec<Cell>> {
+    let mut seen: HashSet<Cell> = HashSet::new();
+    let mut found = vec![];
+    let mut cells: Vec<&Cell> = filled.iter().collect();
+    cells.sort();
+    for start in cells {
+        if !seen.insert(*start) {
+            continue;
+        }
+        let mut island = vec![*start];
+        let mut queue = VecDeque::from([*start]);
+        while let Some(cell) = queue.pop_front() {
+            for by in FACES {
+                let next = offset(cell, by);
+                if filled.contains(&next) && seen.insert(next) {
+                    island.push(next);
+                    queue.push_back(next);
+                }
+            }
+        }
+        found.push(island);
+    }
+    found.sort_by_key(|island| std::cmp::Reverse(island.len()));
+    found
+}
+
+/// Remove every voxel more than wall_thickness voxels from empty space,
+/// measured so walls are at least that thick in every direction. Returns the
+/// removed voxels.
+fn hollow(filled: &mut HashSet<Cell>, wall_thickness: u32) -> HashSet<Cell> {
+    let around: Vec<Cell> = (0..27)
+        .map(|index| [index % 3 - 1, (index / 3) % 3 - 1, index / 9 - 1])
+        .filter(|by| *by != [0, 0, 0])
+        .collect();
+    let mut depth: HashSet<Cell> = HashSet::new();
+    let mut queue: VecDeque<(Cell, u32)> = VecDeque::new();
+    for cell in filled.iter() {
+        if around
+            .iter()
+            .any(|by| !filled.contains(&offset(*cell, *by)))
+        {
+            depth.insert(*cell);
+            queue.push_back((*cell, 1));
+        }
+    }
+    while let Some((cell, distance)) = queue.pop_front() {
+        if distance >= wall_thickness {
+            continue;
+        }
+        for by in &around {
+            let next = offset(cell, *by);
+            if filled.contains(&next) && depth.insert(next) {
+                queue.push_back((next, distance + 1));
+            }
+        }
+    }
+    let removed: HashSet<Cell> = filled.difference(&depth).copied().collect();
+    filled.retain(|cell| depth.contains(cell));
+    removed
+}
+
+/// Drill a hole of size by size voxels down from the lowest point of every hollow
+/// so resin or powder can drain out. Returns how many voxels were removed.
+fn drain(filled: &mut HashSet<Cell>, hollows: &HashSet<Cell>, size: u32) -> usize {
+    if size == 0 {
+        return 0;
+    }
+    let mut removed = 0;
+    for hollow in islands(hollows) {
+        let bottom = hollow.iter().map(|cell| cell[1]).min().unwrap_or(0);
+        let floor: Vec<&Cell> = hollow.iter().filter(|cell| cell[1] == bottom).collect();
+        let count = floor.len() as f32;
+        let middle = [
+            floor.iter().map(|cell| cell[0] as f32).sum::<f32>() / count,
+            floor.iter().map(|cell| cell[2] as f32).sum::<f32>() / count,
+        ];
+        let Some(center) = floor.iter().min_by(|a, b| {
+            let distance = |cell: &&&Cell| {
+                (cell[0] as f32 - middle[0]).powi(2) + (cell[2] as f32 - middle[1]).powi(2)
+            };
+            distance(a).total_cmp(&distance(b))
+        }) else {
+            continue;
+        };
+        let start = -((size as i32 - 1) / 2);
+        for dx in start..start + size as i32 {
+            for dz in start..start + size as i32 {
+                let column = [center[0] + dx, bottom, center[2] + dz];
+                if !hollow.contains(&column) {
+                    continue;
+                }
+                let mut cell = offset(column, [0, -1, 0]);
+                while filled.remove(&cell) {
+                    removed += 1;
+                    cell = offset(cell, [0, -1, 0]);
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// Get voxels ready to print: join voxels that only touch at an edge or corner,
+/// then hollow them out and drill drain holes if asked. Returns the voxels to print
+/// and a report of what was found.
+pub fn prepare(
+    voxels: &[Cell],
+    settings: &PrintSettings,
+) -> Result<(HashSet<Cell>, PrintReport), PrintError> {
+    if voxels.is_empty() {
+        return Err(PrintError::Empty);
+    }
+    let mut filled: HashSet<Cell> = voxels.iter().copied().collect();
+    let (non_manifold_edges, non_manifold_vertices) = count_problems(&filled);
+    let islands = islands(&filled).iter().map(Vec::len).collect();
+    let mut added = make_manifold(&mut filled);
+
+    let mut removed = 0;
+    if settings.hollow {
+        let hollows = hollow(&mut filled, settings.wall_thickness);
+        removed = hollows.len() + drain(&mut filled, &hollows, settings.drain_hole_size);
+        // Thin parts of the hollow can leave walls touching at an edge.
+        added += make_manifold(&mut filled);
+    }
+
+    let triangles = faces(&filled).len() * 2;
+    let mut min = [i32::MAX; 3];
+    let mut max = [i32::MIN; 3];
+    for cell in &filled {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(cell[axis]);
+            max[axis] = max[axis].max(cell[axis] + 1);
+        }
+    }
+    let size = [0, 1, 2].map(|axis| (max[axis] - min[axis]) as f32 * settings.mm_per_voxel);
+    let report = PrintReport {
+        voxels: filled.len(),
+        islands,
+        non_manifold_edges,
+        non_manifold_vertices,
+        added,
+        removed,
+        triangles,
+        size_mm: [size[0], size[2], size[1]],
+    };
+    Ok((filled, report))
+}
+
+/// Every face of a filled voxel that has an empty neighbour, as the voxel and the
+/// index of the direction in `FACES`.
+fn faces(filled: &HashSet<Cell>) -> Vec<(Cell, usize)> {
+    let mut cells: Vec<&Cell> = filled.iter().collect();
+    cells.sort();
+    cells
+        .into_iter()
+        .flat_map(|cell| {
+            FACES
+                .iter()
+                .enumerate()
+                .filter(|(_, by)| !filled.contains(&offset(*cell, **by)))
+                .map(|(direction, _)| (*cell, direction))
+        })
+        .collect()
+}
+
+/// Write prepared voxels as a binary STL file in mm. Printers expect z to be up, so
+/// the model is turned to match and moved so it sits on the build plate at the origin.
+pub fn stl(filled: &HashSet<Cell>, mm_per_voxel: f32) -> Vec<u8> {
+    let faces = faces(filled);
+    let min = [0, 1, 2].map(|axis| filled.iter().map(|cell| cell[axis]).min().unwrap_or(0));
+    let max_z = filled.iter().map(|cell| cell[2] + 1).max().unwrap_or(0);
+    // Turn y up into z up, keeping the model the right way round.
+    let to_print = |point: [i32; 3]| -> [f32; 3] {
+        [
+            (point[0] - min[0]) as f32 * mm_per_voxel,
+            (max_z - point[2]) as f32 * mm_per_voxel,
+            (point[1] - min[1]) as f32 * mm_per_voxel,
+        ]
+    };
+
+    let mut bytes = vec![0u8; 80];
+    bytes[..20].copy_from_slice(b"creator print export");
+    bytes.extend_from_slice(&((faces.len() * 2) as u32).to_le_bytes());
+    for (cell, direction) in faces {
+        let by = FACES[direction];
+        let axis = by.iter().position(|value| *value != 0).unwrap_or(0);
+        let sign = by[axis];
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let corner = |du: i32, dv: i32| {
+            let mut point = cell;
+            if sign > 0 {
+                point[axis] += 1;
+            }
+            point[u] += du;
+            point[v] += dv;
+            to_print(point)
+        };
+        // Counter clockwise seen from outside, same as the render meshes.
+        let corners = if sign > 0 {
+            [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)]
+        } else {
+            [corner(0, 0), corner(0, 1), corner(1, 1), corner(1, 0)]
+        };
+        let normal = [by[0], -by[2], by[1]].map(|value| value as f32);
+        for triangle in [[0, 1, 2], [0, 2, 3]] {
+            for value in normal {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            for index in triangle {
+                for value in corners[index] {
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            bytes.extend_from_slice(&[0, 0]);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(size: i32) -> Vec<Cell> {
+        (0..size * size * size)
+            .map(|index| [index % size, (index / size) % size, index / (size * size)])
+            .collect()
+    }
+
+    #[test]
+    fn voxels_touching_at_an_edge_are_joined() {
+        let (filled, report) = prepare(&[[0, 0, 0], [1, 1, 0]], &PrintSettings::default()).unwrap();
+        assert_eq!(report.non_manifold_edges, 1);
+        assert_eq!(report.islands, [1, 1]);
+        assert_eq!(report.added, 1);
+        assert_eq!(report.voxels, 3);
+        assert!(filled.contains(&[0, 0, 0]) && filled.contains(&[1, 1, 0]));
+        assert_eq!(count_problems(&filled), (0, 0));
+        assert_eq!(islands(&filled).len(), 1);
+    }
+
+    #[test]
+    fn voxels_touching_at_a_corner_are_joined() {
+        let (filled, report) = prepare(&[[0, 0, 0], [1, 1, 1]], &PrintSettings::default()).unwrap();
+        assert_eq!(report.non_manifold_edges, 0);
+        assert_eq!(report.non_manifold_vertices, 1);
+        assert_eq!(report.islands, [1, 1]);
+        assert!(report.added > 0);
+        assert_eq!(report.voxels, 2 + report.added);
+        assert_eq!(count_problems(&filled), (0, 0));
+        assert_eq!(islands(&filled).len(), 1);
+    }
+
+    #[test]
+    fn a_hollowed_cube_keeps_its_walls_and_drains() {
+        let settings = PrintSettings {
+            hollow: true,
+            wall_thickness: 2,
+            drain_hole_size: 2,
+            ..PrintSettings::default()
+        };
+        let (filled, report) = prepare(&cube(8), &settings).unwrap();
+        // The middle 4 by 4 by 4 is removed, then 2 by 2 columns through the 2 voxel floor.
+        assert_eq!(report.removed, 64 + 8);
+        assert_eq!(report.voxels, 512 - 72);
+        assert_eq!(report.added, 0);
+        assert_eq!(report.islands, [512]);
+        assert_eq!(report.size_mm, [8.0, 8.0, 8.0]);
+        assert!((2..6).all(|y| !filled.contains(&[3, y, 3])));
+        let drained = filled
+            .iter()
+            .filter(|cell| cell[1] < 2 && (2..6).contains(&cell[0]) && (2..6).contains(&cell[2]))
+            .count();
+        assert_eq!(drained, 2 * 16 - 8);
+        assert_eq!(count_problems(&filled), (0, 0));
+
+        let closed = PrintSettings {
+            drain_hole_size: 0,
+            ..settings
+        };
+        let (_, report) = prepare(&cube(8), &closed).unwrap();
+        assert_eq!(report.removed, 64);
+    }
+
+    #[test]
+    fn the_stl_has_the_reported_triangles() {
+        for voxels in [vec![[0, 0, 0]], cube(3), vec![[0, 0, 0], [1, 1, 0]]] {
+            let (filled, report) = prepare(&voxels, &PrintSettings::default()).unwrap();
+            let bytes = stl(&filled, 1.0);
+            let count = u32::from_le_bytes(bytes[80..84].try_into().unwrap()) as usize;
+            assert_eq!(count, report.triangles);
+            assert_eq!(bytes.len(), 84 + 50 * report.triangles);
+        }
+        let (_, report) = prepare(&[[0, 0, 0]], &PrintSettings::default()).unwrap();
+        assert_eq!(report.triangles, 12);
+    }
+
+    #[test]
+    fn nothing_to_print_is_an_error() {
+        assert_eq!(
+            prepare(&[], &PrintSettings::default()).err(),
+            Some(PrintError::Empty)
+        );
+    }
+}
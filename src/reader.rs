@@ -0,0 +1,83 @@
+use std::fmt;
+
+/// The bytes ran out, or held a value that makes no sense. Each file format turns
+/// this into its own error.
+#[derive(Debug, PartialEq)]
+pub struct ReadError(pub String);
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Reads little endian values from a byte buffer without running off the end.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Start reading from the start of the buffer.
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, offset: 0 }
+    }
+
+    /// Are there more bytes to read?
+    pub fn has_more(&self) -> bool {
+        self.offset < self.bytes.len()
+    }
+
+    /// Take the next count bytes.
+    pub fn take(&mut self, count: usize) -> Result<&'a [u8], ReadError> {
+        let end = self
+            .offset
+            .checked_add(count)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| ReadError(format!("unexpected end of data at {}", self.offset)))?;
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    /// Read 4 bytes, like a chunk id.
+    pub fn id(&mut self) -> Result<[u8; 4], ReadError> {
+        let bytes = self.take(4)?;
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// Read a little endian signed integer.
+    pub fn i32(&mut self) -> Result<i32, ReadError> {
+        Ok(i32::from_le_bytes(self.id()?))
+    }
+
+    /// Read a size or count which must not be negative.
+    pub fn length(&mut self) -> Result<usize, ReadError> {
+        let value = self.i32()?;
+        usize::try_from(value).map_err(|_| ReadError(format!("negative length {value}")))
+    }
+
+    /// Read a little endian float.
+    pub fn f32(&mut self) -> Result<f32, ReadError> {
+        Ok(f32::from_le_bytes(self.id()?))
+    }
+
+    /// Read an unsigned LEB128 variable length integer.
+    pub fn varint(&mut self) -> Result<u64, ReadError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(ReadError(format!("number too long at {}", self.offset)))
+    }
+
+    /// Read a zig zag encoded signed integer.
+    pub fn signed(&mut self) -> Result<i32, ReadError> {
+        let value = self.varint()? as u32;
+        Ok(((value >> 1) as i32) ^ -((value & 1) as i32))
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use nalgebra::{Isometry3, Perspective3, Rotation3, Vector3};
+
+use crate::camera::Camera;
+use crate::image_import::Image;
+use crate::mesh_export::to_byte;
+use crate::picking::{self, Ray, Viewport};
+use crate::voxel_state::VoxelState;
+
+/// The biggest image we render, in pixels along each side.
+pub const MAX_SIZE: u32 = 4096;
+/// Light every face gets, the same as the camera shader.
+const AMBIENT: f32 = 0.5;
+/// How much light faces pointing at the light get.
+const DIFFUSE: f32 = 0.6;
+/// How much darker a face in shadow is.
+const SHADOW: f32 = 0.2;
+/// The shader lights faces from here, it does not move with the light camera.
+const LIGHT_DIRECTION: [f32; 3] = [3.0, 10.0, -5.0];
+/// The background colour of the editor, made opaque.
+pub const BACKGROUND: [f32; 4] = [0.1, 0.1, 0.8, 1.0];
+/// Stop looking through transparent voxels once a pixel is this opaque.
+const OPAQUE: f32 = 0.99;
+
+/// Things that stop an image being rendered.
+#[derive(Debug, PartialEq)]
+pub enum RenderError {
+    /// Images must be between 1 and `MAX_SIZE` pixels along each side.
+    InvalidSize(u32, u32),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::InvalidSize(width, height) => write!(
+                f,
+                "An image of {width} by {height} can't be rendered, each side must be 1 to {MAX_SIZE} pixels"
+            ),
+        }
+    }
+}
+
+/// The same random number as the camera shader.
+fn rand(x: f32, y: f32) -> f32 {
+    let value = (x * 12.9898 + y * 78.233).sin() * 43_758.547;
+    value - value.floor()
+}
+
+/// The ripples the camera shader fades fluid voxels with, at a point and time.
+fn animate_fluid(point: Vector3<f32>, time: f32) -> f32 {
+    const RIPPLES: [[f32; 3]; 6] = [
+        [100.0, 40.0, 10.0],
+        [50.0, -40.0, 30.0],
+        [-40.0, 40.0, -80.0],
+        [34.0, 23.0, 12.0],
+        [8.0, -13.0, 73.0],
+        [-25.0, 67.0, -34.0],
+    ];
+    RIPPLES
+        .iter()
+        .map(|ripple| ((point - Vector3::from(*ripple)).norm() * 4.0 + time * 10.0).sin())
+        .sum()
+}
+
+/// Lights rays through filled voxels the way the camera shader lights meshes.
+pub struct Tracer {
+    voxels: HashMap<[i32; 3], VoxelState>,
+    /// Rays only need to walk the smallest world that holds every voxel.
+    range: i32,
+    /// Faces pointing this way get the most diffuse light.
+    light_direction: Vector3<f32>,
+    /// Shadow rays go this way.
+    towards_light: Vector3<f32>,
+}
+
+impl Tracer {
+    /// Get ready to trace filled voxels, lit from a direction with shadows cast away
+    /// from the light camera like the editor does.
+    pub fn new(voxels: &[VoxelState], light: &Camera) -> Tracer {
+        let voxels: HashMap<[i32; 3], VoxelState> = voxels
+            .iter()
+            .filter(|voxel| voxel.active)
+            .map(|voxel| (voxel.position, *voxel))
+            .collect();
+        let range = voxels
+            .keys()
+            .flat_map(|position| position.map(|value| value.max(-value - 1)))
+            .max()
+            .map_or(1, |furthest| furthest + 1);
+        Tracer {
+            voxels,
+            range,
+            light_direction: Vector3::from(LIGHT_DIRECTION).normalize(),
+            towards_light: (light.eye - light.target).normalize(),
+        }
+    }
+
+    /// Turn the lights around the y axis, so they stay in the same place on screen
+    /// when the camera is turned by the same angle.
+    pub fn turn_light(&mut self, angle: f32) {
+        let turn = Rotation3::from_axis_angle(&Vector3::y_axis(), angle);
+        self.light_direction = turn * self.light_direction;
+        self.towards_light = turn * self.towards_light;
+    }
+
+    /// Is there a voxel between a point and the light?
+    fn in_shadow(&self, point: Vector3<f32>) -> bool {
+        let mut blocked = false;
+        let ray = Ray {
+            origin: point,
+            direction: self.towards_light,
+        };
+        picking::traverse(&ray, self.range, |cell, _, _| {
+            blocked = self.voxels.contains_key(&cell);
+            blocked
+        });
+        blocked
+    }
+
+    /// The colour seen along a ray. Transparent voxels are blended front to back over
+    /// the background. Fluid voxels are shown as they look at time 0.
+    pub fn trace(&self, ray: &Ray, background: [f32; 4]) -> [u8; 4] {
+        let mut color = [0.0f32; 3];
+        let mut alpha = 0.0f32;
+        let mut previous: Option<VoxelState> = None;
+        picking::traverse(ray, self.range, |cell, entered_axis, distance| {
+            let voxel = self.voxels.get(&cell).copied();
+            // Faces between voxels of the same material are not drawn.
+            let same = match (voxel, previous) {
+                (Some(voxel), Some(before)) => voxel.material_key() == before.material_key(),
+                _ => false,
+            };
+            previous = voxel;
+            let Some(voxel) = voxel.filter(|_| !same) else {
+                return false;
+            };
+
+            let mut normal = Vector3::zeros();
+            normal[entered_axis] = -ray.direction[entered_axis].signum();
+            let point = ray.origin + ray.direction * distance;
+            let shade = normal.dot(&self.light_direction).max(0.0);
+            let shadow = if self.in_shadow(point + normal * 1e-3) {
+                1.0
+            } else {
+                0.0
+            };
+            let mut combined = AMBIENT + DIFFUSE * shade - SHADOW * shadow;
+            if voxel.noise != 0 {
+                combined *= rand(point.x, point.y) * 0.2 + 0.9;
+            }
+            let mut opacity = voxel.color[3];
+            if voxel.fluid != 0 {
+                opacity *= animate_fluid(point, 0.0) * 0.2 + 0.9;
+            }
+            let opacity = opacity.clamp(0.0, 1.0);
+
+            let weight = (1.0 - alpha) * opacity;
+            for (channel, value) in color.iter_mut().enumerate() {
+                *value += weight * (voxel.color[channel] * combined).clamp(0.0, 1.0);
+            }
+            alpha += weight;
+            alpha >= OPAQUE
+        });
+
+        let weight = (1.0 - alpha) * background[3];
+        for (channel, value) in color.iter_mut().enumerate() {
+            *value += weight * background[channel];
+        }
+        alpha += weight;
+        // The colours are premultiplied, PNG wants them straight.
+        let straight = if alpha > 0.0 {
+            color.map(|channel| channel / alpha)
+        } else {
+            color
+        };
+        [
+            to_byte(straight[0]),
+            to_byte(straight[1]),
+            to_byte(straight[2]),
+            to_byte(alpha),
+        ]
+    }
+}
+
+/// Render filled voxels from a camera with the same ambient, diffuse and shadow lighting
+/// as the camera shader.
+pub fn render(
+    camera: &Camera,
+    light: &Camera,
+    width: u32,
+    height: u32,
+    background: [f32; 4],
+    voxels: &[VoxelState],
+) -> Result<Image, RenderError> {
+    if !(1..=MAX_SIZE).contains(&width) || !(1..=MAX_SIZE).contains(&height) {
+        return Err(RenderError::InvalidSize(width, height));
+    }
+    // The same projection as the camera in the editor.
+    let projection = Perspective3::new(
+        width as f32 / height as f32,
+        std::f32::consts::PI / 4.0,
+        1.0,
+        200.0,
+    )
+    .into_inner();
+    let view = Isometry3::look_at_rh(&camera.eye, &camera.target, &Vector3::y()).to_homogeneous();
+    let viewport = Viewport::new(&projection, &view, width as i32, height as i32)
+        .ok_or(RenderError::InvalidSize(width, height))?;
+    let tracer = Tracer::new(voxels, light);
+
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            pixels.push(tracer.trace(&viewport.ray(x as i32, y as i32), background));
+        }
+    }
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+
+    /// A camera looking at the middle of the voxel at the origin from along +x.
+    fn side_camera() -> Camera {
+        Camera {
+            eye: Point3::new(10.0, 0.5, 0.5),
+            target: Point3::new(0.5, 0.5, 0.5),
+        }
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        let camera = side_camera();
+        for (width, height) in [(0, 10), (10, 0), (MAX_SIZE + 1, 10), (10, MAX_SIZE + 1)] {
+            assert!(matches!(
+                render(&camera, &camera, width, height, BACKGROUND, &[]),
+                Err(RenderError::InvalidSize(w, h)) if w == width && h == height
+            ));
+        }
+    }
+
+    #[test]
+    fn an_empty_scene_is_the_background() {
+        let camera = side_camera();
+        let image = render(&camera, &camera, 8, 6, BACKGROUND, &[]).unwrap();
+        assert_eq!((image.width, image.height), (8, 6));
+        assert!(image
+            .pixels
+            .iter()
+            .all(|pixel| *pixel == BACKGROUND.map(to_byte)));
+    }
+
+    #[test]
+    fn a_voxel_is_lit_like_the_shader() {
+        let color = [0.4, 0.8, 0.2, 1.0];
+        let voxel = VoxelState {
+            position: [0, 0, 0],
+            active: true,
+            color,
+            fluid: 0,
+            noise: 0,
+        };
+        let camera = side_camera();
+        // The light is behind the camera so the face it sees is not in shadow.
+        let image = render(&camera, &camera, 9, 9, BACKGROUND, &[voxel]).unwrap();
+
+        // The +x face, lit by the shader's fixed light direction.
+        let shade = Vector3::from(LIGHT_DIRECTION).normalize().x;
+        let combined = AMBIENT + DIFFUSE * shade;
+        let expected = [
+            to_byte(color[0] * combined),
+            to_byte(color[1] * combined),
+            to_byte(color[2] * combined),
+            255,
+        ];
+        assert_eq!(image.pixels[4 * 9 + 4], expected);
+        assert_eq!(image.pixels[0], BACKGROUND.map(to_byte));
+    }
+
+    #[test]
+    fn rendered_images_survive_png() {
+        let voxel = VoxelState {
+            position: [0, 0, 0],
+            active: true,
+            color: [1.0, 0.0, 0.0, 0.5],
+            fluid: 0,
+            noise: 0,
+        };
+        let camera = side_camera();
+        let image = render(&camera, &camera, 16, 12, [0.0; 4], &[voxel]).unwrap();
+        let decoded = Image::decode_png(&image.encode_png().unwrap()).unwrap();
+        assert_eq!((decoded.width, decoded.height), (16, 12));
+        assert_eq!(decoded.pixels, image.pixels);
+    }
+}
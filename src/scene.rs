@@ -1,21 +1,45 @@
 use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::sync::{Mutex, MutexGuard};
 use web_sys::WebGlRenderingContext;
 use web_time::{Duration, Instant};
 
-use crate::command::{Command, CommandType};
+use crate::animation::{Easing, LayerAnimation, Preset, Timeline};
+use crate::clipboard::{Clipboard, PasteMode};
+use crate::command::{Command, CommandType, MODIFIER_CTRL, MODIFIER_SHIFT};
 use crate::command_queue::CommandQueue;
 use crate::drawable::Drawable;
+use crate::flood::{Connectivity, Matching};
+use crate::gltf;
 use crate::graphics::Graphics;
 use crate::grid::Grid;
+use crate::history::{Edit, History};
+use crate::image_import::{self, Image, Plane};
+use crate::layer::LayerError;
+use crate::mesh_export;
+use crate::mesh_import::{self, Triangle};
+use crate::mesher;
 use crate::model::Model;
 use crate::mouse::Mouse;
-use crate::ocnode::Ocnode;
+use crate::ocnode::{MAX_LEVELS, MIN_LEVELS};
+use crate::overlay::Overlay;
+use crate::picking::{self, PickTarget, Viewport};
+use crate::print::{self, PrintReport, PrintSettings};
+use crate::render;
+use crate::scene_file::{self, CameraSettings, SceneFileError, SceneSettings};
+use crate::settings;
+use crate::sprite_sheet::{self, SpriteSheetSettings};
 use crate::storage::Storage;
 use crate::stored_octree::StoredOctree;
+use crate::symmetry::Symmetry;
+use crate::terrain::{self, TerrainSettings};
+use crate::transform::{self, Region, Transform};
+use crate::vox::VoxError;
+use crate::voxel_state::VoxelState;
 use crate::{camera::Camera, cube::Cube};
 use gloo::events::EventListener;
-use nalgebra::{Point2, Point3, Vector3};
+use nalgebra::{Isometry3, Point2, Point3, Vector3};
 use wasm_bindgen::JsCast;
 
 /// Simple list of supported selection shapes.
@@ -31,6 +55,48 @@ pub enum SelectionShape {
     CircleYZ,
 }
 
+/// What happens to the voxels in the selection when it is used.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum PaintMode {
+    /// Fill the selection, or empty it if it is already full.
+    Toggle,
+    /// Fill every voxel in the selection.
+    Add,
+    /// Empty every voxel in the selection.
+    Erase,
+    /// Change the material of the filled voxels in the selection.
+    Paint,
+    /// Copy the material of the voxel in the middle of the selection.
+    Eyedropper,
+}
+
+impl PaintMode {
+    /// Find a paint mode by name.
+    pub fn from_name(name: &str) -> Result<PaintMode, String> {
+        match name.to_lowercase().as_str() {
+            "toggle" => Ok(PaintMode::Toggle),
+            "add" => Ok(PaintMode::Add),
+            "erase" => Ok(PaintMode::Erase),
+            "paint" => Ok(PaintMode::Paint),
+            "eyedropper" => Ok(PaintMode::Eyedropper),
+            _ => Err(format!(
+                "Unknown paint mode {name}, expected toggle, add, erase, paint or eyedropper"
+            )),
+        }
+    }
+
+    /// The name used by `from_name`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PaintMode::Toggle => "toggle",
+            PaintMode::Add => "add",
+            PaintMode::Erase => "erase",
+            PaintMode::Paint => "paint",
+            PaintMode::Eyedropper => "eyedropper",
+        }
+    }
+}
+
 /// This represents the data and the links to input/output required to render the scene.
 pub struct Scene {
     /// The current camera.
@@ -53,6 +119,16 @@ pub struct Scene {
     selection_radius: u32,
     /// What shape is the selection.
     selection_shape: SelectionShape,
+    /// What using the selection does.
+    paint_mode: PaintMode,
+    /// Mirror planes that copy every edit.
+    symmetry: Symmetry,
+    /// Voxels that were copied or cut.
+    clipboard: Clipboard,
+    /// Show where the clipboard would be pasted.
+    pasting: bool,
+    /// Where the clipboard would be pasted, meshed so it can be drawn in one call per material.
+    ghost_overlay: Overlay,
     /// What colour will we fill if the selection is toggled.
     material_color: [f32; 4],
     /// Are we currently drawing a frame?
@@ -75,6 +151,22 @@ pub struct Scene {
     grid_visible: bool,
     /// Speed of re-drawing when screen is idle.
     target_fps: u32,
+    /// Voxel edits that can be undone or redone.
+    history: History,
+    /// The play head for layer animations.
+    timeline: Timeline,
+    /// How the last frame was drawn, used to find what is under the mouse.
+    viewport: Option<Viewport>,
+    /// Does the mouse pick the voxel under it or the empty cell in front of it?
+    pick_target: PickTarget,
+    /// Voxels chosen with the magic wand that can be recoloured, deleted or moved together.
+    selected: Vec<[i32; 3]>,
+    /// The magic wand selection meshed so it can be drawn in one call.
+    selected_overlay: Overlay,
+    /// Which neighbours the magic wand spreads to.
+    wand_connectivity: Connectivity,
+    /// What neighbours must share with the start voxel for the magic wand to spread to them.
+    wand_matching: Matching,
 }
 
 impl Scene {
@@ -109,6 +201,11 @@ impl Scene {
             selection_position: [0, 0, 0],
             selection_radius: 1,
             selection_shape: SelectionShape::Sphere,
+            paint_mode: PaintMode::Toggle,
+            symmetry: Symmetry::new(),
+            clipboard: Clipboard::new(),
+            pasting: false,
+            ghost_overlay: Overlay::new(),
             material_color: [0.8, 0.8, 0.8, 1.0],
             drawing: false,
             throttle: 10,
@@ -120,6 +217,14 @@ impl Scene {
             last_draw: None,
             grid_visible: true,
             target_fps: 1,
+            history: History::new(),
+            timeline: Timeline::new(),
+            viewport: None,
+            pick_target: PickTarget::Adjacent,
+            selected: Vec::new(),
+            selected_overlay: Overlay::new(),
+            wand_connectivity: Connectivity::Faces,
+            wand_matching: Matching::Occupancy,
         });
         GLOBSTATE.lock().unwrap()
     }
@@ -144,21 +249,107 @@ impl Scene {
         self.model.set_name(name);
     }
 
+    /// Start a new empty scene with a world that is size voxels wide.
+    /// The size must be a power of 2 between 16 and 512.
+    pub fn new_scene(name: String, size: u32) -> Result<(), String> {
+        let smallest = 2u32.pow(MIN_LEVELS - 1);
+        let largest = 2u32.pow(MAX_LEVELS - 1);
+        if !size.is_power_of_two() || size < smallest || size > largest {
+            return Err(format!(
+                "World size must be a power of 2 between {smallest} and {largest}, got {size}"
+            ));
+        }
+        let levels = size.trailing_zeros() + 1;
+
+        let mut scene = Self::access();
+        scene.model.reset(levels);
+        scene.set_name(name);
+        scene.history.clear();
+        scene.selected.clear();
+        let position = scene.selection_position;
+        scene
+            .selection_cube
+            .translate(position.map(|value| -value as f32));
+        scene.selection_position = [0, 0, 0];
+        scene.fit_grid();
+        scene.dirty = true;
+        Ok(())
+    }
+
+    /// Make the grid cover the whole world.
+    fn fit_grid(&mut self) {
+        let size = (self.model.range() * 2) as u16;
+        if self.grid_xz.scale != size {
+            self.grid_xz.set_scale(size);
+        }
+    }
+
     /// Process a mouse down event.
-    pub fn handle_mouse_down(scene: &mut Scene) {
-        scene.mouse.is_pressed = true;
+    pub fn handle_mouse_down(command: &Command, scene: &mut Scene) {
+        let position = Point2::new(command.data1 as i32, command.data2 as i32);
+        scene.mouse.press(position);
     }
 
-    /// Process a mouse up event.
-    pub fn handle_mouse_up(scene: &mut Scene) {
+    /// Process a mouse up event. A click without a drag toggles the voxels under the mouse.
+    pub fn handle_mouse_up(command: &Command, scene: &mut Scene) {
+        let position = Point2::new(command.data1 as i32, command.data2 as i32);
+        let clicked = scene.mouse.is_pressed && !scene.mouse.update_drag(position);
         scene.mouse.is_pressed = false;
+        if clicked && Self::pick(scene, position) {
+            Self::handle_use_selection(scene);
+        }
+    }
+
+    /// Move the selection to the cell under a point on the canvas.
+    /// Returns false if there is nothing under the point.
+    pub fn pick(scene: &mut Scene, position: Point2<i32>) -> bool {
+        let Some(viewport) = scene.viewport else {
+            return false;
+        };
+        let ray = viewport.ray(position.x, position.y);
+        let range = scene.model.range();
+        let model = &scene.model;
+        let hit = picking::cast(&ray, range, |cell| model.visible_voxel_at(cell).is_some());
+        // Only toggling and adding need the empty cell in front of a voxel.
+        let target = match scene.paint_mode {
+            PaintMode::Toggle | PaintMode::Add => scene.pick_target,
+            PaintMode::Erase | PaintMode::Paint | PaintMode::Eyedropper => PickTarget::Voxel,
+        };
+        let Some(cell) = hit.map(|hit| hit.cell(target)) else {
+            return false;
+        };
+        if cell.iter().any(|value| *value < -range || *value >= range) {
+            return false;
+        }
+        scene.selection_position = cell;
+        true
+    }
+
+    /// Switch between picking the voxel under the mouse and the empty cell in front of it.
+    pub fn handle_toggle_pick_target(scene: &mut Scene) {
+        scene.pick_target = match scene.pick_target {
+            PickTarget::Adjacent => PickTarget::Voxel,
+            PickTarget::Voxel => PickTarget::Adjacent,
+        };
+        log::info!("Pick target is now {:?}", scene.pick_target);
+    }
+
+    /// Choose what the mouse picks for the global scene.
+    pub fn set_pick_target(name: &str) -> Result<(), String> {
+        let target = PickTarget::from_name(name)?;
+        let mut scene = Self::access();
+        scene.pick_target = target;
+        Ok(())
     }
 
-    /// Process a mouse moved event.
+    /// Process a mouse moved event. Dragging orbits the camera,
+    /// otherwise the selection follows the mouse.
     pub fn handle_mouse_moved(command: &Command, scene: &mut Scene) {
         let current_position = Point2::new(command.data1 as i32, command.data2 as i32);
 
-        if scene.mouse.is_pressed {
+        if !scene.mouse.is_pressed {
+            Self::pick(scene, current_position);
+        } else if scene.mouse.update_drag(current_position) {
             let position_diff = Point2::new(
                 current_position.x - scene.mouse.last_position.x,
                 current_position.y - scene.mouse.last_position.y,
@@ -283,12 +474,125 @@ impl Scene {
     }
 
     /// The key was pressed to toggle the state of the current selection.
-    pub fn handle_toggle_voxel(scene: &mut Scene) {
+    /// Use the selection in the current paint mode.
+    pub fn handle_use_selection(scene: &mut Scene) {
+        match scene.paint_mode {
+            PaintMode::Toggle => Self::handle_toggle_voxel(scene),
+            PaintMode::Add => Self::handle_fill_voxels(scene, true),
+            PaintMode::Erase => Self::handle_fill_voxels(scene, false),
+            PaintMode::Paint => Self::handle_paint_voxels(scene),
+            PaintMode::Eyedropper => Self::handle_eyedropper(scene),
+        }
+    }
+
+    /// Choose what using the selection does.
+    pub fn handle_set_paint_mode(scene: &mut Scene, mode: PaintMode) {
+        scene.paint_mode = mode;
+        log::info!("Paint mode is now {}", mode.name());
+    }
+
+    /// Choose what using the selection does for the global scene.
+    pub fn set_paint_mode(name: &str) -> Result<(), String> {
+        let mode = PaintMode::from_name(name)?;
+        let mut scene = Self::access();
+        Self::handle_set_paint_mode(&mut scene, mode);
+        Ok(())
+    }
+
+    /// What using the selection does for the global scene.
+    pub fn paint_mode() -> String {
+        let scene = Self::access();
+        scene.paint_mode.name().to_string()
+    }
+
+    /// The current material color with every channel between 0 and 1.
+    fn clamped_material_color(&self) -> [f32; 4] {
+        self.material_color.map(|channel| channel.clamp(0.0, 1.0))
+    }
+
+    /// Fill or empty every voxel in the selection.
+    pub fn handle_fill_voxels(scene: &mut Scene, value: bool) {
+        let selections = Self::edit_positions(scene);
+        let color = scene.clamped_material_color();
+        let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
+        let layer = scene.model.active_layer_id();
+        match scene.model.toggle_voxels(
+            selections,
+            value,
+            color,
+            camera_eye,
+            scene.fluid,
+            scene.noise,
+        ) {
+            Ok(previous) => scene.history.record(layer, previous),
+            Err(error) => log::info!("{error}"),
+        }
+    }
+
+    /// Give the filled voxels in the selection the current material without filling empty ones.
+    pub fn handle_paint_voxels(scene: &mut Scene) {
+        let selections = Self::edit_positions(scene);
+        let color = scene.clamped_material_color();
+        let states: Vec<VoxelState> = scene
+            .model
+            .active_voxels_at(&selections)
+            .into_iter()
+            .filter(|state| state.active)
+            .map(|state| VoxelState {
+                color,
+                fluid: scene.fluid,
+                noise: scene.noise,
+                ..state
+            })
+            .collect();
+        if let Err(error) = Self::edit_voxels(scene, &states) {
+            log::info!("{error}");
+        }
+    }
+
+    /// Copy the colour, fluid and noise of the visible voxel in the middle of the selection.
+    pub fn handle_eyedropper(scene: &mut Scene) {
+        let Some(voxel) = scene.model.visible_voxel_at(scene.selection_position) else {
+            log::info!("There is no voxel to copy the material from");
+            return;
+        };
+        let [red, green, blue, _alpha] = voxel.color;
+        scene.material_color = voxel.color;
+        scene.selection_cube.color = [red, green, blue, 0.5];
+        scene.fluid = voxel.fluid;
+        scene.noise = voxel.noise;
+    }
+
+    /// The voxels in the selection and their copies across the mirror planes.
+    fn edit_positions(scene: &Scene) -> Vec<[i32; 3]> {
+        let range = scene.model.range();
         let selections = Self::selection_voxels(
             &scene.selection_position,
             scene.selection_radius as i32,
             scene.selection_shape,
+            range,
         );
+        scene.symmetry.mirror(selections, range)
+    }
+
+    /// Turn the mirror plane across an axis on or off, keeping where it is.
+    pub fn handle_toggle_mirror(scene: &mut Scene, axis: usize) {
+        scene.symmetry.enabled[axis] = !scene.symmetry.enabled[axis];
+        log::info!("Mirror planes are now {:?}", scene.symmetry.enabled);
+    }
+
+    /// Turn the mirror plane across an axis on or off and move it for the global scene.
+    pub fn set_mirror(axis: &str, enabled: bool, position: f32) -> Result<(), String> {
+        let axis = Symmetry::axis_from_name(axis)?;
+        let mut scene = Self::access();
+        scene.symmetry.set_plane(axis, enabled, position)?;
+        scene.dirty = true;
+        Ok(())
+    }
+
+    /// Fill the selection, or empty it if every voxel in it is already filled.
+    pub fn handle_toggle_voxel(scene: &mut Scene) {
+        let selections = Self::edit_positions(scene);
 
         let value: bool = scene.model.all_voxels_active(&selections);
         let count = selections.len();
@@ -299,24 +603,371 @@ impl Scene {
         } else {
             log::info!("Toggle all voxels active: TRUE {count} {fluid} {noise}");
         }
-        //for selection in selections {
-        //log::info!("Selection {:?}", selection);
-        let color = [
-            (scene.material_color[0]).clamp(0.0, 1.0),
-            (scene.material_color[1]).clamp(0.0, 1.0),
-            (scene.material_color[2]).clamp(0.0, 1.0),
-            (scene.material_color[3]).clamp(0.0, 1.0),
-        ];
-        let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
-        scene.model.toggle_voxels(
-            selections,
-            !value,
-            color,
-            camera_eye,
-            scene.fluid,
-            scene.noise,
+        Self::handle_fill_voxels(scene, !value);
+    }
+
+    /// Select the voxels in the active layer connected to the one in the middle of the selection.
+    pub fn handle_magic_wand(scene: &mut Scene) {
+        scene.selected = scene.model.flood(
+            scene.selection_position,
+            scene.wand_connectivity,
+            scene.wand_matching,
         );
-        //}
+        log::info!("Selected {} connected voxels", scene.selected.len());
+    }
+
+    /// Set up the magic wand and use it at the selection for the global scene.
+    /// Returns how many voxels were selected.
+    pub fn select_connected(
+        neighbours: u32,
+        matching: &str,
+        tolerance: f32,
+    ) -> Result<usize, String> {
+        let connectivity =
+            Connectivity::from_neighbours(neighbours).map_err(|error| error.to_string())?;
+        let matching =
+            Matching::from_name(matching, tolerance).map_err(|error| error.to_string())?;
+        let mut scene = Self::access();
+        scene.wand_connectivity = connectivity;
+        scene.wand_matching = matching;
+        Self::handle_magic_wand(&mut scene);
+        scene.dirty = true;
+        Ok(scene.selected.len())
+    }
+
+    /// Forget the magic wand selection.
+    pub fn handle_clear_selected(scene: &mut Scene) {
+        scene.selected.clear();
+    }
+
+    /// Forget the magic wand selection for the global scene.
+    pub fn clear_selected() {
+        let mut scene = Self::access();
+        Self::handle_clear_selected(&mut scene);
+        scene.dirty = true;
+    }
+
+    /// Write voxel states into the active layer as one undoable edit.
+    fn edit_voxels(scene: &mut Scene, states: &[VoxelState]) -> Result<(), LayerError> {
+        let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
+        let layer = scene.model.active_layer_id();
+        let previous = scene.model.edit_voxels(states, camera_eye)?;
+        scene.history.record(layer, previous);
+        Ok(())
+    }
+
+    /// Paint the selected voxels with the current material.
+    pub fn handle_recolor_selected(scene: &mut Scene) {
+        let color = scene.clamped_material_color();
+        let states: Vec<VoxelState> = scene
+            .model
+            .active_voxels_at(&scene.selected)
+            .into_iter()
+            .filter(|state| state.active)
+            .map(|state| VoxelState {
+                color,
+                fluid: scene.fluid,
+                noise: scene.noise,
+                ..state
+            })
+            .collect();
+        if let Err(error) = Self::edit_voxels(scene, &states) {
+            log::info!("{error}");
+        }
+    }
+
+    /// Paint the selected voxels with the current material for the global scene.
+    pub fn recolor_selected() {
+        let mut scene = Self::access();
+        Self::handle_recolor_selected(&mut scene);
+        scene.dirty = true;
+    }
+
+    /// Remove the selected voxels.
+    pub fn handle_delete_selected(scene: &mut Scene) {
+        let states: Vec<VoxelState> = scene
+            .model
+            .active_voxels_at(&scene.selected)
+            .into_iter()
+            .map(|state| VoxelState {
+                active: false,
+                ..state
+            })
+            .collect();
+        match Self::edit_voxels(scene, &states) {
+            Ok(()) => scene.selected.clear(),
+            Err(error) => log::info!("{error}"),
+        }
+    }
+
+    /// Remove the selected voxels for the global scene.
+    pub fn delete_selected() {
+        let mut scene = Self::access();
+        Self::handle_delete_selected(&mut scene);
+        scene.dirty = true;
+    }
+
+    /// Move the selected voxels by an offset. The selection moves with them.
+    /// Nothing changes if any voxel would leave the world.
+    pub fn handle_move_selected(scene: &mut Scene, offset: [i32; 3]) -> Result<(), String> {
+        let range = scene.model.range();
+        let moved: Vec<[i32; 3]> = scene
+            .selected
+            .iter()
+            .map(|position| [0, 1, 2].map(|axis| position[axis] + offset[axis]))
+            .collect();
+        if let Some(outside) = moved.iter().find(|position| {
+            position
+                .iter()
+                .any(|value| *value < -range || *value >= range)
+        }) {
+            return Err(format!(
+                "The selection can't move, {outside:?} is outside the world"
+            ));
+        }
+
+        // Work out the final state of every voxel touched so the move is a single edit.
+        let sources = scene.model.active_voxels_at(&scene.selected);
+        let mut states: HashMap<[i32; 3], VoxelState> = HashMap::new();
+        for source in &sources {
+            states.insert(
+                source.position,
+                VoxelState {
+                    active: false,
+                    ..*source
+                },
+            );
+        }
+        for source in &sources {
+            let position = [0, 1, 2].map(|axis| source.position[axis] + offset[axis]);
+            states.insert(
+                position,
+                VoxelState {
+                    position,
+                    ..*source
+                },
+            );
+        }
+        let mut states: Vec<VoxelState> = states.into_values().collect();
+        states.sort_by_key(|state| state.position);
+
+        Self::edit_voxels(scene, &states).map_err(|error| error.to_string())?;
+        scene.selected = moved;
+        Ok(())
+    }
+
+    /// Move the selected voxels by an offset for the global scene.
+    pub fn move_selected(offset: [i32; 3]) -> Result<(), String> {
+        let mut scene = Self::access();
+        Self::handle_move_selected(&mut scene, offset)?;
+        scene.dirty = true;
+        Ok(())
+    }
+
+    /// Rearrange the filled voxels in the box around the selection of the active layer.
+    /// The box holds the magic wand selection if there is one, otherwise the selection shape.
+    /// Nothing changes if any voxel would leave the world.
+    pub fn handle_transform(scene: &mut Scene, transform: Transform) -> Result<(), String> {
+        let Some(region) = Region::bounding(&Self::copy_positions(scene)) else {
+            return Ok(());
+        };
+        let voxels = scene.model.active_voxels_in(&region);
+        let states = transform::apply(&voxels, &region, transform, scene.model.range())
+            .map_err(|error| error.to_string())?;
+        Self::edit_voxels(scene, &states).map_err(|error| error.to_string())?;
+        if !scene.selected.is_empty() {
+            scene.selected = states
+                .iter()
+                .filter(|state| state.active)
+                .map(|state| state.position)
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Rearrange the voxels around the selection for the global scene.
+    pub fn transform(transform: Transform) -> Result<(), String> {
+        let mut scene = Self::access();
+        Self::handle_transform(&mut scene, transform)?;
+        scene.dirty = true;
+        Ok(())
+    }
+
+    /// Log why a transform from a key press could not be applied.
+    fn handle_transform_key(scene: &mut Scene, transform: Transform) {
+        if let Err(error) = Self::handle_transform(scene, transform) {
+            log::info!("{error}");
+        }
+    }
+
+    /// The voxels to copy: the magic wand selection if there is one, otherwise the selection
+    /// shape. Only positions inside the world are kept, so a box around them never reaches
+    /// past the edge.
+    fn copy_positions(scene: &Scene) -> Vec<[i32; 3]> {
+        if !scene.selected.is_empty() {
+            return scene.selected.clone();
+        }
+        let range = scene.model.range();
+        Self::selection_voxels(
+            &scene.selection_position,
+            scene.selection_radius as i32,
+            scene.selection_shape,
+            range,
+        )
+        .into_iter()
+        .filter(|position| position.iter().all(|value| (-range..range).contains(value)))
+        .collect()
+    }
+
+    /// Copy the voxels in the selection of the active layer to the clipboard.
+    pub fn handle_copy(scene: &mut Scene) {
+        let positions = Self::copy_positions(scene);
+        let states = scene.model.active_voxels_at(&positions);
+        scene.clipboard = Clipboard::copy(&states, scene.selection_position);
+        scene.pasting = !scene.clipboard.is_empty();
+        log::info!("Copied {} voxels", scene.clipboard.voxels.len());
+    }
+
+    /// Copy the voxels in the selection and then empty it.
+    pub fn handle_cut(scene: &mut Scene) {
+        let positions = Self::copy_positions(scene);
+        let states = scene.model.active_voxels_at(&positions);
+        let cleared: Vec<VoxelState> = states
+            .iter()
+            .filter(|state| state.active)
+            .map(|state| VoxelState {
+                active: false,
+                ..*state
+            })
+            .collect();
+        if let Err(error) = Self::edit_voxels(scene, &cleared) {
+            log::info!("{error}");
+            return;
+        }
+        scene.clipboard = Clipboard::copy(&states, scene.selection_position);
+        scene.pasting = !scene.clipboard.is_empty();
+        scene.selected.clear();
+        log::info!("Cut {} voxels", scene.clipboard.voxels.len());
+    }
+
+    /// Paste the clipboard into the active layer at the selection.
+    pub fn handle_paste(scene: &mut Scene, mode: PasteMode) {
+        let range = scene.model.range();
+        let placed: Vec<[i32; 3]> = scene
+            .clipboard
+            .placed(scene.selection_position, range)
+            .iter()
+            .map(|state| state.position)
+            .collect();
+        let filled: HashSet<[i32; 3]> = scene
+            .model
+            .active_voxels_at(&placed)
+            .into_iter()
+            .filter(|state| state.active)
+            .map(|state| state.position)
+            .collect();
+        let states = scene
+            .clipboard
+            .paste(scene.selection_position, range, mode, |position| {
+                filled.contains(&position)
+            });
+        if let Err(error) = Self::edit_voxels(scene, &states) {
+            log::info!("{error}");
+        }
+    }
+
+    /// Copy the selection for the global scene.
+    pub fn copy() {
+        let mut scene = Self::access();
+        Self::handle_copy(&mut scene);
+        scene.dirty = true;
+    }
+
+    /// Cut the selection for the global scene.
+    pub fn cut() {
+        let mut scene = Self::access();
+        Self::handle_cut(&mut scene);
+        scene.dirty = true;
+    }
+
+    /// Paste the clipboard for the global scene, over or merged with the voxels already there.
+    pub fn paste(merge: bool) {
+        let mut scene = Self::access();
+        let mode = if merge {
+            PasteMode::Merge
+        } else {
+            PasteMode::Over
+        };
+        Self::handle_paste(&mut scene, mode);
+        scene.dirty = true;
+    }
+
+    /// The clipboard of the global scene as JSON.
+    pub fn clipboard() -> String {
+        let scene = Self::access();
+        scene.clipboard.to_json()
+    }
+
+    /// Replace the clipboard of the global scene with JSON from `clipboard`.
+    pub fn set_clipboard(json: &str) -> Result<(), String> {
+        let clipboard = Clipboard::from_json(json)?;
+        let mut scene = Self::access();
+        scene.pasting = !clipboard.is_empty();
+        scene.clipboard = clipboard;
+        scene.dirty = true;
+        Ok(())
+    }
+
+    /// Forget the magic wand selection and stop showing where the clipboard would go.
+    pub fn handle_cancel(scene: &mut Scene) {
+        Self::handle_clear_selected(scene);
+        scene.pasting = false;
+    }
+
+    /// Undo the last voxel edit for the global scene.
+    pub fn undo() {
+        let mut scene = Self::access();
+        scene.dirty = true;
+
+        Self::handle_undo(&mut scene);
+    }
+
+    /// Redo the last undone voxel edit for the global scene.
+    pub fn redo() {
+        let mut scene = Self::access();
+        scene.dirty = true;
+
+        Self::handle_redo(&mut scene);
+    }
+
+    /// Restore the voxels from before the last edit.
+    pub fn handle_undo(scene: &mut Scene) {
+        if let Some(previous) = scene.history.undo() {
+            let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
+            let replaced = scene
+                .model
+                .set_voxels(previous.layer, &previous.states, camera_eye);
+            scene.history.push_redo(Edit {
+                layer: previous.layer,
+                states: replaced,
+            });
+        } else {
+            log::info!("Nothing to undo");
+        }
+    }
+
+    /// Apply the last undone edit again.
+    pub fn handle_redo(scene: &mut Scene) {
+        if let Some(next) = scene.history.redo() {
+            let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
+            let replaced = scene.model.set_voxels(next.layer, &next.states, camera_eye);
+            scene.history.push_undo(Edit {
+                layer: next.layer,
+                states: replaced,
+            });
+        } else {
+            log::info!("Nothing to redo");
+        }
     }
 
     /// Save the scene to the browser.
@@ -330,6 +981,402 @@ impl Scene {
         model.save().await;
     }
 
+    /// Encode the global scene as a MagicaVoxel .vox file.
+    pub fn export_vox() -> Result<Vec<u8>, VoxError> {
+        let scene = Self::access();
+        scene.model.export_vox()
+    }
+
+    /// Mesh the visible layers of the global scene as an OBJ file using an MTL file
+    /// called `{name}.mtl`. Returns (obj, mtl).
+    pub fn export_obj(name: &str) -> (String, String) {
+        let root = Self::access().model.visible_tree();
+        mesh_export::obj(&mesher::face_quads(&root), &format!("{name}.mtl"))
+    }
+
+    /// Mesh the visible layers of the global scene as a watertight binary PLY file.
+    pub fn export_ply() -> Vec<u8> {
+        let root = Self::access().model.visible_tree();
+        mesh_export::ply(&mesher::face_quads(&root))
+    }
+
+    /// Mesh the visible layers of the global scene as a binary glTF file, with
+    /// a node for every layer or one node for the merged layers.
+    pub fn export_glb(per_layer: bool) -> Vec<u8> {
+        let scene = Self::access();
+        if per_layer {
+            let nodes: Vec<(&str, Vec<mesher::Quad>)> = scene
+                .model
+                .visible_layer_trees()
+                .into_iter()
+                .map(|(name, root)| (name, mesher::greedy_quads(root)))
+                .collect();
+            gltf::glb(&nodes)
+        } else {
+            let root = scene.model.visible_tree();
+            gltf::glb(&[(scene.model.name.as_str(), mesher::greedy_quads(&root))])
+        }
+    }
+
+    /// Prepare the visible layers of the global scene for printing and report what was found.
+    pub fn print_report(json: &str) -> Result<String, String> {
+        let settings =
+            settings::from_json::<PrintSettings>(json).map_err(|error| error.to_string())?;
+        let (_, report) = Self::prepare_print(&settings)?;
+        serde_json::to_string(&report).map_err(|error| error.to_string())
+    }
+
+    /// Prepare the visible layers of the global scene for printing as a binary STL file.
+    pub fn export_print_stl(json: &str) -> Result<Vec<u8>, String> {
+        let settings =
+            settings::from_json::<PrintSettings>(json).map_err(|error| error.to_string())?;
+        let (filled, _) = Self::prepare_print(&settings)?;
+        Ok(print::stl(&filled, settings.mm_per_voxel))
+    }
+
+    /// Make the visible voxels of the global scene printable.
+    fn prepare_print(settings: &PrintSettings) -> Result<(HashSet<[i32; 3]>, PrintReport), String> {
+        let positions: Vec<[i32; 3]> = Self::access()
+            .model
+            .visible_voxels()
+            .map_err(|error| error.to_string())?
+            .iter()
+            .map(|state| state.position)
+            .collect();
+        print::prepare(&positions, settings).map_err(|error| error.to_string())
+    }
+
+    /// Render the visible layers of the global scene from its camera as a PNG on the CPU.
+    pub fn render_png(width: u32, height: u32, transparent: bool) -> Result<Vec<u8>, String> {
+        let scene = Self::access();
+        let background = if transparent {
+            [0.0; 4]
+        } else {
+            render::BACKGROUND
+        };
+        let image = render::render(
+            &scene.camera,
+            &scene.light,
+            width,
+            height,
+            background,
+            &scene
+                .model
+                .visible_voxels()
+                .map_err(|error| error.to_string())?,
+        )
+        .map_err(|error| error.to_string())?;
+        image.encode_png().map_err(|error| error.to_string())
+    }
+
+    /// Draw the visible layers of the global scene into a sprite sheet.
+    /// Returns the sheet as a PNG and the atlas as JSON.
+    pub fn export_sprite_sheet(json: &str) -> Result<(Vec<u8>, String), String> {
+        let settings =
+            settings::from_json::<SpriteSheetSettings>(json).map_err(|error| error.to_string())?;
+        let scene = Self::access();
+        let voxels = scene
+            .model
+            .visible_voxels()
+            .map_err(|error| error.to_string())?;
+        let (image, atlas) = sprite_sheet::sprite_sheet(&voxels, &scene.light, &settings)
+            .map_err(|error| error.to_string())?;
+        let png = image.encode_png().map_err(|error| error.to_string())?;
+        let atlas = serde_json::to_string(&atlas).map_err(|error| error.to_string())?;
+        Ok((png, atlas))
+    }
+
+    /// Add the voxels from a MagicaVoxel .vox file to the active layer of the global scene.
+    pub fn import_vox(bytes: &[u8]) -> Result<(), String> {
+        let mut scene = Self::access();
+        scene.dirty = true;
+
+        let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
+        let layer = scene.model.active_layer_id();
+        let previous = scene.model.import_vox(bytes, camera_eye)?;
+        scene.history.record(layer, previous);
+        Ok(())
+    }
+
+    /// Fill the active layer of the global scene with terrain built from JSON settings.
+    /// Returns how many voxels were added.
+    pub fn generate_terrain(json: &str) -> Result<usize, String> {
+        let settings =
+            settings::from_json::<TerrainSettings>(json).map_err(|error| error.to_string())?;
+        let mut scene = Self::access();
+        let voxels = terrain::generate(&settings, scene.model.range());
+        Self::edit_voxels(&mut scene, &voxels).map_err(|error| error.to_string())?;
+        scene.dirty = true;
+        Ok(voxels.len())
+    }
+
+    /// Add terrain from a grayscale PNG heightmap to the active layer of the global scene.
+    /// The heightmap is centred on the selection and filled with the current colour.
+    /// Returns how many voxels were added.
+    pub fn import_heightmap(bytes: &[u8], max_height: u32) -> Result<usize, String> {
+        let image = Image::decode_png(bytes).map_err(|error| error.to_string())?;
+        let mut scene = Self::access();
+        let color = scene.clamped_material_color();
+        let voxels = image_import::heightmap(
+            &image,
+            scene.selection_position,
+            max_height,
+            color,
+            scene.model.range(),
+        )
+        .map_err(|error| error.to_string())?;
+        Self::edit_voxels(&mut scene, &voxels).map_err(|error| error.to_string())?;
+        scene.dirty = true;
+        Ok(voxels.len())
+    }
+
+    /// Add a PNG sprite as a relief on a plane through the selection to the active layer
+    /// of the global scene. Returns how many voxels were added.
+    pub fn import_sprite(bytes: &[u8], plane: &str, thickness: u32) -> Result<usize, String> {
+        let plane = Plane::from_name(plane).map_err(|error| error.to_string())?;
+        let image = Image::decode_png(bytes).map_err(|error| error.to_string())?;
+        let mut scene = Self::access();
+        let voxels = image_import::sprite(
+            &image,
+            scene.selection_position,
+            plane,
+            thickness,
+            scene.model.range(),
+        )
+        .map_err(|error| error.to_string())?;
+        Self::edit_voxels(&mut scene, &voxels).map_err(|error| error.to_string())?;
+        scene.dirty = true;
+        Ok(voxels.len())
+    }
+
+    /// Voxelize an OBJ mesh into the active layer of the global scene. Faces without
+    /// colours use the material colour. Returns how many voxels were added.
+    pub fn import_obj(obj: &str, mtl: &str, solid: bool) -> Result<usize, String> {
+        let color = Self::access().clamped_material_color();
+        let triangles =
+            mesh_import::parse_obj(obj, mtl, color).map_err(|error| error.to_string())?;
+        Self::import_triangles(&triangles, solid)
+    }
+
+    /// Voxelize an STL mesh into the active layer of the global scene. Triangles without
+    /// colours use the material colour. Returns how many voxels were added.
+    pub fn import_stl(bytes: &[u8], solid: bool) -> Result<usize, String> {
+        let color = Self::access().clamped_material_color();
+        let triangles = mesh_import::parse_stl(bytes, color).map_err(|error| error.to_string())?;
+        Self::import_triangles(&triangles, solid)
+    }
+
+    /// Voxelize triangles scaled to fill the world into the active layer as one edit.
+    fn import_triangles(triangles: &[Triangle], solid: bool) -> Result<usize, String> {
+        let mut scene = Self::access();
+        let voxels = mesh_import::voxelize(triangles, scene.model.range(), solid)
+            .map_err(|error| error.to_string())?;
+        Self::edit_voxels(&mut scene, &voxels).map_err(|error| error.to_string())?;
+        scene.dirty = true;
+        Ok(voxels.len())
+    }
+
+    /// Save the global scene, its cameras and material settings as a portable file.
+    pub fn export_scene() -> Vec<u8> {
+        let scene = Self::access();
+        let settings = SceneSettings {
+            name: scene.model.name.clone(),
+            camera: CameraSettings::from_camera(&scene.camera),
+            light: CameraSettings::from_camera(&scene.light),
+            material_color: scene.material_color,
+            fluid: scene.fluid,
+            noise: scene.noise,
+            layers: vec![],
+            active_layer: scene.model.active_layer(),
+        };
+        scene_file::write(settings, &scene.model.prepare_layers())
+    }
+
+    /// Replace the global scene with one from a portable file.
+    /// Nothing is changed if the file is invalid.
+    pub fn import_scene(bytes: &[u8]) -> Result<(), SceneFileError> {
+        let (settings, layers) = scene_file::read(bytes)?;
+        let mut scene = Self::access();
+        scene.model.load_layers(&layers, settings.active_layer)?;
+
+        scene.set_name(settings.name);
+        settings.camera.apply(&mut scene.camera);
+        settings.light.apply(&mut scene.light);
+        let [red, green, blue, _alpha] = settings.material_color;
+        scene.material_color = settings.material_color;
+        scene.selection_cube.color = [red, green, blue, 0.5];
+        scene.fluid = settings.fluid;
+        scene.noise = settings.noise;
+        scene.history.clear();
+        scene.selected.clear();
+        scene.fit_grid();
+        scene.dirty = true;
+        Ok(())
+    }
+
+    /// Change the layers of the global scene and redraw.
+    fn change_layers<T>(change: impl FnOnce(&mut Model) -> T) -> T {
+        let mut scene = Self::access();
+        scene.dirty = true;
+        change(&mut scene.model)
+    }
+
+    /// Add an empty layer above the active one and make it active.
+    pub fn add_layer(name: String) -> usize {
+        Self::change_layers(|model| model.add_layer(name))
+    }
+
+    /// Remove a layer and its voxels.
+    pub fn remove_layer(index: usize) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.remove_layer(index))
+    }
+
+    /// Change the name of a layer.
+    pub fn rename_layer(index: usize, name: String) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.rename_layer(index, name))
+    }
+
+    /// Move a layer up or down the list.
+    pub fn move_layer(from: usize, to: usize) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.move_layer(from, to))
+    }
+
+    /// Choose the layer that edits go to.
+    pub fn select_layer(index: usize) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.select_layer(index))
+    }
+
+    /// Show or hide a layer.
+    pub fn set_layer_visible(index: usize, visible: bool) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.set_layer_visible(index, visible))
+    }
+
+    /// Lock or unlock a layer.
+    pub fn set_layer_locked(index: usize, locked: bool) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.set_layer_locked(index, locked))
+    }
+
+    /// Fade a layer.
+    pub fn set_layer_opacity(index: usize, opacity: f32) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.set_layer_opacity(index, opacity))
+    }
+
+    /// Start playing the layer animations.
+    pub fn play_animation() {
+        let mut scene = Self::access();
+        scene.dirty = true;
+        scene.timeline.play();
+    }
+
+    /// Stop the layer animations where they are.
+    pub fn pause_animation() {
+        let mut scene = Self::access();
+        scene.timeline.pause();
+    }
+
+    /// Jump to a time in seconds.
+    pub fn scrub_animation(time: f32) {
+        let mut scene = Self::access();
+        scene.dirty = true;
+        scene.timeline.scrub(time);
+    }
+
+    /// The current animation time in seconds.
+    pub fn animation_time() -> f32 {
+        let scene = Self::access();
+        scene.timeline.time
+    }
+
+    /// The length of the longest layer animation in seconds.
+    pub fn animation_duration() -> f32 {
+        let scene = Self::access();
+        scene.model.animation_duration()
+    }
+
+    /// Replace the animation of a layer with a preset.
+    pub fn apply_animation_preset(index: usize, name: &str) -> Result<(), String> {
+        let preset = Preset::from_name(name).map_err(|error| error.to_string())?;
+        Self::change_layers(|model| {
+            let animation = preset.animation(model.range());
+            model.animate_layer(index, |current| *current = animation)
+        })
+        .map_err(|error| error.to_string())
+    }
+
+    /// Change the keyframes of a layer.
+    pub fn animate_layer(
+        index: usize,
+        change: impl FnOnce(&mut LayerAnimation),
+    ) -> Result<(), LayerError> {
+        Self::change_layers(|model| model.animate_layer(index, change))
+    }
+
+    /// Keyframes can only be added at real times from the start onwards.
+    fn check_keyframe_time(time: f32) -> Result<(), String> {
+        if !time.is_finite() || time < 0.0 {
+            return Err(format!(
+                "Keyframe time must be 0 or more seconds, got {time}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add a translation keyframe to a layer.
+    pub fn add_translation_keyframe(
+        index: usize,
+        time: f32,
+        translation: [f32; 3],
+    ) -> Result<(), String> {
+        Self::check_keyframe_time(time)?;
+        Self::animate_layer(index, |animation| {
+            animation.add_translation(time, translation, Easing::Linear)
+        })
+        .map_err(|error| error.to_string())
+    }
+
+    /// Add a rotation keyframe to a layer, in radians.
+    pub fn add_rotation_keyframe(
+        index: usize,
+        time: f32,
+        rotation: [f32; 3],
+    ) -> Result<(), String> {
+        Self::check_keyframe_time(time)?;
+        Self::animate_layer(index, |animation| {
+            animation.add_rotation(time, rotation, Easing::Linear)
+        })
+        .map_err(|error| error.to_string())
+    }
+
+    /// Add a visibility keyframe to a layer.
+    pub fn add_visibility_keyframe(index: usize, time: f32, visible: bool) -> Result<(), String> {
+        Self::check_keyframe_time(time)?;
+        Self::animate_layer(index, |animation| animation.add_visibility(time, visible))
+            .map_err(|error| error.to_string())
+    }
+
+    /// Describe the layers of the global scene as JSON, from bottom to top.
+    pub fn layers_json() -> String {
+        let scene = Self::access();
+        let active = scene.model.active_layer();
+        let layers: Vec<serde_json::Value> = scene
+            .model
+            .layers()
+            .iter()
+            .enumerate()
+            .map(|(index, layer)| {
+                serde_json::json!({
+                    "name": layer.name,
+                    "visible": layer.visible,
+                    "locked": layer.locked,
+                    "opacity": layer.opacity(),
+                    "animated": !layer.animation.is_empty(),
+                    "active": index == active,
+                })
+            })
+            .collect();
+        serde_json::Value::Array(layers).to_string()
+    }
+
     /// Move the selection shape left.
     pub fn handle_move_selection_left(scene: &mut Scene) {
         scene.selection_cube.translate([-1.0, 0.0, 0.0]);
@@ -410,8 +1457,34 @@ impl Scene {
     /// Handle a key press.
     pub fn handle_key_down(command: &Command, scene: &mut Scene) {
         let key = command.data1;
+        let ctrl = command.data2 & MODIFIER_CTRL != 0;
+        let shift = command.data2 & MODIFIER_SHIFT != 0;
 
         match key {
+            // CTRL+SHIFT+Z
+            90 if ctrl && shift => Self::handle_redo(scene),
+            // CTRL+Z
+            90 if ctrl => Self::handle_undo(scene),
+            // SHIFT+R
+            82 if shift => {
+                Self::handle_transform_key(scene, Transform::Rotate { axis: 1, turns: 1 })
+            }
+            // SHIFT+M
+            77 if shift => Self::handle_transform_key(scene, Transform::Flip(0)),
+            // CTRL+C
+            67 if ctrl => Self::handle_copy(scene),
+            // CTRL+X
+            88 if ctrl => Self::handle_cut(scene),
+            // CTRL+SHIFT+V
+            86 if ctrl && shift => Self::handle_paste(scene, PasteMode::Merge),
+            // CTRL+V
+            86 if ctrl => Self::handle_paste(scene, PasteMode::Over),
+            // SHIFT+X
+            88 if shift => Self::handle_toggle_mirror(scene, 0),
+            // SHIFT+Y
+            89 if shift => Self::handle_toggle_mirror(scene, 1),
+            // SHIFT+Z
+            90 if shift => Self::handle_toggle_mirror(scene, 2),
             // E
             69 => Self::handle_move_up(scene),
             // C
@@ -425,7 +1498,27 @@ impl Scene {
             // S or X or DOWN
             83 | 88 | 40 => Self::handle_move_backward(scene),
             // SPACEBAR
-            32 => Self::handle_toggle_voxel(scene),
+            32 => Self::handle_use_selection(scene),
+            // 1
+            49 => Self::handle_set_paint_mode(scene, PaintMode::Toggle),
+            // 2
+            50 => Self::handle_set_paint_mode(scene, PaintMode::Add),
+            // 3
+            51 => Self::handle_set_paint_mode(scene, PaintMode::Erase),
+            // 4
+            52 => Self::handle_set_paint_mode(scene, PaintMode::Paint),
+            // 5
+            53 => Self::handle_set_paint_mode(scene, PaintMode::Eyedropper),
+            // V
+            86 => Self::handle_toggle_pick_target(scene),
+            // G
+            71 => Self::handle_magic_wand(scene),
+            // R
+            82 => Self::handle_recolor_selected(scene),
+            // DELETE or BACKSPACE
+            46 | 8 => Self::handle_delete_selected(scene),
+            // ESCAPE
+            27 => Self::handle_cancel(scene),
             // 4 or J
             100 | 74 => Self::handle_move_selection_left(scene),
             // 6 or L
@@ -453,10 +1546,10 @@ impl Scene {
         while let Some(command) = command_opt {
             match command.command_type {
                 CommandType::MouseDown => {
-                    Self::handle_mouse_down(&mut scene);
+                    Self::handle_mouse_down(&command, &mut scene);
                 }
                 CommandType::MouseUp => {
-                    Self::handle_mouse_up(&mut scene);
+                    Self::handle_mouse_up(&command, &mut scene);
                 }
                 CommandType::MouseMoved => {
                     Self::handle_mouse_moved(&command, &mut scene);
@@ -552,7 +1645,7 @@ impl Scene {
             let mut scene = Self::access();
             scene.drawing = false;
             scene.loading = true;
-            scene.model.voxels.name.clone()
+            scene.model.name.clone()
         };
 
         let storage = Storage::new();
@@ -560,10 +1653,12 @@ impl Scene {
         if serial.is_some() {
             let mut scene = Self::access();
             let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
-            scene
-                .model
-                .voxels
-                .load_from_serial(serial.unwrap(), camera_eye);
+            if let Err(error) = scene.model.load_from_serial(serial.unwrap(), camera_eye) {
+                log::error!("Could not load the scene: {error}");
+            }
+            scene.history.clear();
+            scene.selected.clear();
+            scene.fit_grid();
             scene.drawing = true;
             scene.loading = false;
         }
@@ -574,7 +1669,9 @@ impl Scene {
         let model = {
             let mut scene = Self::access();
 
-            scene.model.voxels.clear();
+            scene.model.clear();
+            scene.history.clear();
+            scene.selected.clear();
             scene.model.clone()
         };
         model.delete_scene().await;
@@ -632,10 +1729,12 @@ impl Scene {
         if serial.is_some() {
             let mut scene = Self::access();
             let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
-            scene
-                .model
-                .voxels
-                .load_from_serial(serial.unwrap(), camera_eye);
+            if let Err(error) = scene.model.load_from_serial(serial.unwrap(), camera_eye) {
+                log::error!("Could not load the scene: {error}");
+            }
+            scene.history.clear();
+            scene.selected.clear();
+            scene.fit_grid();
             scene.drawing = true;
             scene.loading = false;
         } else {
@@ -670,10 +1769,17 @@ impl Scene {
         let key_down_closure = EventListener::new(&canvas, "keydown", move |event| {
             let key_event = event.clone().dyn_into::<web_sys::KeyboardEvent>().unwrap();
             log::info!("Key down");
+            let mut modifiers = 0;
+            if key_event.ctrl_key() || key_event.meta_key() {
+                modifiers |= MODIFIER_CTRL;
+            }
+            if key_event.shift_key() {
+                modifiers |= MODIFIER_SHIFT;
+            }
             Scene::queue_command(Command {
                 command_type: CommandType::KeyDown,
                 data1: key_event.key_code(),
-                data2: key_event.key_code(),
+                data2: modifiers,
             });
         });
 
@@ -713,21 +1819,23 @@ impl Scene {
 
         wheel_closure.forget();
 
-        let mouse_down_closure = EventListener::new(&canvas, "mousedown", move |_event| {
+        let mouse_down_closure = EventListener::new(&canvas, "mousedown", move |event| {
+            let mouse_event = event.clone().dyn_into::<web_sys::MouseEvent>().unwrap();
             Scene::queue_command(Command {
                 command_type: CommandType::MouseDown,
-                data1: 1,
-                data2: 1,
+                data1: mouse_event.offset_x() as u32,
+                data2: mouse_event.offset_y() as u32,
             });
         });
 
         mouse_down_closure.forget();
 
-        let mouse_up_closure = EventListener::new(&canvas, "mouseup", move |_event| {
+        let mouse_up_closure = EventListener::new(&canvas, "mouseup", move |event| {
+            let mouse_event = event.clone().dyn_into::<web_sys::MouseEvent>().unwrap();
             Scene::queue_command(Command {
                 command_type: CommandType::MouseUp,
-                data1: 1,
-                data2: 1,
+                data1: mouse_event.offset_x() as u32,
+                data2: mouse_event.offset_y() as u32,
             });
         });
 
@@ -739,20 +1847,29 @@ impl Scene {
         (from[0] - to[0]).pow(2) + (from[1] - to[1]).pow(2) + (from[2] - to[2]).pow(2)
     }
 
+    /// Limit a loop along one axis to the positions the selection can reach.
+    fn axis_range(center: i32, radius: i32, range: i32) -> Range<i32> {
+        max(center - radius + 1, -range)..min(center + radius, range)
+    }
+
     /// Generate voxels based on selection.
+    /// The range is the size of the world, the actual positions go from -range to +range.
     pub fn selection_voxels(
         center: &[i32; 3],
         radius: i32,
         shape: SelectionShape,
+        range: i32,
     ) -> Vec<[i32; 3]> {
         let mut voxels = Vec::new();
-        let range: i32 = Ocnode::range() * 2;
         let radius_squared: i32 = radius.pow(2);
+        let x_range = Self::axis_range(center[0], radius, range);
+        let y_range = Self::axis_range(center[1], radius, range);
+        let z_range = Self::axis_range(center[2], radius, range);
 
         if shape == SelectionShape::Sphere {
-            for x in -range..range {
-                for y in -range..range {
-                    for z in -range..range {
+            for x in x_range {
+                for y in y_range.clone() {
+                    for z in z_range.clone() {
                         let voxel_position = [x, y, z];
                         let distance: i32 =
                             Self::calculate_distance_squared(center, &voxel_position);
@@ -764,9 +1881,9 @@ impl Scene {
                 }
             }
         } else if shape == SelectionShape::Cube {
-            for x in -range..range {
-                for y in -range..range {
-                    for z in -range..range {
+            for x in x_range {
+                for y in y_range.clone() {
+                    for z in z_range.clone() {
                         let voxel_position = [x, y, z];
                         if (center[0] - voxel_position[0]).abs() < radius
                             && (center[1] - voxel_position[1]).abs() < radius
@@ -779,8 +1896,8 @@ impl Scene {
             }
         } else if shape == SelectionShape::SquareXZ {
             // SquareXZ
-            for x in -range..range {
-                for z in -range..range {
+            for x in x_range {
+                for z in z_range.clone() {
                     let voxel_position = [x, center[1], z];
                     if (center[0] - voxel_position[0]).abs() < radius
                         && (center[2] - voxel_position[2]).abs() < radius
@@ -791,8 +1908,8 @@ impl Scene {
             }
         } else if shape == SelectionShape::SquareXY {
             // SquareXY
-            for x in -range..range {
-                for y in -range..range {
+            for x in x_range {
+                for y in y_range.clone() {
                     let voxel_position = [x, y, center[2]];
                     if (center[0] - voxel_position[0]).abs() < radius
                         && (center[1] - voxel_position[1]).abs() < radius
@@ -803,8 +1920,8 @@ impl Scene {
             }
         } else if shape == SelectionShape::SquareYZ {
             // SquareYZ
-            for y in -range..range {
-                for z in -range..range {
+            for y in y_range {
+                for z in z_range.clone() {
                     let voxel_position = [center[0], y, z];
                     if (center[1] - voxel_position[1]).abs() < radius
                         && (center[2] - voxel_position[2]).abs() < radius
@@ -815,8 +1932,8 @@ impl Scene {
             }
         } else if shape == SelectionShape::CircleXZ {
             // CircleXZ
-            for x in -range..range {
-                for z in -range..range {
+            for x in x_range {
+                for z in z_range.clone() {
                     let voxel_position = [x, center[1], z];
                     if (((center[0] - voxel_position[0]).abs() as f64).powi(2)
                         + ((center[2] - voxel_position[2]).abs() as f64).powi(2))
@@ -829,8 +1946,8 @@ impl Scene {
             }
         } else if shape == SelectionShape::CircleXY {
             // CircleXY
-            for x in -range..range {
-                for y in -range..range {
+            for x in x_range {
+                for y in y_range.clone() {
                     let voxel_position = [x, y, center[2]];
                     if (((center[0] - voxel_position[0]).abs() as f64).powi(2)
                         + ((center[1] - voxel_position[1]).abs() as f64).powi(2))
@@ -843,8 +1960,8 @@ impl Scene {
             }
         } else if shape == SelectionShape::CircleYZ {
             // CircleYZ
-            for y in -range..range {
-                for z in -range..range {
+            for y in y_range {
+                for z in z_range.clone() {
                     let voxel_position = [center[0], y, z];
                     if (((center[1] - voxel_position[1]).abs() as f64).powi(2)
                         + ((center[2] - voxel_position[2]).abs() as f64).powi(2))
@@ -865,6 +1982,7 @@ impl Scene {
         let mut scene = Self::access();
 
         scene.elapsed += 0.01;
+        let time = scene.timeline.tick(Instant::now());
         graphics.prepare_shadow_frame();
 
         let light = if !graphics.swap_cameras {
@@ -877,10 +1995,16 @@ impl Scene {
         } else {
             scene.light
         };
+        scene.viewport = Viewport::new(
+            &graphics.build_camera_projection(),
+            &Isometry3::look_at_rh(&camera.eye, &camera.target, &Vector3::y()).to_homogeneous(),
+            graphics.canvas_width,
+            graphics.canvas_height,
+        );
 
         if !graphics.swap_shaders {
-            for voxel in scene.model.drawables().iter() {
-                graphics.draw_shadow(voxel, WebGlRenderingContext::TRIANGLES, light);
+            for mesh in scene.model.meshes(time) {
+                graphics.draw_shadow(mesh, WebGlRenderingContext::TRIANGLES, light);
             }
         }
 
@@ -888,13 +2012,7 @@ impl Scene {
 
         graphics.prepare_camera_frame();
 
-        let selections = Self::selection_voxels(
-            &scene.selection_position,
-            scene.selection_radius as i32,
-            scene.selection_shape,
-        );
-
-        for selection in selections {
+        for selection in Self::edit_positions(&scene) {
             scene.selection_cube.translation = [
                 selection[0] as f32 + 0.1,
                 selection[1] as f32 + 0.1,
@@ -908,6 +2026,30 @@ impl Scene {
                 scene.elapsed,
             );
         }
+        let elapsed = scene.elapsed;
+        let color = scene.selection_cube.color;
+        let selected: Vec<VoxelState> = scene
+            .selected
+            .iter()
+            .map(|position| VoxelState {
+                position: *position,
+                active: true,
+                color,
+                fluid: 0,
+                noise: 0,
+            })
+            .collect();
+        let levels = scene.model.levels();
+        for mesh in scene.selected_overlay.meshes(selected, levels) {
+            graphics.draw(
+                mesh,
+                WebGlRenderingContext::TRIANGLES,
+                camera,
+                light,
+                elapsed,
+            );
+        }
+
         if scene.grid_visible {
             graphics.draw(
                 &scene.grid_xz,
@@ -918,23 +2060,52 @@ impl Scene {
             );
         }
 
-        let drawables = scene.model.drawables();
-
-        /*let camera_eye = [scene.camera.eye.x, scene.camera.eye.y, scene.camera.eye.z];
-        drawables.sort_by(|a, b| {
-            let a_dist = a.depth(camera_eye);
-            let b_dist = b.depth(camera_eye);
+        for mesh in scene.model.meshes(time) {
+            graphics.draw(
+                mesh,
+                WebGlRenderingContext::TRIANGLES,
+                camera,
+                light,
+                elapsed,
+            );
+        }
 
-            b_dist.partial_cmp(&a_dist).unwrap()
-        });*/
+        // Show where the clipboard would be pasted as see through voxels.
+        if scene.pasting {
+            let ghost: Vec<VoxelState> = scene
+                .clipboard
+                .placed(scene.selection_position, scene.model.range())
+                .into_iter()
+                .map(|state| {
+                    let [red, green, blue, _alpha] = state.color;
+                    VoxelState {
+                        color: [red, green, blue, 0.3],
+                        fluid: 0,
+                        noise: 0,
+                        ..state
+                    }
+                })
+                .collect();
+            let levels = scene.model.levels();
+            for mesh in scene.ghost_overlay.meshes(ghost, levels) {
+                graphics.draw(
+                    mesh,
+                    WebGlRenderingContext::TRIANGLES,
+                    camera,
+                    light,
+                    elapsed,
+                );
+            }
+        }
 
-        for voxel in drawables.iter() {
+        // The guides are see through so they go on top of everything else.
+        for guide in scene.symmetry.guides(scene.model.range()) {
             graphics.draw(
-                voxel,
+                &guide,
                 WebGlRenderingContext::TRIANGLES,
                 camera,
                 light,
-                scene.elapsed,
+                elapsed,
             );
         }
 
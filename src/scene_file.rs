@@ -0,0 +1,235 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::animation::LayerAnimation;
+use crate::camera::Camera;
+use crate::scene_format::SceneFormatError;
+use crate::stored_octree::StoredLayer;
+
+/// Every scene file starts with these bytes.
+const MAGIC: &[u8; 4] = b"CRFL";
+/// The newest version of the file layout.
+const FILE_VERSION: u16 = 1;
+
+/// Things that can go wrong reading a scene file.
+#[derive(Debug)]
+pub enum SceneFileError {
+    /// The file does not start with the magic bytes.
+    NotASceneFile,
+    /// The file was written by a newer version of the app.
+    UnsupportedVersion(u16),
+    /// The settings in the file could not be read.
+    Settings(String),
+    /// A setting has a value we can't use.
+    InvalidValue(String),
+    /// The voxels could not be decoded.
+    Voxels(SceneFormatError),
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneFileError::NotASceneFile => write!(f, "The file is not a scene file"),
+            SceneFileError::UnsupportedVersion(version) => write!(
+                f,
+                "The file was saved with version {version} but only {FILE_VERSION} is supported"
+            ),
+            SceneFileError::Settings(reason) => {
+                write!(f, "The scene settings could not be read: {reason}")
+            }
+            SceneFileError::InvalidValue(reason) => {
+                write!(f, "The scene file is invalid: {reason}")
+            }
+            SceneFileError::Voxels(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl From<SceneFormatError> for SceneFileError {
+    fn from(error: SceneFormatError) -> Self {
+        SceneFileError::Voxels(error)
+    }
+}
+
+/// Where a camera is and what it looks at.
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct CameraSettings {
+    pub eye: [f32; 3],
+    pub target: [f32; 3],
+}
+
+impl CameraSettings {
+    /// Copy the points from a camera.
+    pub fn from_camera(camera: &Camera) -> CameraSettings {
+        CameraSettings {
+            eye: [camera.eye.x, camera.eye.y, camera.eye.z],
+            target: [camera.target.x, camera.target.y, camera.target.z],
+        }
+    }
+
+    /// Move a camera to these points.
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.eye.coords.copy_from_slice(&self.eye);
+        camera.target.coords.copy_from_slice(&self.target);
+    }
+}
+
+/// A layer without its voxels.
+#[derive(Serialize, Deserialize)]
+pub struct LayerSettings {
+    pub name: String,
+    pub visible: bool,
+    pub locked: bool,
+    pub opacity: f32,
+    #[serde(default)]
+    pub animation: LayerAnimation,
+    /// How many bytes of encoded voxels belong to this layer.
+    pub size: usize,
+}
+
+/// Everything except the voxels, stored as JSON so it is easy to extend.
+#[derive(Serialize, Deserialize)]
+pub struct SceneSettings {
+    pub name: String,
+    pub camera: CameraSettings,
+    pub light: CameraSettings,
+    /// The colour new voxels are filled with.
+    pub material_color: [f32; 4],
+    /// Are new voxels fluid?
+    pub fluid: i32,
+    /// Are new voxels noisy?
+    pub noise: i32,
+    /// The layers from bottom to top. Files with no layers have one layer of voxels.
+    #[serde(default)]
+    pub layers: Vec<LayerSettings>,
+    /// Which layer is being edited.
+    #[serde(default)]
+    pub active_layer: usize,
+}
+
+impl SceneSettings {
+    /// Check that every value can be used by the scene.
+    fn validate(&self) -> Result<(), SceneFileError> {
+        let points = [
+            self.camera.eye,
+            self.camera.target,
+            self.light.eye,
+            self.light.target,
+        ];
+        if points.iter().flatten().any(|value| !value.is_finite()) {
+            return Err(SceneFileError::InvalidValue(
+                "camera positions must be numbers".to_string(),
+            ));
+        }
+        if self.camera.eye == self.camera.target || self.light.eye == self.light.target {
+            return Err(SceneFileError::InvalidValue(
+                "cameras must not look at their own position".to_string(),
+            ));
+        }
+        if self
+            .material_color
+            .iter()
+            .any(|channel| !(0.0..=1.0).contains(channel))
+        {
+            return Err(SceneFileError::InvalidValue(
+                "material colour channels must be between 0 and 1".to_string(),
+            ));
+        }
+        if self
+            .layers
+            .iter()
+            .any(|layer| !(0.0..=1.0).contains(&layer.opacity))
+        {
+            return Err(SceneFileError::InvalidValue(
+                "layer opacity must be between 0 and 1".to_string(),
+            ));
+        }
+        if !(0..=1).contains(&self.fluid) || !(0..=1).contains(&self.noise) {
+            return Err(SceneFileError::InvalidValue(
+                "fluid and noise must be 0 or 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Build a scene file from the settings and the layers.
+/// The voxels of each layer follow the settings, encoded by `scene_format`.
+pub fn write(mut settings: SceneSettings, layers: &[StoredLayer]) -> Vec<u8> {
+    settings.layers = layers
+        .iter()
+        .map(|layer| LayerSettings {
+            name: layer.name.clone(),
+            visible: layer.visible,
+            locked: layer.locked,
+            opacity: layer.opacity,
+            animation: layer.animation.clone(),
+            size: layer.data.len(),
+        })
+        .collect();
+    let header = serde_json::to_vec(&settings).expect("Settings are always serializable");
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FILE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&header);
+    for layer in layers {
+        bytes.extend_from_slice(&layer.data);
+    }
+    bytes
+}
+
+/// Split a scene file into the checked settings and the layers with their encoded voxels.
+pub fn read(bytes: &[u8]) -> Result<(SceneSettings, Vec<StoredLayer>), SceneFileError> {
+    if bytes.len() < 10 || &bytes[0..4] != MAGIC {
+        return Err(SceneFileError::NotASceneFile);
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FILE_VERSION {
+        return Err(SceneFileError::UnsupportedVersion(version));
+    }
+    let header_length = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]) as usize;
+    let header_end = 10usize
+        .checked_add(header_length)
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| SceneFileError::Settings("the file is truncated".to_string()))?;
+    let header = &bytes[10..header_end];
+    let mut voxels = &bytes[header_end..];
+
+    let settings: SceneSettings = serde_json::from_slice(header)
+        .map_err(|error| SceneFileError::Settings(error.to_string()))?;
+    settings.validate()?;
+
+    if settings.layers.is_empty() {
+        let layer = StoredLayer {
+            name: "Layer 1".to_string(),
+            visible: true,
+            locked: false,
+            opacity: 1.0,
+            animation: LayerAnimation::default(),
+            data: voxels.to_vec(),
+        };
+        return Ok((settings, vec![layer]));
+    }
+    let mut layers: Vec<StoredLayer> = vec![];
+    for layer in &settings.layers {
+        if layer.size > voxels.len() {
+            return Err(SceneFileError::InvalidValue(format!(
+                "the voxels for layer {} are truncated",
+                layer.name
+            )));
+        }
+        let (data, rest) = voxels.split_at(layer.size);
+        voxels = rest;
+        layers.push(StoredLayer {
+            name: layer.name.clone(),
+            visible: layer.visible,
+            locked: layer.locked,
+            opacity: layer.opacity,
+            animation: layer.animation.clone(),
+            data: data.to_vec(),
+        });
+    }
+    Ok((settings, layers))
+}
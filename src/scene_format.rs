@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use morton_encoding::morton_decode;
+
+use crate::ocnode::{Ocnode, MAX_LEVELS, MIN_LEVELS};
+use crate::reader::{ReadError, Reader};
+use crate::voxel_state::{MaterialKey, VoxelState};
+
+/// Every encoded scene starts with these bytes.
+const MAGIC: &[u8; 4] = b"CRSC";
+/// The newest version of the encoding. Older versions can still be decoded.
+pub const FORMAT_VERSION: u16 = 1;
+/// Palette index used for empty space.
+const EMPTY: u64 = 0;
+
+/// Things that can go wrong decoding a scene.
+#[derive(Debug, PartialEq)]
+pub enum SceneFormatError {
+    /// The data does not start with the magic bytes.
+    NotAScene,
+    /// The data was written by a newer version of the app.
+    UnsupportedVersion(u16),
+    /// The world size is not one we can handle.
+    BadLevels(u32),
+    /// The data is truncated or has values that make no sense.
+    Malformed(String),
+}
+
+impl fmt::Display for SceneFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneFormatError::NotAScene => write!(f, "The data is not a saved scene"),
+            SceneFormatError::UnsupportedVersion(version) => write!(
+                f,
+                "The scene was saved with format version {version} but only {FORMAT_VERSION} is supported"
+            ),
+            SceneFormatError::BadLevels(levels) => write!(
+                f,
+                "The scene has {levels} levels but only {MIN_LEVELS} to {MAX_LEVELS} are supported"
+            ),
+            SceneFormatError::Malformed(reason) => write!(f, "The scene is malformed: {reason}"),
+        }
+    }
+}
+
+impl From<ReadError> for SceneFormatError {
+    fn from(error: ReadError) -> Self {
+        SceneFormatError::Malformed(error.0)
+    }
+}
+
+/// How many voxels are in a cube at this level of a tree.
+fn block_volume(levels: u32, level: u32) -> u64 {
+    1u64 << (3 * (levels - level))
+}
+
+/// The position for a Morton code. Positions are shifted so the corner of the
+/// world is 0 and x is the lowest bit, which matches the order of child cubes.
+fn morton_position(index: u64, range: i32) -> [i32; 3] {
+    let [z, y, x]: [u16; 3] = morton_decode(index);
+    [x, y, z].map(|value| value as i32 - range)
+}
+
+/// Add an unsigned LEB128 variable length integer.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Add a signed variable length integer, zig zag encoded so small negatives stay small.
+fn write_signed(bytes: &mut Vec<u8>, value: i32) {
+    write_varint(bytes, ((value << 1) ^ (value >> 31)) as u32 as u64);
+}
+
+/// Encode a tree as a list of runs in Morton order with a palette of materials.
+/// The leaves must cover the whole tree in Morton order, as returned by `Ocnode::leaves`.
+pub fn encode(levels: u32, leaves: &[(VoxelState, u32)]) -> Vec<u8> {
+    let mut palette: Vec<VoxelState> = vec![];
+    let mut indexes: BTreeMap<MaterialKey, u64> = BTreeMap::new();
+    let mut runs: Vec<(u64, u64)> = vec![];
+
+    for (state, level) in leaves {
+        let index = if state.active {
+            *indexes.entry(state.material_key()).or_insert_with(|| {
+                palette.push(*state);
+                palette.len() as u64
+            })
+        } else {
+            EMPTY
+        };
+        let length = block_volume(levels, *level);
+        match runs.last_mut() {
+            Some((run_length, run_index)) if *run_index == index => *run_length += length,
+            _ => runs.push((length, index)),
+        }
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.push(levels as u8);
+
+    write_varint(&mut bytes, palette.len() as u64);
+    for state in &palette {
+        for channel in state.color {
+            bytes.extend_from_slice(&channel.to_le_bytes());
+        }
+        write_signed(&mut bytes, state.fluid);
+        write_signed(&mut bytes, state.noise);
+    }
+
+    write_varint(&mut bytes, runs.len() as u64);
+    for (length, index) in runs {
+        write_varint(&mut bytes, length);
+        write_varint(&mut bytes, index);
+    }
+    bytes
+}
+
+/// Decode a scene. Returns the number of levels in the tree and the filled
+/// cubes, each with the level of the tree it belongs to.
+pub fn decode(bytes: &[u8]) -> Result<(u32, Vec<(VoxelState, u32)>), SceneFormatError> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(4).ok() != Some(MAGIC.as_slice()) {
+        return Err(SceneFormatError::NotAScene);
+    }
+    let version_bytes = reader.take(2)?;
+    let version = u16::from_le_bytes([version_bytes[0], version_bytes[1]]);
+
+    // Each version that changes the layout gets its own reader here.
+    match version {
+        1 => decode_version_1(&mut reader),
+        _ => Err(SceneFormatError::UnsupportedVersion(version)),
+    }
+}
+
+/// Read the palette and runs written by version 1.
+fn decode_version_1(
+    reader: &mut Reader,
+) -> Result<(u32, Vec<(VoxelState, u32)>), SceneFormatError> {
+    let levels = reader.take(1)?[0] as u32;
+    if !(MIN_LEVELS..=MAX_LEVELS).contains(&levels) {
+        return Err(SceneFormatError::BadLevels(levels));
+    }
+    let range = Ocnode::range(levels);
+    let total = block_volume(levels, 1);
+
+    let palette_size = reader.varint()?;
+    let mut palette: Vec<VoxelState> = vec![];
+    for _ in 0..palette_size {
+        let color = [reader.f32()?, reader.f32()?, reader.f32()?, reader.f32()?];
+        palette.push(VoxelState {
+            position: [0; 3],
+            active: true,
+            color,
+            fluid: reader.signed()?,
+            noise: reader.signed()?,
+        });
+    }
+
+    let run_count = reader.varint()?;
+    let mut blocks: Vec<(VoxelState, u32)> = vec![];
+    let mut start: u64 = 0;
+    for _ in 0..run_count {
+        let length = reader.varint()?;
+        let index = reader.varint()?;
+        let end = start
+            .checked_add(length)
+            .filter(|end| *end <= total)
+            .ok_or_else(|| SceneFormatError::Malformed("runs are larger than the world".into()))?;
+        if index != EMPTY {
+            let material = *palette.get(index as usize - 1).ok_or_else(|| {
+                SceneFormatError::Malformed(format!("colour {index} is not in the palette"))
+            })?;
+            // Split the run into the biggest cubes that line up with the tree.
+            while start < end {
+                let mut level = levels;
+                while level > 1
+                    && start.is_multiple_of(block_volume(levels, level - 1))
+                    && start + block_volume(levels, level - 1) <= end
+                {
+                    level -= 1;
+                }
+                let mut state = material;
+                state.position = morton_position(start, range);
+                blocks.push((state, level));
+                start += block_volume(levels, level);
+            }
+        }
+        start = end;
+    }
+    if start != total {
+        return Err(SceneFormatError::Malformed(format!(
+            "runs cover {start} voxels but the world has {total}"
+        )));
+    }
+    Ok((levels, blocks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(position: [i32; 3], color: [f32; 4], fluid: i32, noise: i32) -> VoxelState {
+        VoxelState {
+            position,
+            active: true,
+            color,
+            fluid,
+            noise,
+        }
+    }
+
+    /// The start of a version 1 scene with the smallest world.
+    fn header() -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.push(MIN_LEVELS as u8);
+        bytes
+    }
+
+    #[test]
+    fn decoding_gives_back_the_same_leaves() {
+        let mut tree = Ocnode::new(MIN_LEVELS);
+        let mut states = vec![
+            voxel([-8, -8, -8], [1.0, 0.0, 0.0, 1.0], 0, 0),
+            voxel([7, 7, 7], [0.0, 1.0, 0.0, 0.5], 1, 0),
+            voxel([3, -2, 5], [0.0, 0.0, 1.0, 1.0], 0, 1),
+        ];
+        // A whole aligned cube so the tree has a leaf bigger than one voxel.
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    states.push(voxel([x, y, z], [0.5, 0.5, 0.5, 1.0], 0, 0));
+                }
+            }
+        }
+        tree.set_voxels(&states);
+        let leaves = tree.leaves();
+        assert!(leaves
+            .iter()
+            .any(|(state, level)| state.active && *level < MIN_LEVELS));
+
+        let (levels, blocks) = decode(&encode(MIN_LEVELS, &leaves)).unwrap();
+        assert_eq!(levels, MIN_LEVELS);
+        let mut decoded = Ocnode::new(levels);
+        for (state, level) in &blocks {
+            decoded.fill(state, *level);
+        }
+        assert_eq!(decoded.leaves(), leaves);
+    }
+
+    #[test]
+    fn version_1_bytes_do_not_change() {
+        let mut tree = Ocnode::new(MIN_LEVELS);
+        // Morton codes 1 and 2: x is the lowest bit, then y.
+        tree.set_voxels(&[
+            voxel([-7, -8, -8], [1.0, 0.0, 0.0, 1.0], 1, 0),
+            voxel([-8, -7, -8], [1.0, 0.0, 0.0, 1.0], 1, 0),
+        ]);
+        let expected: Vec<u8> = vec![
+            b'C', b'R', b'S', b'C', // magic
+            1, 0, // version
+            5, // levels
+            1, // palette size
+            0x00, 0x00, 0x80, 0x3f, // red
+            0x00, 0x00, 0x00, 0x00, // green
+            0x00, 0x00, 0x00, 0x00, // blue
+            0x00, 0x00, 0x80, 0x3f, // alpha
+            2,    // fluid, zig zag encoded
+            0,    // noise
+            3,    // run count
+            1, 0, // 1 empty voxel
+            2, 1, // 2 voxels of colour 1
+            0xfd, 0x1f, 0, // 4093 empty voxels
+        ];
+        assert_eq!(encode(MIN_LEVELS, &tree.leaves()), expected);
+
+        let (_, blocks) = decode(&expected).unwrap();
+        let positions: Vec<[i32; 3]> = blocks.iter().map(|(state, _)| state.position).collect();
+        assert_eq!(positions, vec![[-7, -8, -8], [-8, -7, -8]]);
+    }
+
+    #[test]
+    fn bad_data_is_rejected() {
+        let mut tree = Ocnode::new(MIN_LEVELS);
+        tree.set_voxels(&[voxel([0, 0, 0], [1.0; 4], 0, 0)]);
+        let bytes = encode(MIN_LEVELS, &tree.leaves());
+        assert!(matches!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(SceneFormatError::Malformed(_))
+        ));
+
+        assert_eq!(decode(b"NOPE"), Err(SceneFormatError::NotAScene));
+        let mut newer = header();
+        newer[4] = 2;
+        assert_eq!(decode(&newer), Err(SceneFormatError::UnsupportedVersion(2)));
+        let mut small = header();
+        small[6] = 2;
+        assert_eq!(decode(&small), Err(SceneFormatError::BadLevels(2)));
+
+        // One run filling the 4096 voxel world with a colour that isn't in the palette.
+        let mut palette = header();
+        palette.extend_from_slice(&[0, 1, 0x80, 0x20, 1]);
+        assert!(matches!(
+            decode(&palette),
+            Err(SceneFormatError::Malformed(reason)) if reason.contains("palette")
+        ));
+
+        // One run longer than the world.
+        let mut long = header();
+        long.extend_from_slice(&[0, 1, 0x81, 0x20, 0]);
+        assert!(matches!(
+            decode(&long),
+            Err(SceneFormatError::Malformed(reason)) if reason.contains("larger")
+        ));
+
+        // Runs that stop before the end of the world.
+        let mut short = header();
+        short.extend_from_slice(&[0, 1, 0xff, 0x1f, 0]);
+        assert!(matches!(
+            decode(&short),
+            Err(SceneFormatError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,38 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+
+/// Things wrong with settings given as JSON.
+#[derive(Debug, PartialEq)]
+pub enum SettingsError {
+    /// The settings could not be read.
+    Read(String),
+    /// A setting has a value we can't use.
+    InvalidValue(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Read(reason) => write!(f, "The settings could not be read: {reason}"),
+            SettingsError::InvalidValue(reason) => {
+                write!(f, "The settings are invalid: {reason}")
+            }
+        }
+    }
+}
+
+/// Settings that are given as JSON. Every field should have a default so only the
+/// interesting ones need to be given.
+pub trait Settings: DeserializeOwned {
+    /// Check that every value can be used.
+    fn validate(&self) -> Result<(), SettingsError>;
+}
+
+/// Read settings from JSON and check them. Missing fields keep their defaults.
+pub fn from_json<T: Settings>(json: &str) -> Result<T, SettingsError> {
+    let settings: T =
+        serde_json::from_str(json).map_err(|error| SettingsError::Read(error.to_string()))?;
+    settings.validate()?;
+    Ok(settings)
+}
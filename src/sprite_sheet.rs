@@ -0,0 +1,386 @@
+use std::fmt;
+
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::Camera;
+use crate::image_import::Image;
+use crate::picking::Ray;
+use crate::render::{Tracer, MAX_SIZE};
+use crate::settings::{Settings, SettingsError};
+use crate::voxel_state::VoxelState;
+
+/// The most empty pixels around a frame.
+const MAX_PADDING: u32 = 64;
+
+/// Things that stop a sprite sheet being made.
+#[derive(Debug, PartialEq)]
+pub enum SpriteSheetError {
+    /// A setting has a value we can't use.
+    Settings(SettingsError),
+    /// There is nothing to draw.
+    Empty,
+}
+
+impl fmt::Display for SpriteSheetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpriteSheetError::Settings(error) => write!(f, "{error}"),
+            SpriteSheetError::Empty => write!(f, "There are no visible voxels to draw"),
+        }
+    }
+}
+
+/// How steeply the model is looked down on. Both are drawn without perspective.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    /// Every axis is drawn the same length, looking down at about 35 degrees.
+    Isometric,
+    /// Looking down at 30 degrees, so the floor is drawn twice as wide as it is deep
+    /// like most pixel art.
+    Dimetric,
+}
+
+impl Projection {
+    /// How far above the floor the view is, in radians.
+    fn elevation(&self) -> f32 {
+        match self {
+            Projection::Isometric => (1.0 / 2.0f32.sqrt()).atan(),
+            Projection::Dimetric => std::f32::consts::PI / 6.0,
+        }
+    }
+}
+
+/// What goes in a sprite sheet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SpriteSheetSettings {
+    /// How many frames, looking from evenly spaced directions around the model.
+    pub directions: u32,
+    pub projection: Projection,
+    /// How many pixels wide a voxel is.
+    pub scale: u32,
+    /// How many frames go in each row of the sheet. 0 puts every frame in one row.
+    pub columns: u32,
+    /// Empty pixels around every frame.
+    pub padding: u32,
+    /// The file name the atlas gives for the sheet.
+    pub image: String,
+}
+
+impl Default for SpriteSheetSettings {
+    fn default() -> SpriteSheetSettings {
+        SpriteSheetSettings {
+            directions: 8,
+            projection: Projection::Isometric,
+            scale: 2,
+            columns: 0,
+            padding: 1,
+            image: "sprites.png".to_string(),
+        }
+    }
+}
+
+impl Settings for SpriteSheetSettings {
+    fn validate(&self) -> Result<(), SettingsError> {
+        if !(1..=64).contains(&self.directions) {
+            return Err(SettingsError::InvalidValue(
+                "there must be 1 to 64 directions".to_string(),
+            ));
+        }
+        if !(1..=32).contains(&self.scale) {
+            return Err(SettingsError::InvalidValue(
+                "the scale must be 1 to 32 pixels per voxel".to_string(),
+            ));
+        }
+        if self.padding > MAX_PADDING {
+            return Err(SettingsError::InvalidValue(format!(
+                "the padding can be at most {MAX_PADDING} pixels"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Where a frame is in the sheet.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Rectangle {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+/// One frame of the sheet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub filename: String,
+    /// How far the view is turned around the model from the first frame, in degrees.
+    pub angle: f32,
+    pub frame: Rectangle,
+}
+
+/// The size of the sheet.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Size {
+    pub w: u32,
+    pub h: u32,
+}
+
+/// Details about the whole sheet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Meta {
+    pub image: String,
+    pub size: Size,
+    pub scale: u32,
+    pub projection: Projection,
+}
+
+/// Where every frame is in the sheet, laid out like the JSON array atlases most
+/// game engines can read.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Atlas {
+    pub frames: Vec<Frame>,
+    pub meta: Meta,
+}
+
+/// The way a frame looks at the model: towards the eye, right and up on screen.
+fn view(azimuth: f32, elevation: f32) -> [Vector3<f32>; 3] {
+    let eye = Vector3::new(
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+        elevation.cos() * azimuth.cos(),
+    );
+    let right = (-eye).cross(&Vector3::y()).normalize();
+    let up = right.cross(&-eye);
+    [eye, right, up]
+}
+
+/// Draw filled voxels from evenly spaced directions without perspective and pack the
+/// frames into one sheet. Every pixel is one ray through its middle so edges stay sharp.
+/// The first frame looks from the same side as the editor camera starts, and the lights
+/// turn with the view so every frame is lit the same way.
+pub fn sprite_sheet(
+    voxels: &[VoxelState],
+    light: &Camera,
+    settings: &SpriteSheetSettings,
+) -> Result<(Image, Atlas), SpriteSheetError> {
+    let filled: Vec<&VoxelState> = voxels.iter().filter(|voxel| voxel.active).collect();
+    if filled.is_empty() {
+        return Err(SpriteSheetError::Empty);
+    }
+    let mut min = [i32::MAX; 3];
+    let mut max = [i32::MIN; 3];
+    for voxel in &filled {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(voxel.position[axis]);
+            max[axis] = max[axis].max(voxel.position[axis] + 1);
+        }
+    }
+    let center = Vector3::from([0, 1, 2].map(|axis| (min[axis] + max[axis]) as f32 / 2.0));
+    let half = Vector3::from([0, 1, 2].map(|axis| (max[axis] - min[axis]) as f32 / 2.0));
+
+    let elevation = settings.projection.elevation();
+    let step = std::f32::consts::TAU / settings.directions as f32;
+    let first = std::f32::consts::FRAC_PI_4;
+    let views: Vec<[Vector3<f32>; 3]> = (0..settings.directions)
+        .map(|index| view(first + step * index as f32, elevation))
+        .collect();
+
+    // Every frame is the same size, big enough for the model from any direction.
+    let mut extent = [0.0f32; 2];
+    for [_, right, up] in &views {
+        for corner in 0..8 {
+            let offset = Vector3::new(
+                if corner & 1 == 0 { -half.x } else { half.x },
+                if corner & 2 == 0 { -half.y } else { half.y },
+                if corner & 4 == 0 { -half.z } else { half.z },
+            );
+            extent[0] = extent[0].max(offset.dot(right).abs());
+            extent[1] = extent[1].max(offset.dot(up).abs());
+        }
+    }
+    let scale = settings.scale as f32;
+    let [width, height] =
+        extent.map(|value| (value * 2.0 * scale).ceil() as u32 + 2 * settings.padding);
+    let columns = match settings.columns {
+        0 => settings.directions,
+        columns => columns.min(settings.directions),
+    };
+    let rows = settings.directions.div_ceil(columns);
+    let (sheet_width, sheet_height) = match (width.checked_mul(columns), height.checked_mul(rows)) {
+        (Some(sheet_width), Some(sheet_height))
+            if sheet_width <= MAX_SIZE && sheet_height <= MAX_SIZE =>
+        {
+            (sheet_width, sheet_height)
+        }
+        _ => {
+            return Err(SpriteSheetError::Settings(SettingsError::InvalidValue(
+                format!("the sheet would be more than {MAX_SIZE} pixels wide or high"),
+            )))
+        }
+    };
+
+    let mut tracer = Tracer::new(voxels, light);
+    let distance = half.norm() * 2.0 + 1.0;
+    let mut pixels = vec![[0u8; 4]; (sheet_width * sheet_height) as usize];
+    let mut frames = vec![];
+    for (index, [eye, right, up]) in views.iter().enumerate() {
+        if index > 0 {
+            tracer.turn_light(step);
+        }
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let (left, top) = (column * width, row * height);
+        for y in 0..height {
+            for x in 0..width {
+                let across = (x as f32 + 0.5 - width as f32 / 2.0) / scale;
+                let down = (y as f32 + 0.5 - height as f32 / 2.0) / scale;
+                let ray = Ray {
+                    origin: center + eye * distance + right * across - up * down,
+                    direction: -eye,
+                };
+                pixels[((top + y) * sheet_width + left + x) as usize] =
+                    tracer.trace(&ray, [0.0; 4]);
+            }
+        }
+        frames.push(Frame {
+            filename: format!("direction_{index}"),
+            angle: index as f32 * 360.0 / settings.directions as f32,
+            frame: Rectangle {
+                x: left,
+                y: top,
+                w: width,
+                h: height,
+            },
+        });
+    }
+
+    let image = Image {
+        width: sheet_width,
+        height: sheet_height,
+        pixels,
+    };
+    let atlas = Atlas {
+        frames,
+        meta: Meta {
+            image: settings.image.clone(),
+            size: Size {
+                w: sheet_width,
+                h: sheet_height,
+            },
+            scale: settings.scale,
+            projection: settings.projection,
+        },
+    };
+    Ok((image, atlas))
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+    use crate::settings;
+
+    fn light() -> Camera {
+        Camera {
+            eye: Point3::new(10.0, 20.0, 30.0),
+            target: Point3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    fn voxel(position: [i32; 3]) -> VoxelState {
+        VoxelState {
+            position,
+            active: true,
+            color: [0.4, 0.8, 0.2, 1.0],
+            fluid: 0,
+            noise: 0,
+        }
+    }
+
+    fn parse(json: &str) -> SpriteSheetSettings {
+        settings::from_json(json).unwrap()
+    }
+
+    #[test]
+    fn frames_fit_the_model() {
+        // An isometric unit cube is sqrt(2) wide and about 1.63 high.
+        let one = [voxel([0, 0, 0])];
+        let (_, atlas) = sprite_sheet(&one, &light(), &parse(r#"{"padding": 0}"#)).unwrap();
+        for frame in &atlas.frames {
+            assert_eq!((frame.frame.w, frame.frame.h), (3, 4));
+        }
+        let (_, atlas) = sprite_sheet(&one, &light(), &parse(r#"{"padding": 1}"#)).unwrap();
+        for frame in &atlas.frames {
+            assert_eq!((frame.frame.w, frame.frame.h), (5, 6));
+        }
+    }
+
+    #[test]
+    fn frames_are_laid_out_in_rows() {
+        let json = r#"{"directions": 4, "columns": 2, "image": "hero.png"}"#;
+        let (image, atlas) = sprite_sheet(&[voxel([0, 0, 0])], &light(), &parse(json)).unwrap();
+        let (w, h) = (atlas.frames[0].frame.w, atlas.frames[0].frame.h);
+        let placed: Vec<(u32, u32)> = atlas
+            .frames
+            .iter()
+            .map(|frame| (frame.frame.x, frame.frame.y))
+            .collect();
+        assert_eq!(placed, [(0, 0), (w, 0), (0, h), (w, h)]);
+        let angles: Vec<f32> = atlas.frames.iter().map(|frame| frame.angle).collect();
+        assert_eq!(angles, [0.0, 90.0, 180.0, 270.0]);
+        assert_eq!(atlas.frames[3].filename, "direction_3");
+        assert_eq!(atlas.meta.size, Size { w: 2 * w, h: 2 * h });
+        assert_eq!(atlas.meta.image, "hero.png");
+        assert_eq!((image.width, image.height), (2 * w, 2 * h));
+        assert_eq!(image.pixels.len(), (4 * w * h) as usize);
+    }
+
+    #[test]
+    fn columns_are_limited_to_the_directions() {
+        let rows = |json: &str| {
+            let (_, atlas) = sprite_sheet(&[voxel([0, 0, 0])], &light(), &parse(json)).unwrap();
+            atlas.meta.size.h / atlas.frames[0].frame.h
+        };
+        assert_eq!(rows(r#"{"directions": 8, "columns": 0}"#), 1);
+        assert_eq!(rows(r#"{"directions": 8, "columns": 100}"#), 1);
+        assert_eq!(rows(r#"{"directions": 8, "columns": 3}"#), 3);
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        for json in [
+            r#"{"directions": 0}"#,
+            r#"{"directions": 65}"#,
+            r#"{"scale": 0}"#,
+            r#"{"scale": 33}"#,
+            r#"{"padding": 65}"#,
+            r#"{"padding": 4294967295}"#,
+        ] {
+            assert!(
+                matches!(
+                    settings::from_json::<SpriteSheetSettings>(json),
+                    Err(SettingsError::InvalidValue(_))
+                ),
+                "{json} should be rejected"
+            );
+        }
+        assert_eq!(parse(r#"{"padding": 64}"#).padding, 64);
+    }
+
+    #[test]
+    fn sheets_bigger_than_the_limit_are_rejected() {
+        let far = [voxel([0, 0, 0]), voxel([200, 0, 0])];
+        let json = r#"{"scale": 32, "padding": 64}"#;
+        assert!(matches!(
+            sprite_sheet(&far, &light(), &parse(json)),
+            Err(SpriteSheetError::Settings(SettingsError::InvalidValue(_)))
+        ));
+        assert_eq!(
+            sprite_sheet(&[], &light(), &SpriteSheetSettings::default()).err(),
+            Some(SpriteSheetError::Empty)
+        );
+    }
+}
@@ -1,9 +1,53 @@
-use crate::ocnode::Ocnode;
+use crate::animation::LayerAnimation;
+use crate::ocnode::{Ocnode, LEVELS};
 use serde::{Deserialize, Serialize};
 
+/// Scenes saved before the size was stored are all the default size.
+fn default_levels() -> u32 {
+    LEVELS
+}
+
+/// Records saved before the binary format have no version.
+pub const LEGACY_VERSION: u32 = 0;
+/// Records that store a single layer in the binary format from `scene_format`.
+pub const BINARY_VERSION: u32 = 1;
+/// Records that store a list of layers, each in the binary format.
+pub const LAYERED_VERSION: u32 = 2;
+
+/// Used to serialize one layer of a scene.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredLayer {
+    pub name: String,
+    pub visible: bool,
+    pub locked: bool,
+    pub opacity: f32,
+    /// Keyframes that move the layer, missing from scenes saved before animations.
+    #[serde(default)]
+    pub animation: LayerAnimation,
+    /// The encoded voxels of the layer.
+    pub data: Vec<u8>,
+}
+
 /// Used to serialize a scene.
 #[derive(Serialize, Deserialize)]
 pub struct StoredOctree {
     pub name: String,
+    /// Which layout this record uses, so old records can still be loaded.
+    #[serde(default)]
+    pub version: u32,
+    /// How many levels the tree has, which decides the size of the world.
+    #[serde(default = "default_levels")]
+    pub levels: u32,
+    /// The encoded scene, only used by `BINARY_VERSION`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub data: Vec<u8>,
+    /// Every filled voxel as a separate record, only used by `LEGACY_VERSION`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub active_nodes: Vec<Ocnode>,
+    /// The layers from bottom to top, used from `LAYERED_VERSION`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<StoredLayer>,
+    /// Which layer is being edited.
+    #[serde(default)]
+    pub active_layer: usize,
 }
@@ -0,0 +1,105 @@
+use crate::mesh::Mesh;
+
+/// The colour of the guides that show where the mirror planes are.
+const GUIDE_COLOR: [f32; 4] = [0.3, 0.6, 1.0, 0.15];
+
+/// Mirror planes that copy every edit to the other side of the model.
+/// Each axis has its own plane which is at right angles to that axis.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Symmetry {
+    /// Is there a mirror plane across the x, y and z axis?
+    pub enabled: [bool; 3],
+    /// Where each plane crosses its axis, in world units.
+    /// Planes snap to the edges and middles of voxels.
+    pub planes: [f32; 3],
+}
+
+impl Symmetry {
+    /// No mirror planes, all placed at the world origin.
+    pub const fn new() -> Symmetry {
+        Symmetry {
+            enabled: [false; 3],
+            planes: [0.0; 3],
+        }
+    }
+
+    /// Find an axis index by name.
+    pub fn axis_from_name(name: &str) -> Result<usize, String> {
+        match name.to_lowercase().as_str() {
+            "x" => Ok(0),
+            "y" => Ok(1),
+            "z" => Ok(2),
+            _ => Err(format!("Unknown axis {name}, expected x, y or z")),
+        }
+    }
+
+    /// Turn the mirror plane for an axis on or off and move it.
+    pub fn set_plane(&mut self, axis: usize, enabled: bool, position: f32) -> Result<(), String> {
+        if !position.is_finite() {
+            return Err(format!("The mirror position {position} must be a number"));
+        }
+        self.enabled[axis] = enabled;
+        self.planes[axis] = (position * 2.0).round() / 2.0;
+        Ok(())
+    }
+
+    /// Mirror a voxel index across the plane for an axis.
+    fn reflect(&self, axis: usize, index: i32) -> i32 {
+        (self.planes[axis] * 2.0).round() as i32 - index - 1
+    }
+
+    /// Add the mirrored copies of a list of voxel positions in a world where
+    /// positions go from -range to +range. Copies outside the world are dropped.
+    pub fn mirror(&self, positions: Vec<[i32; 3]>, range: i32) -> Vec<[i32; 3]> {
+        let mut mirrored = positions;
+        for axis in (0..3).filter(|axis| self.enabled[*axis]) {
+            let copies: Vec<[i32; 3]> = mirrored
+                .iter()
+                .map(|position| {
+                    let mut copy = *position;
+                    copy[axis] = self.reflect(axis, position[axis]);
+                    copy
+                })
+                .filter(|copy| copy[axis] >= -range && copy[axis] < range)
+                .collect();
+            mirrored.extend(copies);
+        }
+        mirrored.sort();
+        mirrored.dedup();
+        mirrored
+    }
+
+    /// Build translucent guides across the world showing where each enabled plane is.
+    pub fn guides(&self, range: i32) -> Vec<Mesh> {
+        let extent = range as f32;
+        let mut guides = vec![];
+        for axis in (0..3).filter(|axis| self.enabled[*axis]) {
+            let position = self.planes[axis];
+            // The two axes the plane spreads along.
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let corner = |a: f32, b: f32| {
+                let mut point = [0.0; 3];
+                point[axis] = position;
+                point[u] = a;
+                point[v] = b;
+                point
+            };
+            let corners = [
+                corner(-extent, -extent),
+                corner(extent, -extent),
+                corner(extent, extent),
+                corner(-extent, extent),
+            ];
+            let mut normal = [0.0; 3];
+            normal[axis] = 1.0;
+            let mut mesh = Mesh::new(GUIDE_COLOR, 0, 0);
+            mesh.push_quad(&corners, normal);
+            // Add the back so the guide can be seen from both sides.
+            let [first, second, third, fourth] = corners;
+            normal[axis] = -1.0;
+            mesh.push_quad(&[first, fourth, third, second], normal);
+            guides.push(mesh);
+        }
+        guides
+    }
+}
@@ -0,0 +1,447 @@
+use serde::{Deserialize, Serialize};
+
+use crate::settings::{Settings, SettingsError};
+use crate::voxel_state::VoxelState;
+
+/// Fractal noise mostly stays between -0.6 and 0.6, this brings it close to -1 and 1.
+const NOISE_STRETCH: f32 = 1.6;
+
+/// The colour of the ground up to a height.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct ColorBand {
+    /// The highest voxel with this colour.
+    pub up_to: i32,
+    pub color: [f32; 4],
+}
+
+/// Everything that shapes generated terrain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TerrainSettings {
+    /// The same seed always gives the same terrain.
+    pub seed: u64,
+    /// The lowest the ground can be. The ground is solid down to here.
+    pub min_height: i32,
+    /// The highest the ground can be.
+    pub max_height: i32,
+    /// How wide hills are, in voxels.
+    pub scale: f32,
+    /// How many layers of detail are added.
+    pub octaves: u32,
+    /// How much each layer of detail is scaled down.
+    pub persistence: f32,
+    /// How much smaller each layer of detail is.
+    pub lacunarity: f32,
+    /// Carve caves out of the ground.
+    pub caves: bool,
+    /// How wide caves are, in voxels.
+    pub cave_scale: f32,
+    /// Noise above this value becomes a cave, from -1 to 1. Higher values give fewer caves.
+    pub cave_threshold: f32,
+    /// Fill hollows below this height with water.
+    pub water_level: Option<i32>,
+    pub water_color: [f32; 4],
+    /// Colours by height, lowest first. Voxels above the last band use its colour.
+    pub bands: Vec<ColorBand>,
+    /// Columns that rise or fall this many voxels to a neighbour use the cliff colour.
+    pub cliff_slope: i32,
+    pub cliff_color: [f32; 4],
+}
+
+impl Default for TerrainSettings {
+    fn default() -> TerrainSettings {
+        TerrainSettings {
+            seed: 1,
+            min_height: 0,
+            max_height: 16,
+            scale: 32.0,
+            octaves: 4,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            caves: false,
+            cave_scale: 12.0,
+            cave_threshold: 0.3,
+            water_level: Some(4),
+            water_color: [0.2, 0.4, 0.8, 0.6],
+            bands: vec![
+                ColorBand {
+                    up_to: 5,
+                    color: [0.85, 0.8, 0.55, 1.0],
+                },
+                ColorBand {
+                    up_to: 11,
+                    color: [0.3, 0.6, 0.2, 1.0],
+                },
+                ColorBand {
+                    up_to: 14,
+                    color: [0.45, 0.4, 0.35, 1.0],
+                },
+                ColorBand {
+                    up_to: 16,
+                    color: [0.95, 0.95, 0.95, 1.0],
+                },
+            ],
+            cliff_slope: 3,
+            cliff_color: [0.5, 0.5, 0.5, 1.0],
+        }
+    }
+}
+
+impl Settings for TerrainSettings {
+    fn validate(&self) -> Result<(), SettingsError> {
+        if self.min_height > self.max_height {
+            return Err(SettingsError::InvalidValue(
+                "the minimum height must not be above the maximum height".to_string(),
+            ));
+        }
+        let sizes = [self.scale, self.cave_scale, self.lacunarity];
+        if sizes.iter().any(|size| !size.is_finite() || *size <= 0.0) {
+            return Err(SettingsError::InvalidValue(
+                "scales and lacunarity must be more than 0".to_string(),
+            ));
+        }
+        if !self.persistence.is_finite() || !self.cave_threshold.is_finite() {
+            return Err(SettingsError::InvalidValue(
+                "persistence and cave threshold must be numbers".to_string(),
+            ));
+        }
+        if !(1..=12).contains(&self.octaves) {
+            return Err(SettingsError::InvalidValue(
+                "octaves must be between 1 and 12".to_string(),
+            ));
+        }
+        let colors = self
+            .bands
+            .iter()
+            .map(|band| band.color)
+            .chain([self.water_color, self.cliff_color]);
+        if colors
+            .flatten()
+            .any(|channel| !(0.0..=1.0).contains(&channel))
+        {
+            return Err(SettingsError::InvalidValue(
+                "colour channels must be between 0 and 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TerrainSettings {
+    /// The colour of a voxel at a height in a column that is this steep.
+    fn color_at(&self, height: i32, slope: i32) -> [f32; 4] {
+        if slope >= self.cliff_slope {
+            return self.cliff_color;
+        }
+        self.bands
+            .iter()
+            .find(|band| height <= band.up_to)
+            .or(self.bands.last())
+            .map(|band| band.color)
+            .unwrap_or(self.cliff_color)
+    }
+}
+
+/// Gradient noise from Ken Perlin's improved noise, with the permutation shuffled by a seed.
+pub struct Noise {
+    permutation: [u8; 512],
+}
+
+/// A small random number generator, splitmix64, so the noise only depends on the seed.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut value = *state;
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+/// Smooth the distance across a cell so the noise has no creases.
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// Blend between 2 values.
+fn lerp(amount: f32, from: f32, to: f32) -> f32 {
+    from + amount * (to - from)
+}
+
+/// The dot product of a distance with one of 12 gradients picked by a hash.
+fn gradient(hash: u8, x: f32, y: f32, z: f32) -> f32 {
+    let hash = hash & 15;
+    let u = if hash < 8 { x } else { y };
+    let v = if hash < 4 {
+        y
+    } else if hash == 12 || hash == 14 {
+        x
+    } else {
+        z
+    };
+    let u = if hash & 1 == 0 { u } else { -u };
+    let v = if hash & 2 == 0 { v } else { -v };
+    u + v
+}
+
+impl Noise {
+    /// Create noise that is always the same for a seed.
+    pub fn new(seed: u64) -> Noise {
+        let mut values: [u8; 256] = std::array::from_fn(|index| index as u8);
+        let mut state = seed;
+        for index in (1..values.len()).rev() {
+            let other = (next_random(&mut state) % (index as u64 + 1)) as usize;
+            values.swap(index, other);
+        }
+        Noise {
+            permutation: std::array::from_fn(|index| values[index % 256]),
+        }
+    }
+
+    /// Sample the noise at a point. The result is roughly between -1 and 1.
+    pub fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        let p = &self.permutation;
+        let cell = [x, y, z].map(|value| (value.floor() as i32 & 255) as usize);
+        let [x, y, z] = [x, y, z].map(|value| value - value.floor());
+        let [u, v, w] = [x, y, z].map(fade);
+
+        let a = p[cell[0]] as usize + cell[1];
+        let aa = p[a] as usize + cell[2];
+        let ab = p[a + 1] as usize + cell[2];
+        let b = p[cell[0] + 1] as usize + cell[1];
+        let ba = p[b] as usize + cell[2];
+        let bb = p[b + 1] as usize + cell[2];
+
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, gradient(p[aa], x, y, z), gradient(p[ba], x - 1.0, y, z)),
+                lerp(
+                    u,
+                    gradient(p[ab], x, y - 1.0, z),
+                    gradient(p[bb], x - 1.0, y - 1.0, z),
+                ),
+            ),
+            lerp(
+                v,
+                lerp(
+                    u,
+                    gradient(p[aa + 1], x, y, z - 1.0),
+                    gradient(p[ba + 1], x - 1.0, y, z - 1.0),
+                ),
+                lerp(
+                    u,
+                    gradient(p[ab + 1], x, y - 1.0, z - 1.0),
+                    gradient(p[bb + 1], x - 1.0, y - 1.0, z - 1.0),
+                ),
+            ),
+        )
+    }
+
+    /// Fractal Brownian motion: layers of noise, each smaller and fainter than the last.
+    /// The result is roughly between -1 and 1.
+    pub fn fbm(&self, point: [f32; 3], octaves: u32, persistence: f32, lacunarity: f32) -> f32 {
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        let mut largest = 0.0;
+        for _ in 0..octaves {
+            let [x, y, z] = point.map(|value| value * frequency);
+            total += self.sample(x, y, z) * amplitude;
+            largest += amplitude;
+            amplitude *= persistence;
+            frequency *= lacunarity;
+        }
+        if largest == 0.0 {
+            0.0
+        } else {
+            total / largest
+        }
+    }
+}
+
+/// Build terrain in a world where positions go from -range to +range.
+/// The same settings always give the same voxels, sorted by position.
+pub fn generate(settings: &TerrainSettings, range: i32) -> Vec<VoxelState> {
+    let noise = Noise::new(settings.seed);
+    let caves = Noise::new(settings.seed.wrapping_add(1));
+    let top = range - 1;
+    let min_height = settings.min_height.clamp(-range, top);
+    let max_height = settings.max_height.clamp(-range, top);
+
+    // Work out the height of every column first so slopes can be measured.
+    let width = (range * 2) as usize;
+    let heights: Vec<i32> = (0..width * width)
+        .map(|index| {
+            let x = (index % width) as f32 - range as f32;
+            let z = (index / width) as f32 - range as f32;
+            // Keep away from whole numbers where gradient noise is always 0.
+            let point = [x / settings.scale + 0.5, 0.5, z / settings.scale + 0.5];
+            let height = noise.fbm(
+                point,
+                settings.octaves,
+                settings.persistence,
+                settings.lacunarity,
+            );
+            // Noise rarely gets near its limits so stretch it to use the whole height range.
+            let amount = (height * NOISE_STRETCH * 0.5 + 0.5).clamp(0.0, 1.0);
+            min_height + (amount * (max_height - min_height) as f32).round() as i32
+        })
+        .collect();
+    let height_at = |column: i32, row: i32| {
+        let column = column.clamp(0, width as i32 - 1) as usize;
+        let row = row.clamp(0, width as i32 - 1) as usize;
+        heights[row * width + column]
+    };
+
+    let mut voxels = vec![];
+    for row in 0..width as i32 {
+        for column in 0..width as i32 {
+            let height = height_at(column, row);
+            let slope = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                .iter()
+                .map(|(dx, dz)| (height_at(column + dx, row + dz) - height).abs())
+                .max()
+                .unwrap_or(0);
+            let (x, z) = (column - range, row - range);
+
+            for y in min_height..=height {
+                // The floor is never carved so caves can't fall out of the world.
+                if settings.caves && y > min_height {
+                    let point = [x, y, z].map(|value| value as f32 / settings.cave_scale + 0.5);
+                    if caves.fbm(point, 2, 0.5, 2.0) > settings.cave_threshold {
+                        continue;
+                    }
+                }
+                voxels.push(VoxelState {
+                    position: [x, y, z],
+                    active: true,
+                    color: settings.color_at(y, slope),
+                    fluid: 0,
+                    noise: 0,
+                });
+            }
+
+            if let Some(water_level) = settings.water_level {
+                for y in height + 1..=water_level.min(top) {
+                    voxels.push(VoxelState {
+                        position: [x, y, z],
+                        active: true,
+                        color: settings.water_color,
+                        fluid: 1,
+                        noise: 0,
+                    });
+                }
+            }
+        }
+    }
+    voxels.sort_by_key(|voxel| voxel.position);
+    voxels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings;
+
+    #[test]
+    fn the_same_seed_gives_the_same_terrain() {
+        let settings = TerrainSettings {
+            caves: true,
+            ..TerrainSettings::default()
+        };
+        assert_eq!(generate(&settings, 16), generate(&settings, 16));
+    }
+
+    #[test]
+    fn different_seeds_give_different_terrain() {
+        let first = TerrainSettings::default();
+        let second = TerrainSettings {
+            seed: 2,
+            ..TerrainSettings::default()
+        };
+        assert_ne!(generate(&first, 16), generate(&second, 16));
+    }
+
+    #[test]
+    fn water_is_fluid_and_not_above_the_water_level() {
+        let settings = TerrainSettings {
+            water_level: Some(8),
+            ..TerrainSettings::default()
+        };
+        let voxels = generate(&settings, 16);
+        let water: Vec<&VoxelState> = voxels
+            .iter()
+            .filter(|voxel| voxel.color == settings.water_color)
+            .collect();
+        assert!(!water.is_empty());
+        assert!(water
+            .iter()
+            .all(|voxel| voxel.fluid == 1 && voxel.position[1] <= 8));
+        assert!(voxels
+            .iter()
+            .filter(|voxel| voxel.fluid == 1)
+            .all(|voxel| voxel.color == settings.water_color));
+    }
+
+    #[test]
+    fn voxels_stay_inside_the_world_and_height_limits() {
+        let settings = TerrainSettings {
+            min_height: -3,
+            max_height: 10,
+            water_level: Some(2),
+            caves: true,
+            ..TerrainSettings::default()
+        };
+        let range = 16;
+        let voxels = generate(&settings, range);
+        assert!(!voxels.is_empty());
+        for voxel in &voxels {
+            assert!(voxel
+                .position
+                .iter()
+                .all(|value| (-range..range).contains(value)));
+            assert!((settings.min_height..=settings.max_height).contains(&voxel.position[1]));
+        }
+
+        // Heights past the edge of the world are kept inside it.
+        let tall = TerrainSettings {
+            min_height: -100,
+            max_height: 100,
+            ..TerrainSettings::default()
+        };
+        assert!(generate(&tall, 8)
+            .iter()
+            .all(|voxel| voxel.position.iter().all(|value| (-8..8).contains(value))));
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        let invalid = [
+            r#"{"min_height": 10, "max_height": 5}"#,
+            r#"{"scale": 0}"#,
+            r#"{"cave_scale": -1}"#,
+            r#"{"lacunarity": 0}"#,
+            r#"{"octaves": 0}"#,
+            r#"{"octaves": 13}"#,
+            r#"{"water_color": [0, 0, 2, 1]}"#,
+            r#"{"bands": [{"up_to": 3, "color": [-1, 0, 0, 1]}]}"#,
+        ];
+        for json in invalid {
+            assert!(
+                matches!(
+                    settings::from_json::<TerrainSettings>(json),
+                    Err(SettingsError::InvalidValue(_))
+                ),
+                "{json} should be rejected"
+            );
+        }
+        assert!(matches!(
+            settings::from_json::<TerrainSettings>("{"),
+            Err(SettingsError::Read(_))
+        ));
+        assert_eq!(
+            settings::from_json::<TerrainSettings>("{}"),
+            Ok(TerrainSettings::default())
+        );
+    }
+}
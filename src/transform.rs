@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::voxel_state::VoxelState;
+
+/// A box of voxels. The minimum corner is inside the box and the maximum is just outside.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+}
+
+impl Region {
+    /// The smallest box holding every position. Returns None for an empty list.
+    pub fn bounding(positions: &[[i32; 3]]) -> Option<Region> {
+        let first = positions.first()?;
+        let mut region = Region {
+            min: *first,
+            max: first.map(|value| value + 1),
+        };
+        for position in positions {
+            region.min = [0, 1, 2].map(|axis| region.min[axis].min(position[axis]));
+            region.max = [0, 1, 2].map(|axis| region.max[axis].max(position[axis] + 1));
+        }
+        Some(region)
+    }
+
+    /// Is a position inside the box?
+    pub fn contains(&self, position: [i32; 3]) -> bool {
+        (0..3).all(|axis| position[axis] >= self.min[axis] && position[axis] < self.max[axis])
+    }
+}
+
+/// A way to rearrange the voxels in a region.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transform {
+    /// Move by a number of voxels along each axis.
+    Translate([i32; 3]),
+    /// Turn a number of quarter turns around an axis, counter clockwise looking down the axis.
+    Rotate { axis: usize, turns: i32 },
+    /// Mirror across the middle of the region along an axis.
+    Flip(usize),
+    /// Make every voxel this many voxels wide, growing from the minimum corner.
+    Grow(u32),
+    /// Merge blocks of this many voxels wide into one, shrinking towards the minimum corner.
+    /// A block is filled if at least half of it was filled and takes its most common material.
+    Shrink(u32),
+}
+
+/// Things that can stop a transform.
+#[derive(Debug, PartialEq)]
+pub enum TransformError {
+    /// Axes are 0 for x, 1 for y and 2 for z.
+    InvalidAxis(usize),
+    /// Scale factors must be at least 1, and a grown region must still fit in the world.
+    InvalidFactor(u32),
+    /// Some voxels would end up outside the world. Holds how many and the first one.
+    OutOfRange(usize, [i32; 3]),
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransformError::InvalidAxis(axis) => {
+                write!(f, "There is no axis {axis}, expected 0, 1 or 2")
+            }
+            TransformError::InvalidFactor(factor) => {
+                write!(
+                    f,
+                    "The scale factor {factor} can't be used, it must be at least 1 and the scaled region must fit in the world"
+                )
+            }
+            TransformError::OutOfRange(count, first) => write!(
+                f,
+                "{count} voxels would be moved outside the world, starting at {first:?}"
+            ),
+        }
+    }
+}
+
+/// Turn a position a quarter turn counter clockwise around an axis through a pivot.
+/// Positions are measured in half voxels from the pivot so the middle of any
+/// region can be used.
+fn quarter_turn(position: [i32; 3], axis: usize, pivot: [i32; 3]) -> [i32; 3] {
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    // The middle of the voxel in half voxels from the pivot.
+    let offset = [0, 1, 2].map(|index| 2 * position[index] + 1 - pivot[index]);
+    let mut turned = offset;
+    turned[u] = -offset[v];
+    turned[v] = offset[u];
+    [0, 1, 2].map(|index| (pivot[index] + turned[index] - 1).div_euclid(2))
+}
+
+/// The material that most voxels in a list have. Ties go to the first one seen.
+fn most_common(voxels: &[VoxelState]) -> Option<VoxelState> {
+    let mut counts: Vec<(VoxelState, usize)> = vec![];
+    for voxel in voxels {
+        match counts
+            .iter_mut()
+            .find(|(seen, _)| seen.material_key() == voxel.material_key())
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.push((*voxel, 1)),
+        }
+    }
+    let best = counts.iter().map(|(_, count)| *count).max()?;
+    counts
+        .into_iter()
+        .find(|(_, count)| *count == best)
+        .map(|(voxel, _)| voxel)
+}
+
+/// Turn a scale factor into a whole number we can do sums with.
+fn checked_factor(factor: u32) -> Result<i32, TransformError> {
+    match i32::try_from(factor) {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err(TransformError::InvalidFactor(factor)),
+    }
+}
+
+/// Move the filled voxels of a region to where a transform puts them, in a world
+/// where positions go from -range to +range.
+fn transformed(
+    voxels: &[VoxelState],
+    region: &Region,
+    transform: Transform,
+    range: i32,
+) -> Result<Vec<VoxelState>, TransformError> {
+    let moved = |voxel: &VoxelState, position: [i32; 3]| VoxelState { position, ..*voxel };
+    match transform {
+        Transform::Translate(offset) => Ok(voxels
+            .iter()
+            .map(|voxel| {
+                moved(
+                    voxel,
+                    [0, 1, 2].map(|axis| voxel.position[axis] + offset[axis]),
+                )
+            })
+            .collect()),
+        Transform::Rotate { axis, turns } => {
+            if axis > 2 {
+                return Err(TransformError::InvalidAxis(axis));
+            }
+            let pivot = [0, 1, 2].map(|index| region.min[index] + region.max[index]);
+            Ok(voxels
+                .iter()
+                .map(|voxel| {
+                    let mut position = voxel.position;
+                    for _ in 0..turns.rem_euclid(4) {
+                        position = quarter_turn(position, axis, pivot);
+                    }
+                    moved(voxel, position)
+                })
+                .collect())
+        }
+        Transform::Flip(axis) => {
+            if axis > 2 {
+                return Err(TransformError::InvalidAxis(axis));
+            }
+            Ok(voxels
+                .iter()
+                .map(|voxel| {
+                    let mut position = voxel.position;
+                    position[axis] = region.min[axis] + region.max[axis] - 1 - position[axis];
+                    moved(voxel, position)
+                })
+                .collect())
+        }
+        Transform::Grow(factor) => {
+            let scale = checked_factor(factor)?;
+            // Check the grown region fits before making any voxels, a big factor
+            // would make far more than the world can hold.
+            for axis in 0..3 {
+                let end = (region.max[axis] - region.min[axis])
+                    .checked_mul(scale)
+                    .and_then(|size| region.min[axis].checked_add(size));
+                if end.is_none_or(|end| end > range) {
+                    return Err(TransformError::InvalidFactor(factor));
+                }
+            }
+            let factor = scale;
+            let mut grown = vec![];
+            for voxel in voxels {
+                let corner = [0, 1, 2].map(|axis| {
+                    region.min[axis] + (voxel.position[axis] - region.min[axis]) * factor
+                });
+                for x in 0..factor {
+                    for y in 0..factor {
+                        for z in 0..factor {
+                            grown.push(moved(voxel, [corner[0] + x, corner[1] + y, corner[2] + z]));
+                        }
+                    }
+                }
+            }
+            Ok(grown)
+        }
+        Transform::Shrink(factor) => {
+            let block_size = (factor as usize)
+                .checked_pow(3)
+                .ok_or(TransformError::InvalidFactor(factor))?;
+            let factor = checked_factor(factor)?;
+            let mut blocks: HashMap<[i32; 3], Vec<VoxelState>> = HashMap::new();
+            for voxel in voxels {
+                let block = [0, 1, 2].map(|axis| {
+                    region.min[axis] + (voxel.position[axis] - region.min[axis]).div_euclid(factor)
+                });
+                blocks.entry(block).or_default().push(*voxel);
+            }
+            Ok(blocks
+                .into_iter()
+                .filter(|(_, filled)| filled.len() * 2 >= block_size)
+                .filter_map(|(block, filled)| {
+                    most_common(&filled).map(|voxel| moved(&voxel, block))
+                })
+                .collect())
+        }
+    }
+}
+
+/// Work out every voxel state to write to apply a transform to the filled voxels of a
+/// region in a world where positions go from -range to +range. The region is emptied and
+/// the transformed voxels written on top, so applying the list is a single edit.
+/// Nothing is returned if any voxel would land outside the world.
+pub fn apply(
+    voxels: &[VoxelState],
+    region: &Region,
+    transform: Transform,
+    range: i32,
+) -> Result<Vec<VoxelState>, TransformError> {
+    let filled: Vec<VoxelState> = voxels
+        .iter()
+        .filter(|voxel| voxel.active && region.contains(voxel.position))
+        .copied()
+        .collect();
+    let targets = transformed(&filled, region, transform, range)?;
+
+    let outside: Vec<[i32; 3]> = targets
+        .iter()
+        .map(|voxel| voxel.position)
+        .filter(|position| {
+            position
+                .iter()
+                .any(|value| *value < -range || *value >= range)
+        })
+        .collect();
+    if let Some(first) = outside.first() {
+        return Err(TransformError::OutOfRange(outside.len(), *first));
+    }
+
+    let mut states: HashMap<[i32; 3], VoxelState> = HashMap::new();
+    for voxel in &filled {
+        states.insert(
+            voxel.position,
+            VoxelState {
+                active: false,
+                ..*voxel
+            },
+        );
+    }
+    for voxel in targets {
+        states.insert(voxel.position, voxel);
+    }
+    let mut states: Vec<VoxelState> = states.into_values().collect();
+    states.sort_by_key(|state| state.position);
+    Ok(states)
+}
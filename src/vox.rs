@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::mesh_export::to_byte;
+use crate::model::TooManyVoxels;
+use crate::reader::{ReadError, Reader};
+use crate::voxel_state::VoxelState;
+
+/// The file version written by MagicaVoxel.
+const VOX_VERSION: i32 = 150;
+/// MagicaVoxel models can not be bigger than this in any direction.
+const VOX_MAX_SIZE: i32 = 256;
+/// Colour index 0 means empty so only 255 colours are usable.
+const VOX_PALETTE_SIZE: usize = 255;
+
+/// Things that can go wrong reading or writing a .vox file.
+#[derive(Debug, PartialEq)]
+pub enum VoxError {
+    /// There are no voxels in the model.
+    Empty,
+    /// The model is bigger than a .vox model can hold.
+    TooLarge([i32; 3]),
+    /// The model is bigger than the scene can hold.
+    OutOfBounds { size: [i32; 3], limit: i32 },
+    /// The file is not a .vox file or is truncated.
+    Malformed(String),
+    /// The visible layers have too many voxels to list.
+    TooManyVoxels(TooManyVoxels),
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxError::Empty => write!(f, "There are no voxels in the model"),
+            VoxError::TooLarge(size) => write!(
+                f,
+                "The model is {}x{}x{} voxels but .vox files are limited to {VOX_MAX_SIZE}",
+                size[0], size[1], size[2]
+            ),
+            VoxError::OutOfBounds { size, limit } => write!(
+                f,
+                "The model is {}x{}x{} voxels but the scene is limited to {limit}",
+                size[0], size[1], size[2]
+            ),
+            VoxError::Malformed(reason) => write!(f, "The .vox file is malformed: {reason}"),
+            VoxError::TooManyVoxels(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl From<ReadError> for VoxError {
+    fn from(error: ReadError) -> Self {
+        VoxError::Malformed(error.0)
+    }
+}
+
+impl From<TooManyVoxels> for VoxError {
+    fn from(error: TooManyVoxels) -> Self {
+        VoxError::TooManyVoxels(error)
+    }
+}
+
+/// Convert a colour from floats to bytes.
+fn color_to_rgba(color: [f32; 4]) -> [u8; 4] {
+    [
+        to_byte(color[0]),
+        to_byte(color[1]),
+        to_byte(color[2]),
+        to_byte(color[3]),
+    ]
+}
+
+/// Build a palette of at most 255 colours and the palette index for every colour.
+/// When there are too many colours we drop the low bits of each channel until they fit
+/// and use the average colour of everything that was merged together.
+fn build_palette(colors: &[[u8; 4]]) -> (Vec<[u8; 4]>, Vec<u8>) {
+    for shift in 0..8 {
+        let mut buckets: HashMap<[u8; 4], usize> = HashMap::new();
+        let mut sums: Vec<[u32; 5]> = vec![];
+        let mut indexes: Vec<u8> = Vec::with_capacity(colors.len());
+
+        for color in colors {
+            let key = color.map(|channel| channel >> shift);
+            let next = buckets.len();
+            let bucket = *buckets.entry(key).or_insert(next);
+            if bucket == sums.len() {
+                sums.push([0; 5]);
+            }
+            if bucket >= VOX_PALETTE_SIZE {
+                break;
+            }
+            for channel in 0..4 {
+                sums[bucket][channel] += color[channel] as u32;
+            }
+            sums[bucket][4] += 1;
+            indexes.push(bucket as u8 + 1);
+        }
+
+        if buckets.len() <= VOX_PALETTE_SIZE {
+            let palette = sums
+                .iter()
+                .map(|sum| {
+                    let count = sum[4].max(1);
+                    [
+                        (sum[0] / count) as u8,
+                        (sum[1] / count) as u8,
+                        (sum[2] / count) as u8,
+                        (sum[3] / count) as u8,
+                    ]
+                })
+                .collect();
+            return (palette, indexes);
+        }
+    }
+    // With 1 bit per channel there are only 16 possible colours.
+    unreachable!("palette could not be reduced");
+}
+
+/// Write a chunk header.
+fn write_chunk_header(out: &mut Vec<u8>, id: &[u8; 4], content_size: usize, children_size: usize) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(content_size as i32).to_le_bytes());
+    out.extend_from_slice(&(children_size as i32).to_le_bytes());
+}
+
+/// Encode the active voxels as a MagicaVoxel .vox file.
+/// MagicaVoxel is Z up and creator is Y up, so creator (x, y, z) is stored as (x, -z, y).
+pub fn export(voxels: &[VoxelState]) -> Result<Vec<u8>, VoxError> {
+    let active: Vec<&VoxelState> = voxels.iter().filter(|voxel| voxel.active).collect();
+    if active.is_empty() {
+        return Err(VoxError::Empty);
+    }
+
+    let converted: Vec<[i32; 3]> = active
+        .iter()
+        .map(|voxel| [voxel.position[0], -voxel.position[2], voxel.position[1]])
+        .collect();
+
+    let mut min = [i32::MAX; 3];
+    let mut max = [i32::MIN; 3];
+    for position in &converted {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
+        }
+    }
+    let size = [
+        max[0] - min[0] + 1,
+        max[1] - min[1] + 1,
+        max[2] - min[2] + 1,
+    ];
+    if size.iter().any(|length| *length > VOX_MAX_SIZE) {
+        return Err(VoxError::TooLarge(size));
+    }
+
+    let colors: Vec<[u8; 4]> = active
+        .iter()
+        .map(|voxel| color_to_rgba(voxel.color))
+        .collect();
+    let (palette, indexes) = build_palette(&colors);
+
+    let size_content = 12;
+    let xyzi_content = 4 + 4 * converted.len();
+    let rgba_content = 4 * 256;
+    let children = (12 + size_content) + (12 + xyzi_content) + (12 + rgba_content);
+
+    let mut out: Vec<u8> = Vec::with_capacity(8 + 12 + children);
+    out.extend_from_slice(b"VOX ");
+    out.extend_from_slice(&VOX_VERSION.to_le_bytes());
+    write_chunk_header(&mut out, b"MAIN", 0, children);
+
+    write_chunk_header(&mut out, b"SIZE", size_content, 0);
+    for length in size {
+        out.extend_from_slice(&length.to_le_bytes());
+    }
+
+    write_chunk_header(&mut out, b"XYZI", xyzi_content, 0);
+    out.extend_from_slice(&(converted.len() as i32).to_le_bytes());
+    for (position, index) in converted.iter().zip(indexes.iter()) {
+        out.push((position[0] - min[0]) as u8);
+        out.push((position[1] - min[1]) as u8);
+        out.push((position[2] - min[2]) as u8);
+        out.push(*index);
+    }
+
+    // Palette entry N is colour index N + 1, the last entry is never used.
+    write_chunk_header(&mut out, b"RGBA", rgba_content, 0);
+    for entry in 0..256 {
+        out.extend_from_slice(&palette.get(entry).copied().unwrap_or([0, 0, 0, 255]));
+    }
+
+    Ok(out)
+}
+
+/// The palette MagicaVoxel uses when a file has no RGBA chunk.
+/// Index N is colour index N, index 0 is never used.
+fn default_palette() -> [[u8; 4]; 256] {
+    let mut palette = [[0u8; 4]; 256];
+    let steps: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    let ramp: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+    let mut index = 1;
+
+    for red in steps {
+        for green in steps {
+            for blue in steps {
+                if red == 0 && green == 0 && blue == 0 {
+                    continue;
+                }
+                palette[index] = [red, green, blue, 0xff];
+                index += 1;
+            }
+        }
+    }
+    // Blue, green and red ramps followed by a grey ramp.
+    for channel in [2, 1, 0] {
+        for value in ramp {
+            let mut color = [0, 0, 0, 0xff];
+            color[channel] = value;
+            palette[index] = color;
+            index += 1;
+        }
+    }
+    for value in ramp {
+        palette[index] = [value, value, value, 0xff];
+        index += 1;
+    }
+    palette
+}
+
+/// Decode a MagicaVoxel .vox file into voxel states centred on the origin.
+/// Only the first model in the file is used. The range is the largest
+/// coordinate the scene supports, positions must fit in -range..range.
+pub fn import(bytes: &[u8], range: i32) -> Result<Vec<VoxelState>, VoxError> {
+    let mut reader = Reader::new(bytes);
+
+    if &reader.id()? != b"VOX " {
+        return Err(VoxError::Malformed("missing VOX header".to_string()));
+    }
+    let _version = reader.i32()?;
+    if &reader.id()? != b"MAIN" {
+        return Err(VoxError::Malformed("missing MAIN chunk".to_string()));
+    }
+    let main_content = reader.length()?;
+    let main_children = reader.length()?;
+    reader.take(main_content)?;
+    let mut children = Reader::new(reader.take(main_children)?);
+
+    let mut size: Option<[i32; 3]> = None;
+    let mut models: Vec<Vec<[u8; 4]>> = vec![];
+    let mut palette = default_palette();
+
+    while children.has_more() {
+        let id = children.id()?;
+        let content_size = children.length()?;
+        let children_size = children.length()?;
+        let mut content = Reader::new(children.take(content_size)?);
+        children.take(children_size)?;
+
+        match &id {
+            b"SIZE" if size.is_none() => {
+                size = Some([content.i32()?, content.i32()?, content.i32()?]);
+            }
+            b"XYZI" => {
+                let count = content.length()?;
+                let data = content.take(count.checked_mul(4).ok_or_else(|| {
+                    VoxError::Malformed(format!("voxel count {count} is too large"))
+                })?)?;
+                models.push(
+                    data.chunks_exact(4)
+                        .map(|voxel| [voxel[0], voxel[1], voxel[2], voxel[3]])
+                        .collect(),
+                );
+            }
+            b"RGBA" => {
+                // Palette entry N is colour index N + 1.
+                for entry in palette.iter_mut().skip(1) {
+                    let color = content.take(4)?;
+                    *entry = [color[0], color[1], color[2], color[3]];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if models.len() > 1 {
+        log::info!("Only the first of {} models will be imported", models.len());
+    }
+    let voxels = models
+        .into_iter()
+        .next()
+        .ok_or_else(|| VoxError::Malformed("missing XYZI chunk".to_string()))?;
+    let size = size.ok_or_else(|| VoxError::Malformed("missing SIZE chunk".to_string()))?;
+    if voxels.is_empty() {
+        return Err(VoxError::Empty);
+    }
+
+    // Convert from Z up to Y up, the reverse of export.
+    let converted_size = [size[0], size[2], size[1]];
+    if converted_size.iter().any(|length| *length > range * 2) {
+        return Err(VoxError::OutOfBounds {
+            size: converted_size,
+            limit: range * 2,
+        });
+    }
+    let offset = [
+        converted_size[0] / 2,
+        converted_size[1] / 2,
+        converted_size[2] / 2,
+    ];
+
+    let mut states: Vec<VoxelState> = Vec::with_capacity(voxels.len());
+    for voxel in voxels {
+        let (x, y, z) = (voxel[0] as i32, voxel[1] as i32, voxel[2] as i32);
+        if x >= size[0] || y >= size[1] || z >= size[2] {
+            return Err(VoxError::Malformed(format!(
+                "voxel ({x}, {y}, {z}) is outside the model size"
+            )));
+        }
+        let color = palette[voxel[3] as usize];
+        states.push(VoxelState {
+            position: [
+                x - offset[0],
+                z - offset[1],
+                (converted_size[2] - 1 - y) - offset[2],
+            ],
+            active: true,
+            color: color.map(|channel| channel as f32 / 255.0),
+            fluid: 0,
+            noise: 0,
+        });
+    }
+
+    Ok(states)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(position: [i32; 3], color: [f32; 4]) -> VoxelState {
+        VoxelState {
+            position,
+            active: true,
+            color,
+            fluid: 0,
+            noise: 0,
+        }
+    }
+
+    /// Positions measured from the smallest corner, sorted so lists can be compared.
+    fn relative(voxels: &[VoxelState]) -> Vec<([i32; 3], [u8; 4])> {
+        let min = [0, 1, 2].map(|axis| {
+            voxels
+                .iter()
+                .map(|voxel| voxel.position[axis])
+                .min()
+                .unwrap()
+        });
+        let mut relative: Vec<([i32; 3], [u8; 4])> = voxels
+            .iter()
+            .map(|voxel| {
+                (
+                    [0, 1, 2].map(|axis| voxel.position[axis] - min[axis]),
+                    color_to_rgba(voxel.color),
+                )
+            })
+            .collect();
+        relative.sort();
+        relative
+    }
+
+    #[test]
+    fn round_trip_keeps_positions_and_colors() {
+        let voxels = vec![
+            voxel([0, 0, 0], [1.0, 0.0, 0.0, 1.0]),
+            voxel([3, 1, -2], [0.0, 1.0, 0.0, 1.0]),
+            voxel([-1, 4, 5], [0.2, 0.4, 0.6, 1.0]),
+        ];
+        let imported = import(&export(&voxels).unwrap(), 64).unwrap();
+        assert_eq!(relative(&imported), relative(&voxels));
+    }
+
+    #[test]
+    fn y_up_is_stored_as_z_up() {
+        let column: Vec<VoxelState> = (0..5)
+            .map(|y| voxel([0, y, 0], [1.0, 1.0, 1.0, 1.0]))
+            .collect();
+        let bytes = export(&column).unwrap();
+        // The SIZE chunk follows the 8 byte header and the 12 byte MAIN chunk header.
+        let size: Vec<i32> = bytes[32..44]
+            .chunks_exact(4)
+            .map(|value| i32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+            .collect();
+        assert_eq!(&bytes[20..24], b"SIZE");
+        assert_eq!(size, vec![1, 1, 5]);
+
+        let row: Vec<VoxelState> = (0..5)
+            .map(|z| voxel([0, 0, z], [1.0, 1.0, 1.0, 1.0]))
+            .collect();
+        let imported = import(&export(&row).unwrap(), 64).unwrap();
+        assert_eq!(relative(&imported), relative(&row));
+    }
+
+    #[test]
+    fn too_many_colors_are_merged_into_one_palette() {
+        let voxels: Vec<VoxelState> = (0..300)
+            .map(|index| {
+                let value = index as f32 / 299.0;
+                voxel(
+                    [index % 20, index / 20, 0],
+                    [value, 1.0 - value, (index % 7) as f32 / 6.0, 1.0],
+                )
+            })
+            .collect();
+        let imported = import(&export(&voxels).unwrap(), 64).unwrap();
+        assert_eq!(imported.len(), voxels.len());
+
+        let mut distinct: Vec<[u8; 4]> = imported
+            .iter()
+            .map(|voxel| color_to_rgba(voxel.color))
+            .collect();
+        distinct.sort();
+        distinct.dedup();
+        assert!(distinct.len() <= VOX_PALETTE_SIZE);
+
+        for (before, after) in relative(&voxels).iter().zip(relative(&imported).iter()) {
+            assert_eq!(before.0, after.0);
+            for channel in 0..4 {
+                assert!((before.1[channel] as i32 - after.1[channel] as i32).abs() <= 16);
+            }
+        }
+    }
+
+    #[test]
+    fn export_rejects_empty_and_oversized_models() {
+        assert_eq!(export(&[]), Err(VoxError::Empty));
+        let long = vec![
+            voxel([0, 0, 0], [1.0; 4]),
+            voxel([VOX_MAX_SIZE, 0, 0], [1.0; 4]),
+        ];
+        assert_eq!(
+            export(&long),
+            Err(VoxError::TooLarge([VOX_MAX_SIZE + 1, 1, 1]))
+        );
+    }
+
+    #[test]
+    fn import_rejects_models_bigger_than_the_world() {
+        let column: Vec<VoxelState> = (0..40).map(|y| voxel([0, y, 0], [1.0; 4])).collect();
+        assert_eq!(
+            import(&export(&column).unwrap(), 16),
+            Err(VoxError::OutOfBounds {
+                size: [1, 40, 1],
+                limit: 32
+            })
+        );
+    }
+
+    #[test]
+    fn import_rejects_malformed_files() {
+        let bytes = export(&[voxel([0, 0, 0], [1.0; 4])]).unwrap();
+
+        let mut header = bytes.clone();
+        header[0] = b'X';
+        assert!(matches!(import(&header, 64), Err(VoxError::Malformed(_))));
+
+        let truncated = &bytes[..bytes.len() - 10];
+        assert!(matches!(import(truncated, 64), Err(VoxError::Malformed(_))));
+
+        // Move the only voxel outside the 1x1x1 model. Its x follows the XYZI header
+        // and count.
+        let mut outside = bytes.clone();
+        outside[44 + 12 + 4] = 3;
+        assert!(matches!(import(&outside, 64), Err(VoxError::Malformed(_))));
+    }
+}
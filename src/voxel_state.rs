@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+
+/// A snapshot of a single voxel so it can be restored later.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct VoxelState {
+    /// The x, y, z index of the voxel.
+    pub position: [i32; 3],
+    /// Is this voxel filled?
+    pub active: bool,
+    /// The color of the voxel including alpha channel.
+    pub color: [f32; 4],
+    /// Render this voxel with fluid animation.
+    pub fluid: i32,
+    /// Render this voxel with a noisy texture.
+    pub noise: i32,
+}
+
+/// Colour (as bits so it can be compared), fluid and noise.
+pub type MaterialKey = ([u32; 4], i32, i32);
+
+/// Get a material in a form that can be compared and used as a key.
+pub fn material_key(color: [f32; 4], fluid: i32, noise: i32) -> MaterialKey {
+    (color.map(f32::to_bits), fluid, noise)
+}
+
+impl VoxelState {
+    /// Get the material of the voxel in a form that can be compared and used as a key.
+    pub fn material_key(&self) -> MaterialKey {
+        material_key(self.color, self.fluid, self.noise)
+    }
+}